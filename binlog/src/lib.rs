@@ -0,0 +1,299 @@
+#![no_std]
+
+// SDカードに保存するバイナリ形式のログ
+// ファイルの先頭にヘッダーがあり、その後ろに固定長のレコードが続く。数値はすべてリトルエンディアン
+//
+// ヘッダー (16バイト)
+//   0..4   "BMEL"
+//   4      バージョン
+//   5      レコードの長さ
+//   6..8   予約 (0)
+//   8..12  ファイルを作成した日時 (Unix 秒, UTC)
+//   12..14 予約 (0)
+//   14..16 0..14 の CRC-16
+//
+// レコード (16バイト)
+//   0..4   日時 (Unix 秒, UTC)
+//   4..6   温度 (0.01℃, 符号付き)
+//   6..8   湿度 (0.01%)
+//   8..12  気圧 (Pa = 0.01hPa)
+//   12     状態フラグ
+//   13     地方時と UTC のずれ (15分単位, 符号付き)。15分の倍数でないずれは表せない
+//   14..16 0..14 の CRC-16
+
+pub mod civil;
+
+pub const MAGIC: [u8; 4] = *b"BMEL";
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 16;
+pub const RECORD_SIZE: usize = 16;
+
+// テキスト形式 (txt / csv / tsv) のログで、電源が切れて途中で終わった行の末尾に付ける印
+// 数値として読めないので、この印の付いた行は読み飛ばす
+pub const TORN_LINE_MARKER: &str = "!TORN";
+
+pub const FLAG_RTC_INVALID: u8 = 0x01; // RTC の電圧低下・停止を検出した
+pub const FLAG_SENSOR_ERROR: u8 = 0x02; // BME280 の初期化に失敗している
+pub const FLAG_TIME_ADJUSTED: u8 = 0x04; // 直前に RTC を補正した
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    Crc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u8,
+    pub record_size: u8,
+    pub created_unix: u32,
+}
+
+impl FileHeader {
+    pub fn new(created_unix: u32) -> Self {
+        Self {
+            version: VERSION,
+            record_size: RECORD_SIZE as u8,
+            created_unix,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut b = [0; HEADER_SIZE];
+        b[0..4].copy_from_slice(&MAGIC);
+        b[4] = self.version;
+        b[5] = self.record_size;
+        b[8..12].copy_from_slice(&self.created_unix.to_le_bytes());
+        let crc = crc16(&b[0..14]);
+        b[14..16].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    pub fn decode(b: &[u8]) -> Result<Self, DecodeError> {
+        if b.len() < HEADER_SIZE {
+            return Err(DecodeError::Truncated);
+        }
+        if b[0..4] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if crc16(&b[0..14]) != u16::from_le_bytes([b[14], b[15]]) {
+            return Err(DecodeError::Crc);
+        }
+        if b[4] != VERSION || b[5] as usize != RECORD_SIZE {
+            return Err(DecodeError::UnsupportedVersion(b[4]));
+        }
+        Ok(Self {
+            version: b[4],
+            record_size: b[5],
+            created_unix: u32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    pub unix: u32,
+    pub temperature: i16, // 0.01℃
+    pub humidity: u16,    // 0.01%
+    pub pressure: u32,    // Pa
+    pub flags: u8,
+    pub utc_offset: i8, // 15分単位
+}
+
+impl Record {
+    // 計測値から作る。範囲外の値は飽和させる
+    // 地方時のずれは 15分単位なので、15分の倍数でなければ 0 の方へ切り捨てる（設定ファイルでは受け付けない）
+    pub fn from_values(
+        unix: u32,
+        utc_offset_minutes: i16,
+        temperature: f64,
+        humidity: f64,
+        pressure: f64,
+        flags: u8,
+    ) -> Self {
+        Self {
+            unix,
+            temperature: round(temperature * 100.0).clamp(i16::MIN as f64, i16::MAX as f64) as i16,
+            humidity: round(humidity * 100.0).clamp(0.0, u16::MAX as f64) as u16,
+            pressure: round(pressure * 100.0).clamp(0.0, u32::MAX as f64) as u32,
+            flags,
+            utc_offset: (utc_offset_minutes / 15) as i8,
+        }
+    }
+
+    pub fn temperature_c(&self) -> f64 {
+        self.temperature as f64 / 100.0
+    }
+
+    pub fn humidity_pct(&self) -> f64 {
+        self.humidity as f64 / 100.0
+    }
+
+    pub fn pressure_hpa(&self) -> f64 {
+        self.pressure as f64 / 100.0
+    }
+
+    pub fn utc_offset_minutes(&self) -> i16 {
+        self.utc_offset as i16 * 15
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut b = [0; RECORD_SIZE];
+        b[0..4].copy_from_slice(&self.unix.to_le_bytes());
+        b[4..6].copy_from_slice(&self.temperature.to_le_bytes());
+        b[6..8].copy_from_slice(&self.humidity.to_le_bytes());
+        b[8..12].copy_from_slice(&self.pressure.to_le_bytes());
+        b[12] = self.flags;
+        b[13] = self.utc_offset as u8;
+        let crc = crc16(&b[0..14]);
+        b[14..16].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    pub fn decode(b: &[u8]) -> Result<Self, DecodeError> {
+        if b.len() < RECORD_SIZE {
+            return Err(DecodeError::Truncated);
+        }
+        if crc16(&b[0..14]) != u16::from_le_bytes([b[14], b[15]]) {
+            return Err(DecodeError::Crc);
+        }
+        Ok(Self {
+            unix: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            temperature: i16::from_le_bytes([b[4], b[5]]),
+            humidity: u16::from_le_bytes([b[6], b[7]]),
+            pressure: u32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+            flags: b[12],
+            utc_offset: b[13] as i8,
+        })
+    }
+}
+
+// CRC-16/CCITT-FALSE (多項式 0x1021, 初期値 0xffff)
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(CRC16_INIT, data)
+}
+
+pub const CRC16_INIT: u16 = 0xffff;
+
+// 分かれているデータの CRC を続けて計算する。最初は CRC16_INIT を渡す
+pub fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+// no_std では f64::round() が使えないので、四捨五入は自前で行う
+fn round(value: f64) -> f64 {
+    if value < 0.0 {
+        -((-value + 0.5) as i64 as f64)
+    } else {
+        (value + 0.5) as i64 as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_known_vector() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(
+            crc16_update(crc16_update(CRC16_INIT, b"1234"), b"56789"),
+            0x29B1
+        );
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let record =
+            Record::from_values(1719273600, 540, -12.345, 45.678, 1013.25, FLAG_RTC_INVALID);
+        let decoded = Record::decode(&record.encode()).unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.unix, 1719273600);
+        assert_eq!(decoded.temperature, -1235);
+        assert_eq!(decoded.humidity, 4568);
+        assert_eq!(decoded.pressure, 101325);
+        assert_eq!(decoded.utc_offset_minutes(), 540);
+        assert_eq!(decoded.flags, FLAG_RTC_INVALID);
+    }
+
+    #[test]
+    fn record_saturates_out_of_range_values() {
+        let record = Record::from_values(0, -300, 500.0, -1.0, 1e12, 0);
+        assert_eq!(record.temperature, i16::MAX);
+        assert_eq!(record.humidity, 0);
+        assert_eq!(record.pressure, u32::MAX);
+        assert_eq!(record.utc_offset_minutes(), -300);
+    }
+
+    #[test]
+    fn utc_offset_is_stored_in_quarter_hours() {
+        for minutes in [0, 330, 345, -210, 14 * 60, -12 * 60] {
+            let record = Record::from_values(0, minutes, 0.0, 0.0, 0.0, 0);
+            let decoded = Record::decode(&record.encode()).unwrap();
+            assert_eq!(decoded.utc_offset_minutes(), minutes);
+        }
+        // 15分の倍数でないずれは 0 の方へ切り捨てる
+        assert_eq!(
+            Record::from_values(0, 20, 0.0, 0.0, 0.0, 0).utc_offset_minutes(),
+            15
+        );
+        assert_eq!(
+            Record::from_values(0, -50, 0.0, 0.0, 0.0, 0).utc_offset_minutes(),
+            -45
+        );
+    }
+
+    #[test]
+    fn record_rejects_corruption() {
+        let b = Record::from_values(1719273600, 0, 23.5, 45.5, 1013.25, 0).encode();
+        for i in 0..RECORD_SIZE {
+            let mut corrupted = b;
+            corrupted[i] ^= 0x10;
+            assert_eq!(Record::decode(&corrupted), Err(DecodeError::Crc));
+        }
+        assert_eq!(
+            Record::decode(&b[..RECORD_SIZE - 1]),
+            Err(DecodeError::Truncated)
+        );
+        // 書きかけを 0 で埋めたものも読み飛ばされる
+        assert_eq!(Record::decode(&[0; RECORD_SIZE]), Err(DecodeError::Crc));
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = FileHeader::new(1719273600);
+        let b = header.encode();
+        assert_eq!(&b[0..4], b"BMEL");
+        assert_eq!(FileHeader::decode(&b), Ok(header));
+
+        let mut corrupted = b;
+        corrupted[9] ^= 1;
+        assert_eq!(FileHeader::decode(&corrupted), Err(DecodeError::Crc));
+        assert_eq!(FileHeader::decode(&b[..8]), Err(DecodeError::Truncated));
+        assert_eq!(
+            FileHeader::decode(&[0; HEADER_SIZE]),
+            Err(DecodeError::BadMagic)
+        );
+
+        let mut newer = b;
+        newer[4] = VERSION + 1;
+        let crc = crc16(&newer[0..14]);
+        newer[14..16].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            FileHeader::decode(&newer),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// SDカードに保存したバイナリログ (.bin) を CSV か JSON Lines に変換する
+//
+//   cargo run -p binlog_decode --target x86_64-unknown-linux-gnu -- [--json] FILE...
+//
+// ワークスペースの既定のターゲットは thumbv6m-none-eabi なので、ホストのターゲットを指定すること
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+use binlog::civil::civil_from_days;
+use binlog::{DecodeError, FileHeader, Record, HEADER_SIZE, RECORD_SIZE};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Csv,
+    Json,
+}
+
+fn main() -> ExitCode {
+    let mut output = Output::Csv;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => output = Output::Json,
+            "--csv" => output = Output::Csv,
+            "-h" | "--help" => {
+                eprintln!("usage: binlog_decode [--csv | --json] FILE...");
+                return ExitCode::SUCCESS;
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("usage: binlog_decode [--csv | --json] FILE...");
+        return ExitCode::FAILURE;
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    if output == Output::Csv {
+        let _ = writeln!(
+            out,
+            "time_utc,utc_offset,temperature_c,humidity_pct,pressure_hpa,flags"
+        );
+    }
+
+    let mut failed = false;
+    for file in &files {
+        if let Err(e) = decode_file(file, output, &mut out) {
+            eprintln!("{}: {}", file, e);
+            failed = true;
+        }
+    }
+    let _ = out.flush();
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn decode_file<W: Write>(path: &str, output: Output, out: &mut W) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    FileHeader::decode(&data).map_err(describe)?;
+
+    let mut bad = 0;
+    let body = &data[HEADER_SIZE..];
+    for chunk in body.chunks(RECORD_SIZE) {
+        match Record::decode(chunk) {
+            Ok(record) => write_record(out, &record, output).map_err(|e| e.to_string())?,
+            Err(DecodeError::Truncated) => {
+                eprintln!("{}: trailing {} bytes ignored", path, chunk.len());
+            }
+            Err(_) => bad += 1,
+        }
+    }
+    if bad > 0 {
+        eprintln!("{}: {} records with bad CRC skipped", path, bad);
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(out: &mut W, r: &Record, output: Output) -> io::Result<()> {
+    let time = format_unix(r.unix);
+    let offset = format_offset(r.utc_offset_minutes());
+    match output {
+        Output::Csv => writeln!(
+            out,
+            "{}Z,{},{:.2},{:.2},{:.2},{}",
+            time,
+            offset,
+            r.temperature_c(),
+            r.humidity_pct(),
+            r.pressure_hpa(),
+            r.flags
+        ),
+        Output::Json => writeln!(
+            out,
+            "{{\"time_utc\":\"{}Z\",\"utc_offset\":\"{}\",\"temperature_c\":{:.2},\"humidity_pct\":{:.2},\"pressure_hpa\":{:.2},\"flags\":{}}}",
+            time,
+            offset,
+            r.temperature_c(),
+            r.humidity_pct(),
+            r.pressure_hpa(),
+            r.flags
+        ),
+    }
+}
+
+fn describe(e: DecodeError) -> String {
+    match e {
+        DecodeError::Truncated => "file is shorter than the header".to_string(),
+        DecodeError::BadMagic => "not a BME280 binary log".to_string(),
+        DecodeError::UnsupportedVersion(v) => format!("unsupported version {}", v),
+        DecodeError::Crc => "header CRC mismatch".to_string(),
+    }
+}
+
+fn format_offset(minutes: i16) -> String {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let m = minutes.unsigned_abs();
+    format!("{}{:02}:{:02}", sign, m / 60, m % 60)
+}
+
+// Unix 秒を "2024-06-25T09:42:00" にする
+fn format_unix(unix: u32) -> String {
+    let (y, m, d) = civil_from_days((unix / 86400) as i64);
+    let rest = unix % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        rest / 3600,
+        (rest / 60) % 60,
+        rest % 60
+    )
+}
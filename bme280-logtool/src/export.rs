@@ -0,0 +1,155 @@
+// CSV / JSON Lines / InfluxDB line protocol で書き出す
+
+use std::io::{self, Write};
+
+use crate::process::DailySummary;
+use crate::record::Sample;
+use crate::time::{format_iso, format_offset};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+    Influx,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(Format::Csv),
+            "jsonl" | "json" => Some(Format::Jsonl),
+            "influx" => Some(Format::Influx),
+            _ => None,
+        }
+    }
+}
+
+pub fn write_samples<W: Write>(
+    out: &mut W,
+    samples: &[Sample],
+    format: Format,
+    measurement: &str,
+) -> io::Result<()> {
+    if format == Format::Csv {
+        writeln!(
+            out,
+            "time_utc,utc_offset,temperature_c,humidity_pct,pressure_hpa,filled"
+        )?;
+    }
+    for s in samples {
+        match format {
+            Format::Csv => writeln!(
+                out,
+                "{}Z,{},{:.2},{:.2},{:.2},{}",
+                format_iso(s.unix),
+                format_offset(s.utc_offset),
+                s.temperature,
+                s.humidity,
+                s.pressure,
+                s.filled as u8
+            )?,
+            Format::Jsonl => writeln!(
+                out,
+                "{{\"time_utc\":\"{}Z\",\"utc_offset\":\"{}\",\"temperature_c\":{:.2},\"humidity_pct\":{:.2},\"pressure_hpa\":{:.2},\"filled\":{}}}",
+                format_iso(s.unix),
+                format_offset(s.utc_offset),
+                s.temperature,
+                s.humidity,
+                s.pressure,
+                s.filled
+            )?,
+            // タイムスタンプはナノ秒（InfluxDB の既定の精度）
+            Format::Influx => writeln!(
+                out,
+                "{},filled={} temperature={:.2},humidity={:.2},pressure={:.2} {}",
+                measurement,
+                s.filled,
+                s.temperature,
+                s.humidity,
+                s.pressure,
+                s.unix * 1_000_000_000
+            )?,
+        }
+    }
+    Ok(())
+}
+
+pub fn write_summary<W: Write>(out: &mut W, days: &[DailySummary]) -> io::Result<()> {
+    writeln!(
+        out,
+        "date        count  T min  T max T mean  H min  H max H mean   P min   P max  P mean"
+    )?;
+    for d in days {
+        writeln!(
+            out,
+            "{} {:6} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1} {:7.1} {:7.1} {:7.1}",
+            d.date,
+            d.temperature.count,
+            d.temperature.min,
+            d.temperature.max,
+            d.temperature.mean(),
+            d.humidity.min,
+            d.humidity.max,
+            d.humidity.mean(),
+            d.pressure.min,
+            d.pressure.max,
+            d.pressure.mean()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(format: Format) -> String {
+        let samples = [Sample {
+            unix: 1719308520,
+            utc_offset: 540,
+            temperature: 23.456,
+            humidity: 45.6,
+            pressure: 1013.25,
+            filled: true,
+        }];
+        let mut out = Vec::new();
+        write_samples(&mut out, &samples, format, "room").unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            export(Format::Csv),
+            "time_utc,utc_offset,temperature_c,humidity_pct,pressure_hpa,filled\n\
+             2024-06-25T09:42:00Z,+09:00,23.46,45.60,1013.25,1\n"
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        assert_eq!(
+            export(Format::Jsonl),
+            "{\"time_utc\":\"2024-06-25T09:42:00Z\",\"utc_offset\":\"+09:00\",\
+             \"temperature_c\":23.46,\"humidity_pct\":45.60,\"pressure_hpa\":1013.25,\
+             \"filled\":true}\n"
+        );
+    }
+
+    #[test]
+    fn influx_line_protocol() {
+        assert_eq!(
+            export(Format::Influx),
+            "room,filled=true temperature=23.46,humidity=45.60,pressure=1013.25 \
+             1719308520000000000\n"
+        );
+    }
+
+    #[test]
+    fn format_names() {
+        assert!(Format::parse("csv") == Some(Format::Csv));
+        assert!(Format::parse("json") == Some(Format::Jsonl));
+        assert!(Format::parse("influx") == Some(Format::Influx));
+        assert!(Format::parse("xml").is_none());
+    }
+}
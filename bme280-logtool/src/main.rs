@@ -0,0 +1,155 @@
+// SDカードに保存した日毎のログ (/LOG/YYYY/MM/DD.CSV など。.txt / .tsv / .bin も読める) を読み込み、
+// 結合・補間・再サンプリングして CSV / JSON Lines / InfluxDB line protocol に変換する
+//
+//   cargo run -p bme280-logtool --target x86_64-unknown-linux-gnu -- [OPTIONS] FILE...
+//
+// ワークスペースの既定のターゲットは thumbv6m-none-eabi なので、ホストのターゲットを指定すること
+
+mod export;
+mod process;
+mod record;
+mod time;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process::ExitCode;
+
+use export::Format;
+
+const USAGE: &str = "usage: bme280-logtool [OPTIONS] FILE...
+
+options:
+  --format csv|jsonl|influx  output format (default: csv)
+  --measurement NAME         measurement name for influx (default: bme280)
+  --tz-offset +HH:MM         UTC offset of old text logs written in local time (default: +00:00)
+  --fill-gaps                interpolate missing samples
+  --interval SECONDS         expected logging interval for --fill-gaps (default: 60)
+  --resample SECONDS         average samples into fixed periods
+  --summary                  print daily min/max/mean instead of samples
+  -o FILE                    write to FILE instead of stdout";
+
+struct Options {
+    format: Format,
+    measurement: String,
+    tz_offset: i32,
+    fill_gaps: bool,
+    interval: i64,
+    resample: Option<i64>,
+    summary: bool,
+    output: Option<String>,
+    files: Vec<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut opts = Options {
+        format: Format::Csv,
+        measurement: "bme280".to_string(),
+        tz_offset: 0,
+        fill_gaps: false,
+        interval: 60,
+        resample: None,
+        summary: false,
+        output: None,
+        files: Vec::new(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--format" => {
+                let v = value("--format")?;
+                opts.format = Format::parse(&v).ok_or(format!("unknown format '{}'", v))?;
+            }
+            "--measurement" => opts.measurement = value("--measurement")?,
+            "--tz-offset" => {
+                let v = value("--tz-offset")?;
+                opts.tz_offset = time::parse_offset(&v).ok_or(format!("bad UTC offset '{}'", v))?;
+            }
+            "--fill-gaps" => opts.fill_gaps = true,
+            "--interval" => opts.interval = seconds(&value("--interval")?)?,
+            "--resample" => opts.resample = Some(seconds(&value("--resample")?)?),
+            "--summary" => opts.summary = true,
+            "-o" => opts.output = Some(value("-o")?),
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ => opts.files.push(arg),
+        }
+    }
+    if opts.files.is_empty() {
+        return Err("no input files".to_string());
+    }
+    Ok(opts)
+}
+
+fn seconds(s: &str) -> Result<i64, String> {
+    match s.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("bad number of seconds '{}'", s)),
+    }
+}
+
+fn main() -> ExitCode {
+    let opts = match parse_args() {
+        Ok(opts) => opts,
+        Err(e) if e.is_empty() => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failed = false;
+    let mut warnings = Vec::new();
+    let mut samples = Vec::new();
+    for file in &opts.files {
+        match record::read_file(file, opts.tz_offset, &mut warnings) {
+            Ok(s) => samples.extend(s),
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    for w in &warnings {
+        eprintln!("{}", w);
+    }
+
+    let mut samples = process::merge(samples);
+    if opts.fill_gaps {
+        samples = process::fill_gaps(&samples, opts.interval);
+    }
+    if let Some(period) = opts.resample {
+        samples = process::resample(&samples, period);
+    }
+
+    let out: Box<dyn Write> = match &opts.output {
+        Some(path) => match File::create(path) {
+            Ok(f) => Box::new(f),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+    let result = if opts.summary {
+        export::write_summary(&mut out, &process::daily_summary(&samples))
+    } else {
+        export::write_samples(&mut out, &samples, opts.format, &opts.measurement)
+    };
+    if let Err(e) = result.and_then(|_| out.flush()) {
+        eprintln!("write error: {}", e);
+        failed = true;
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
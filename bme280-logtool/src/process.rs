@@ -0,0 +1,218 @@
+// 複数ファイルの結合、欠測の補間、再サンプリング、日毎の集計
+
+use std::collections::BTreeMap;
+
+use crate::record::Sample;
+use crate::time::format_date;
+
+// 時刻順に並べ、同じ時刻の重複を取り除く（先に読んだものを残す）
+pub fn merge(mut samples: Vec<Sample>) -> Vec<Sample> {
+    samples.sort_by_key(|s| s.unix);
+    samples.dedup_by_key(|s| s.unix);
+    samples
+}
+
+// interval 秒より長く空いているところを直線で補間する
+pub fn fill_gaps(samples: &[Sample], interval: i64) -> Vec<Sample> {
+    let mut out = Vec::with_capacity(samples.len());
+    for pair in samples.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        out.push(a);
+        let span = b.unix - a.unix;
+        let mut t = a.unix + interval;
+        while t < b.unix {
+            let k = (t - a.unix) as f64 / span as f64;
+            out.push(Sample {
+                unix: t,
+                utc_offset: a.utc_offset,
+                temperature: a.temperature + (b.temperature - a.temperature) * k,
+                humidity: a.humidity + (b.humidity - a.humidity) * k,
+                pressure: a.pressure + (b.pressure - a.pressure) * k,
+                filled: true,
+            });
+            t += interval;
+        }
+    }
+    if let Some(last) = samples.last() {
+        out.push(*last);
+    }
+    out
+}
+
+// period 秒ごとの区間の平均にする。時刻は区間の先頭
+pub fn resample(samples: &[Sample], period: i64) -> Vec<Sample> {
+    let mut out: Vec<Sample> = Vec::new();
+    let mut count = 0;
+    for s in samples {
+        let start = s.unix.div_euclid(period) * period;
+        match out.last_mut() {
+            Some(acc) if acc.unix == start => {
+                acc.temperature += s.temperature;
+                acc.humidity += s.humidity;
+                acc.pressure += s.pressure;
+                acc.filled &= s.filled;
+                count += 1;
+            }
+            _ => {
+                average(out.last_mut(), count);
+                out.push(Sample { unix: start, ..*s });
+                count = 1;
+            }
+        }
+    }
+    average(out.last_mut(), count);
+    out
+}
+
+fn average(acc: Option<&mut Sample>, count: usize) {
+    if let Some(acc) = acc {
+        let n = count as f64;
+        acc.temperature /= n;
+        acc.humidity /= n;
+        acc.pressure /= n;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Stat {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: usize,
+}
+
+impl Stat {
+    fn new() -> Self {
+        Self {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+pub struct DailySummary {
+    pub date: String, // 地方時の日付
+    pub temperature: Stat,
+    pub humidity: Stat,
+    pub pressure: Stat,
+}
+
+// 地方時の日付ごとに最小・最大・平均を求める。補間した値は含めない
+pub fn daily_summary(samples: &[Sample]) -> Vec<DailySummary> {
+    let mut days: BTreeMap<String, [Stat; 3]> = BTreeMap::new();
+    for s in samples.iter().filter(|s| !s.filled) {
+        let date = format_date(s.unix + s.utc_offset as i64 * 60);
+        let stats = days.entry(date).or_insert([Stat::new(); 3]);
+        stats[0].add(s.temperature);
+        stats[1].add(s.humidity);
+        stats[2].add(s.pressure);
+    }
+    days.into_iter()
+        .map(|(date, [temperature, humidity, pressure])| DailySummary {
+            date,
+            temperature,
+            humidity,
+            pressure,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(unix: i64, t: f64) -> Sample {
+        Sample {
+            unix,
+            utc_offset: 540,
+            temperature: t,
+            humidity: 50.0,
+            pressure: 1000.0,
+            filled: false,
+        }
+    }
+
+    #[test]
+    fn merge_sorts_and_drops_duplicates() {
+        let merged = merge(vec![
+            sample(120, 3.0),
+            sample(0, 1.0),
+            sample(120, 9.0),
+            sample(60, 2.0),
+        ]);
+        let times: Vec<_> = merged.iter().map(|s| (s.unix, s.temperature)).collect();
+        assert_eq!(times, [(0, 1.0), (60, 2.0), (120, 3.0)]);
+    }
+
+    #[test]
+    fn fill_gaps_interpolates_linearly() {
+        let filled = fill_gaps(&[sample(0, 10.0), sample(60, 11.0), sample(240, 14.0)], 60);
+        let values: Vec<_> = filled
+            .iter()
+            .map(|s| (s.unix, s.temperature, s.filled))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                (0, 10.0, false),
+                (60, 11.0, false),
+                (120, 12.0, true),
+                (180, 13.0, true),
+                (240, 14.0, false)
+            ]
+        );
+        assert!(fill_gaps(&[], 60).is_empty());
+    }
+
+    #[test]
+    fn resample_averages_each_period() {
+        let mut samples = vec![
+            sample(0, 10.0),
+            sample(60, 12.0),
+            sample(600, 20.0),
+            sample(1150, 30.0),
+        ];
+        samples[3].filled = true;
+        let resampled = resample(&samples, 600);
+        let values: Vec<_> = resampled
+            .iter()
+            .map(|s| (s.unix, s.temperature, s.filled))
+            .collect();
+        assert_eq!(values, [(0, 11.0, false), (600, 25.0, false)]);
+        assert_eq!(resampled[0].humidity, 50.0);
+    }
+
+    #[test]
+    fn daily_summary_uses_local_dates_and_skips_filled() {
+        // 2024-06-25T15:00:00Z は地方時 (+09:00) では 26日の 0時
+        let mut samples = vec![
+            sample(1719309600, 20.0),
+            sample(1719309660, 24.0),
+            sample(1719327600, 30.0),
+            sample(1719327660, 99.0),
+        ];
+        samples[3].filled = true;
+        let days = daily_summary(&samples);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].date, "2024-06-25");
+        assert_eq!(days[0].temperature.count, 2);
+        assert_eq!(days[0].temperature.min, 20.0);
+        assert_eq!(days[0].temperature.max, 24.0);
+        assert_eq!(days[0].temperature.mean(), 22.0);
+        assert_eq!(days[1].date, "2024-06-26");
+        assert_eq!(days[1].temperature.count, 1);
+    }
+}
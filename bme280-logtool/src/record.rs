@@ -0,0 +1,298 @@
+// 日毎のログファイルを読み込んで Sample の並びにする
+// 対応している形式
+//   旧テキスト形式  "2024/06/25 18:42 T: 23.4, H: 45.6, P: 1013.2"      (日時は地方時)
+//   テキスト形式    "2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013.2" (日時は UTC)
+//   CSV / TSV       "2024-06-25T09:42:00Z,+09:00,23.45,45.62,1013.25,..."
+//   バイナリ形式    binlog クレートの .bin
+
+use std::fs;
+
+use binlog::{DecodeError, FileHeader, Record, HEADER_SIZE, RECORD_SIZE, TORN_LINE_MARKER};
+
+use crate::time::{is_valid_date, parse_offset, to_unix};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub unix: i64,        // UTC
+    pub utc_offset: i32,  // 分
+    pub temperature: f64, // ℃
+    pub humidity: f64,    // %
+    pub pressure: f64,    // hPa
+    pub filled: bool,     // 欠測を補間した値
+}
+
+impl Sample {
+    // BME280 の測定範囲に入っているか
+    pub fn is_plausible(&self) -> bool {
+        (-40.0..=85.0).contains(&self.temperature)
+            && (0.0..=100.0).contains(&self.humidity)
+            && (300.0..=1100.0).contains(&self.pressure)
+    }
+}
+
+// ファイルを読み込む。解釈できない行は "ファイル名:行番号: 理由" を warnings に積む
+// default_offset は地方時で書かれた旧形式の行に使う
+pub fn read_file(
+    path: &str,
+    default_offset: i32,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<Sample>, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    if data.starts_with(&binlog::MAGIC) {
+        return read_binary(path, &data, warnings);
+    }
+    let text = String::from_utf8_lossy(&data);
+    let mut samples = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("time_utc") {
+            continue;
+        }
+        if line.ends_with(TORN_LINE_MARKER) {
+            warnings.push(format!("{}:{}: torn line skipped", path, n + 1));
+            continue;
+        }
+        match parse_line(line, default_offset) {
+            Some(s) if s.is_plausible() => samples.push(s),
+            Some(_) => warnings.push(format!("{}:{}: value out of range", path, n + 1)),
+            None => warnings.push(format!("{}:{}: unrecognized line", path, n + 1)),
+        }
+    }
+    Ok(samples)
+}
+
+fn read_binary(path: &str, data: &[u8], warnings: &mut Vec<String>) -> Result<Vec<Sample>, String> {
+    FileHeader::decode(data).map_err(|e| match e {
+        DecodeError::Truncated => "file is shorter than the header".to_string(),
+        DecodeError::BadMagic => "not a BME280 binary log".to_string(),
+        DecodeError::UnsupportedVersion(v) => format!("unsupported version {}", v),
+        DecodeError::Crc => "header CRC mismatch".to_string(),
+    })?;
+
+    let mut samples = Vec::new();
+    for (n, chunk) in data[HEADER_SIZE..].chunks(RECORD_SIZE).enumerate() {
+        match Record::decode(chunk) {
+            Ok(r) => {
+                let s = Sample {
+                    unix: r.unix as i64,
+                    utc_offset: r.utc_offset_minutes() as i32,
+                    temperature: r.temperature_c(),
+                    humidity: r.humidity_pct(),
+                    pressure: r.pressure_hpa(),
+                    filled: false,
+                };
+                if s.is_plausible() {
+                    samples.push(s);
+                } else {
+                    warnings.push(format!("{}:#{}: value out of range", path, n));
+                }
+            }
+            Err(DecodeError::Truncated) => {
+                warnings.push(format!("{}: trailing {} bytes ignored", path, chunk.len()))
+            }
+            Err(_) => warnings.push(format!("{}:#{}: bad CRC", path, n)),
+        }
+    }
+    Ok(samples)
+}
+
+// 電源が切れて途中で終わった行 (TORN_LINE_MARKER 付き) は None
+pub fn parse_line(line: &str, default_offset: i32) -> Option<Sample> {
+    if line.ends_with(TORN_LINE_MARKER) {
+        None
+    } else if line.contains('T') && line.as_bytes().get(4) == Some(&b'-') {
+        parse_csv(line)
+    } else {
+        parse_text(line, default_offset)
+    }
+}
+
+// "2024-06-25T09:42:00Z,+09:00,23.45,45.62,1013.25[,...]" (区切りは ',' か '\t')
+fn parse_csv(line: &str) -> Option<Sample> {
+    let sep = if line.contains('\t') { '\t' } else { ',' };
+    let mut fields = line.split(sep).map(str::trim);
+    let unix = parse_iso(fields.next()?.strip_suffix('Z')?)?;
+    let utc_offset = parse_offset(fields.next()?)?;
+    Some(Sample {
+        unix,
+        utc_offset,
+        temperature: fields.next()?.parse().ok()?,
+        humidity: fields.next()?.parse().ok()?,
+        pressure: fields.next()?.parse().ok()?,
+        filled: false,
+    })
+}
+
+// "2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013.2"
+// "2024/06/25 18:42 T: 23.4, H: 45.6, P: 1013.2"
+fn parse_text(line: &str, default_offset: i32) -> Option<Sample> {
+    let mut words = line.split_whitespace();
+    let date = words.next()?;
+    let time = words.next()?;
+    let (time, utc_offset, local) = match time.strip_suffix('Z') {
+        Some(time) => (time, parse_offset(words.next()?)?, false),
+        None => (time, default_offset, true),
+    };
+
+    let mut d = date.split('/');
+    let year: i64 = d.next()?.parse().ok()?;
+    let month: u32 = d.next()?.parse().ok()?;
+    let day: u32 = d.next()?.parse().ok()?;
+    let (hour, minute) = time.split_once(':')?;
+    let hour: u32 = hour.parse().ok()?;
+    let minute: u32 = minute.parse().ok()?;
+    if !is_valid_date(year, month, day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let mut unix = to_unix(year, month, day, hour, minute, 0);
+    if local {
+        unix -= utc_offset as i64 * 60;
+    }
+
+    let rest: String = words.collect::<Vec<_>>().join(" ");
+    Some(Sample {
+        unix,
+        utc_offset,
+        temperature: field(&rest, "T:")?,
+        humidity: field(&rest, "H:")?,
+        pressure: field(&rest, "P:")?,
+        filled: false,
+    })
+}
+
+// "T: 23.4, H: 45.6, P: 1013.2" から key の値を取り出す
+fn field(s: &str, key: &str) -> Option<f64> {
+    let start = s.find(key)? + key.len();
+    s[start..].split(',').next()?.trim().parse().ok()
+}
+
+// "2024-06-25T09:42:00"
+fn parse_iso(s: &str) -> Option<i64> {
+    let (date, time) = s.split_once('T')?;
+    let mut d = date.split('-');
+    let year: i64 = d.next()?.parse().ok()?;
+    let month: u32 = d.next()?.parse().ok()?;
+    let day: u32 = d.next()?.parse().ok()?;
+    let mut t = time.split(':');
+    let hour: u32 = t.next()?.parse().ok()?;
+    let minute: u32 = t.next()?.parse().ok()?;
+    let second: u32 = t.next().unwrap_or("0").parse().ok()?;
+    if !is_valid_date(year, month, day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    Some(to_unix(year, month, day, hour, minute, second))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-06-25T09:42:00Z
+    const T0: i64 = 1719308520;
+
+    fn sample(unix: i64, utc_offset: i32, t: f64, h: f64, p: f64) -> Sample {
+        Sample {
+            unix,
+            utc_offset,
+            temperature: t,
+            humidity: h,
+            pressure: p,
+            filled: false,
+        }
+    }
+
+    #[test]
+    fn parses_every_text_format() {
+        let expected = sample(T0, 540, 23.4, 45.6, 1013.2);
+        assert_eq!(
+            parse_line("2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013.2", 0),
+            Some(expected)
+        );
+        // 旧形式は地方時で書かれている
+        assert_eq!(
+            parse_line("2024/06/25 18:42 T: 23.4, H: 45.6, P: 1013.2", 540),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_line("2024-06-25T09:42:00Z,+09:00,23.4,45.6,1013.2,12.3", 0),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_line("2024-06-25T09:42:00Z\t+09:00\t23.4\t45.6\t1013.2", 0),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_line("2024-06-25T09:42:00Z,-05:30,23.4,45.6,1013.2", 0).map(|s| s.utc_offset),
+            Some(-330)
+        );
+    }
+
+    #[test]
+    fn rejects_broken_lines() {
+        for line in [
+            "2024-06-25T09:42:00Z,+09:00,23.4,45.6,1013.2,12!TORN",
+            "2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013!TORN",
+            "2024-06-25T09:42:00Z,+09:00,23.4,45.6",
+            "2024-06-25T09:42:00Z,+09:00,23.4,45.6,10",
+            "2024-02-30T09:42:00Z,+09:00,23.4,45.6,1013.2",
+            "2024-06-25T24:00:00Z,+09:00,23.4,45.6,1013.2",
+            "2024-06-25T09:42:00,+09:00,23.4,45.6,1013.2",
+            "2024/13/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013.2",
+            "2024/06/25 09:42Z T: 23.4, H: 45.6, P: 1013.2",
+            "2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6",
+            "garbage",
+        ] {
+            let parsed = parse_line(line, 0);
+            assert!(
+                parsed.is_none() || !parsed.unwrap().is_plausible(),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn reads_binary_records_and_skips_bad_ones() {
+        let mut data = FileHeader::new(T0 as u32).encode().to_vec();
+        data.extend(Record::from_values(T0 as u32, 540, 23.4, 45.6, 1013.2, 0).encode());
+        data.extend([0; RECORD_SIZE]);
+        data.extend(Record::from_values(T0 as u32 + 60, 540, 23.5, 45.7, 1013.3, 0).encode());
+        data.extend([1, 2, 3]);
+
+        let mut warnings = Vec::new();
+        let samples = read_binary("25.BIN", &data, &mut warnings).unwrap();
+        assert_eq!(
+            samples,
+            [
+                sample(T0, 540, 23.4, 45.6, 1013.2),
+                sample(T0 + 60, 540, 23.5, 45.7, 1013.3)
+            ]
+        );
+        assert_eq!(
+            warnings,
+            ["25.BIN:#1: bad CRC", "25.BIN: trailing 3 bytes ignored"]
+        );
+
+        assert!(read_binary("25.BIN", &data[..8], &mut warnings).is_err());
+    }
+
+    #[test]
+    fn read_file_skips_torn_lines() {
+        let path = std::env::temp_dir().join(format!("logtool-torn-{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "time_utc,utc_offset,temperature_c,humidity_pct,pressure_hpa\r\n\
+             2024-06-25T09:42:00Z,+09:00,23.4,45.6,1013.2\r\n\
+             2024-06-25T09:43:00Z,+09:00,23.5,45!TORN\r\n\
+             2024-06-25T09:44:00Z,+09:00,23.6,45.8,1013.4\r\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let mut warnings = Vec::new();
+        let samples = read_file(path, 0, &mut warnings).unwrap();
+        let _ = fs::remove_file(path);
+        let times: Vec<_> = samples.iter().map(|s| s.unix - T0).collect();
+        assert_eq!(times, [0, 120]);
+        assert_eq!(warnings, [format!("{}:3: torn line skipped", path)]);
+    }
+}
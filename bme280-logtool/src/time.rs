@@ -0,0 +1,94 @@
+// Unix 秒と日付の変換。日付の計算は binlog::civil のものを使う
+
+use binlog::civil::{civil_from_days, days_from_civil, days_in_month};
+
+pub fn to_unix(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> i64 {
+    days_from_civil(year, month, day) * 86400
+        + hour as i64 * 3600
+        + minute as i64 * 60
+        + second as i64
+}
+
+pub fn is_valid_date(year: i64, month: u32, day: u32) -> bool {
+    (1..=days_in_month(year, month)).contains(&day)
+}
+
+// "2024-06-25T09:42:00"
+pub fn format_iso(unix: i64) -> String {
+    let (y, m, d) = civil_from_days(unix.div_euclid(86400));
+    let rest = unix.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        rest / 3600,
+        (rest / 60) % 60,
+        rest % 60
+    )
+}
+
+// "2024-06-25"
+pub fn format_date(unix: i64) -> String {
+    let (y, m, d) = civil_from_days(unix.div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+// "+09:00"
+pub fn format_offset(minutes: i32) -> String {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let m = minutes.unsigned_abs();
+    format!("{}{:02}:{:02}", sign, m / 60, m % 60)
+}
+
+// "+09:00" / "-05:00" / "Z"
+pub fn parse_offset(s: &str) -> Option<i32> {
+    if s == "Z" {
+        return Some(0);
+    }
+    let (sign, rest) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let (h, m) = rest.split_once(':')?;
+    let h: i32 = h.parse().ok()?;
+    let m: i32 = m.parse().ok()?;
+    if h > 14 || m >= 60 {
+        return None;
+    }
+    Some(sign * (h * 60 + m))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_and_iso() {
+        assert_eq!(to_unix(2024, 6, 25, 9, 42, 0), 1719308520);
+        assert_eq!(format_iso(1719308520), "2024-06-25T09:42:00");
+        assert_eq!(format_iso(-1), "1969-12-31T23:59:59");
+        assert_eq!(format_date(1719308520), "2024-06-25");
+    }
+
+    #[test]
+    fn valid_dates() {
+        assert!(is_valid_date(2024, 2, 29));
+        assert!(!is_valid_date(2023, 2, 29));
+        assert!(!is_valid_date(2024, 4, 31));
+        assert!(!is_valid_date(2024, 0, 1));
+        assert!(!is_valid_date(2024, 1, 0));
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_offset("+09:00"), Some(540));
+        assert_eq!(parse_offset("-05:30"), Some(-330));
+        assert_eq!(parse_offset("Z"), Some(0));
+        assert_eq!(parse_offset("09:00"), None);
+        assert_eq!(parse_offset("+15:00"), None);
+        assert_eq!(format_offset(540), "+09:00");
+        assert_eq!(format_offset(-330), "-05:30");
+    }
+}
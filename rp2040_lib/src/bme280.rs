@@ -1,18 +1,50 @@
 pub mod i2c;
 pub mod spi;
 
-const OSRS_H: u8 = 0x3; // 湿度 x4 サンプリング
-const CTRL_HUM_WDATA: u8 = OSRS_H;
+// 計測の設定。値はデータシートのレジスタの値
+//   osrs_*  : 0=計測しない 1=x1 2=x2 3=x4 4=x8 5=x16
+//   standby : 0=0.5 1=62.5 2=125 3=250 4=500 5=1000 6=10 7=20 (msec)
+//   filter  : 0=なし 1=2 2=4 3=8 4=16
+#[derive(Clone, Copy, PartialEq)]
+pub struct Config {
+    pub osrs_t: u8,
+    pub osrs_p: u8,
+    pub osrs_h: u8,
+    pub standby: u8,
+    pub filter: u8,
+}
 
-const OSRS_T: u8 = 0x3; // 温度 x4 サンプリング
-const OSRS_P: u8 = 0x3; // 気圧 x4 サンプリング
-const MODE: u8 = 0x3; // ノーマルモード
-const CTRL_MEAS_WDATA: u8 = OSRS_T << 5 | OSRS_P << 2 | MODE;
+impl Config {
+    // 温度・湿度・気圧 x4 サンプリング、スタンバイ時間 500msec、フィルター 4
+    pub const DEFAULT: Config = Config {
+        osrs_t: 0x3,
+        osrs_p: 0x3,
+        osrs_h: 0x3,
+        standby: 0x4,
+        filter: 0x2,
+    };
+
+    fn ctrl_hum(&self) -> u8 {
+        self.osrs_h & 0x7
+    }
+
+    fn ctrl_meas(&self) -> u8 {
+        (self.osrs_t & 0x7) << 5 | (self.osrs_p & 0x7) << 2 | MODE
+    }
 
-const T_SB: u8 = 0x4; // スタンバイ時間 500msec
-const FILTER: u8 = 0x2; // フィルター 4
+    fn config(&self) -> u8 {
+        (self.standby & 0x7) << 5 | (self.filter & 0x7) << 2 | SPI3W_EN
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+const MODE: u8 = 0x3; // ノーマルモード
 const SPI3W_EN: u8 = 0; // SPI3Wire enable = desable
-const CONFIG_WDATA: u8 = T_SB << 5 | FILTER << 2 | SPI3W_EN;
 
 const CTRL_HUM_REG: u8 = 0xf2;
 const CTRL_MEAS_REG: u8 = 0xf4;
@@ -34,3 +66,17 @@ trait Interface {
     fn read_trim(&mut self, buffer: &mut [u8; 32]);
     fn read_data(&mut self, buffer: &mut [u8; 8]);
 }
+
+// 露点温度(℃)。Magnus の式による
+pub fn dew_point(temperature: f64, humidity: f64) -> f64 {
+    const A: f64 = 17.62;
+    const B: f64 = 243.12;
+    let gamma = libm::log(humidity.max(0.01) / 100.0) + A * temperature / (B + temperature);
+    B * gamma / (A - gamma)
+}
+
+// 絶対湿度(g/m3)
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    let saturation = 6.112 * libm::exp(17.67 * temperature / (temperature + 243.5)); // hPa
+    saturation * humidity * 2.1674 / (273.15 + temperature)
+}
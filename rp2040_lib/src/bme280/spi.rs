@@ -10,15 +10,14 @@ use embedded_hal::digital::OutputPin;
 use crate::bme280::ID_CODE;
 use crate::bme280::ID_REG;
 
+use crate::bme280::Config;
+
 use crate::bme280::CALIBRATION_OFFSET_H1;
 use crate::bme280::CALIBRATION_OFFSET_H2;
 use crate::bme280::CALIBRATION_OFFSET_T_P;
 use crate::bme280::CONFIG_REG;
-use crate::bme280::CONFIG_WDATA;
 use crate::bme280::CTRL_HUM_REG;
-use crate::bme280::CTRL_HUM_WDATA;
 use crate::bme280::CTRL_MEAS_REG;
-use crate::bme280::CTRL_MEAS_WDATA;
 use crate::bme280::PRESS_MSB_REG;
 
 pub struct BME280<IF, GPIO>
@@ -51,6 +50,7 @@ where
     pub pres_raw: u32,
     pub humi_raw: u32,
     t_fine: i32,
+    config: Config,
 }
 
 impl<IF, GPIO> BME280<IF, GPIO>
@@ -85,8 +85,14 @@ where
             pres_raw: 0,
             humi_raw: 0,
             t_fine: 0,
+            config: Config::DEFAULT,
         }
     }
+
+    // init() の前に呼ぶ
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
     pub fn init(&mut self) -> bool {
         let _ = self.interface.cs.set_high(); // デバイスを非選択にしておく
 
@@ -95,10 +101,12 @@ where
             return false;
         }
 
-        self.interface.write_register(CTRL_HUM_REG, CTRL_HUM_WDATA);
         self.interface
-            .write_register(CTRL_MEAS_REG, CTRL_MEAS_WDATA);
-        self.interface.write_register(CONFIG_REG, CONFIG_WDATA);
+            .write_register(CTRL_HUM_REG, self.config.ctrl_hum());
+        self.interface
+            .write_register(CTRL_MEAS_REG, self.config.ctrl_meas());
+        self.interface
+            .write_register(CONFIG_REG, self.config.config());
         self.read_trim();
         true
     }
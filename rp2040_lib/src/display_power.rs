@@ -0,0 +1,179 @@
+// LCD の表示とバックライトの明るさを決める
+// スイッチが idle_timeout 秒押されなければ消し、次に押されたら点ける（そのスイッチはメニューには渡さない）
+// 夜間 (night_start〜night_end, 地方時) は night_brightness に暗くする
+
+use crate::rtc8564::Time;
+
+const MICROS_PER_SECOND: u64 = 1_000_000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct PowerConfig {
+    pub idle_timeout: u32,    // 秒。0 なら消さない
+    pub brightness: u8,       // %
+    pub night_brightness: u8, // %
+    pub night_start: u16,     // 0時からの分。night_end と同じなら暗くしない
+    pub night_end: u16,
+}
+
+impl PowerConfig {
+    pub const DEFAULT: PowerConfig = PowerConfig {
+        idle_timeout: 0,
+        brightness: 100,
+        night_brightness: 100,
+        night_start: 0,
+        night_end: 0,
+    };
+
+    // 地方時 time が夜間か
+    pub fn is_night(&self, time: &Time) -> bool {
+        let minutes = time.hour as u16 * 60 + time.minute as u16;
+        let (start, end) = (self.night_start, self.night_end);
+        if start == end {
+            false
+        } else if start < end {
+            (start..end).contains(&minutes)
+        } else {
+            // 0時をまたぐ
+            minutes >= start || minutes < end
+        }
+    }
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+pub struct DisplayPower {
+    config: PowerConfig,
+    on: bool,
+    last_activity: u64, // 最後にスイッチが押された時刻 (usec)
+    shown: Option<u8>,  // 最後に返した明るさ
+}
+
+impl DisplayPower {
+    pub const fn new(config: PowerConfig) -> Self {
+        Self {
+            config,
+            on: true,
+            last_activity: 0,
+            shown: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: PowerConfig) {
+        self.config = config;
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    // スイッチが押されたら呼ぶ。表示が消えていたら点けて false を返す（押したことにはしない）
+    pub fn activity(&mut self, now_us: u64) -> bool {
+        self.last_activity = now_us;
+        let was_on = self.on;
+        self.on = true;
+        was_on
+    }
+
+    // 毎回呼ぶ。明るさ (%、0 は消す) が変わったときだけ返す
+    pub fn update(&mut self, now_us: u64, local: &Time) -> Option<u8> {
+        let timeout = self.config.idle_timeout as u64 * MICROS_PER_SECOND;
+        if timeout > 0 && now_us.wrapping_sub(self.last_activity) >= timeout {
+            self.on = false;
+        }
+        let level = if !self.on {
+            0
+        } else if self.config.is_night(local) {
+            self.config.night_brightness
+        } else {
+            self.config.brightness
+        };
+        if self.shown == Some(level) {
+            return None;
+        }
+        self.shown = Some(level);
+        Some(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = MICROS_PER_SECOND;
+
+    fn at(hour: u8, minute: u8) -> Time {
+        Time::new(2024, 6, 25, hour, minute, 0)
+    }
+
+    fn night(start: u16, end: u16) -> PowerConfig {
+        PowerConfig {
+            brightness: 80,
+            night_brightness: 10,
+            night_start: start,
+            night_end: end,
+            ..PowerConfig::DEFAULT
+        }
+    }
+
+    #[test]
+    fn night_window() {
+        let c = night(22 * 60, 6 * 60); // 0時をまたぐ
+        assert!(!c.is_night(&at(21, 59)));
+        assert!(c.is_night(&at(22, 0)));
+        assert!(c.is_night(&at(0, 0)));
+        assert!(c.is_night(&at(5, 59)));
+        assert!(!c.is_night(&at(6, 0)));
+
+        let c = night(60, 5 * 60);
+        assert!(!c.is_night(&at(0, 59)));
+        assert!(c.is_night(&at(1, 0)));
+        assert!(!c.is_night(&at(5, 0)));
+
+        assert!(!PowerConfig::DEFAULT.is_night(&at(0, 0)));
+    }
+
+    #[test]
+    fn brightness_is_reported_only_when_it_changes() {
+        let mut p = DisplayPower::new(night(22 * 60, 6 * 60));
+        assert_eq!(p.update(0, &at(21, 0)), Some(80));
+        assert_eq!(p.update(SECOND, &at(21, 0)), None);
+        assert_eq!(p.update(2 * SECOND, &at(22, 0)), Some(10));
+        assert_eq!(p.update(3 * SECOND, &at(6, 0)), Some(80));
+    }
+
+    #[test]
+    fn idle_timeout_turns_off_and_switch_wakes_up() {
+        let config = PowerConfig {
+            idle_timeout: 30,
+            ..PowerConfig::DEFAULT
+        };
+        let mut p = DisplayPower::new(config);
+        let noon = at(12, 0);
+        assert!(p.activity(5 * SECOND));
+        assert_eq!(p.update(5 * SECOND, &noon), Some(100));
+        assert_eq!(p.update(34 * SECOND, &noon), None);
+        assert!(p.is_on());
+        assert_eq!(p.update(35 * SECOND, &noon), Some(0));
+        assert!(!p.is_on());
+
+        // 消えているときのスイッチは点けるだけ
+        assert!(!p.activity(40 * SECOND));
+        assert!(p.is_on());
+        assert_eq!(p.update(40 * SECOND, &noon), Some(100));
+        assert!(p.activity(50 * SECOND));
+        assert_eq!(p.update(79 * SECOND, &noon), None);
+        assert_eq!(p.update(80 * SECOND, &noon), Some(0));
+    }
+
+    #[test]
+    fn zero_timeout_never_turns_off() {
+        let mut p = DisplayPower::new(PowerConfig::DEFAULT);
+        assert_eq!(p.update(0, &at(12, 0)), Some(100));
+        assert_eq!(p.update(u64::MAX, &at(12, 0)), None);
+        assert!(p.is_on());
+    }
+}
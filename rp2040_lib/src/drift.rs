@@ -0,0 +1,358 @@
+// RTC の進み・遅れを外部の基準時刻（UART から入力した時刻や GPS の NMEA）と比べて求め、
+// 定期的に RTC を補正する
+
+use crate::rtc::Rtc;
+use crate::rtc8564::Time;
+
+const MIN_MEASURE_SECONDS: u32 = 6 * 3600; // これより短い間隔では ppm を計算しない
+const PPM_SCALE: i64 = 100_000_000; // ppm_x100 から秒への換算 (100 * 1_000_000)
+const MAX_PPM_X100: i32 = 50_000; // ±500ppm を超えるのは基準時刻の誤りとみなす
+const RESYNC_SECONDS: i64 = 2; // 測定中でも RTC がこれ以上ずれていたら合わせ直す
+
+const RECORD_MAGIC: [u8; 4] = *b"DRFT";
+pub const RECORD_SIZE: usize = 16;
+
+pub struct DriftTracker {
+    ppm_x100: i32,             // 100倍した ppm。正なら RTC が進む
+    reference_unix: u32,       // 最後に基準時刻に合わせた時刻
+    last_correction_unix: u32, // 最後に補正した時刻
+    corrected_seconds: i32,    // 基準時刻に合わせてから補正した秒数の合計（正なら戻した）
+}
+
+impl DriftTracker {
+    pub fn new() -> Self {
+        Self {
+            ppm_x100: 0,
+            reference_unix: 0,
+            last_correction_unix: 0,
+            corrected_seconds: 0,
+        }
+    }
+
+    pub fn ppm_x100(&self) -> i32 {
+        self.ppm_x100
+    }
+
+    // 基準時刻を受け取ったときに呼ぶ。前回からの経過時間が十分長ければ ppm を更新し、
+    // RTC を基準時刻に合わせて次の測定を始める。ppm を更新したら true を返す
+    // GPS は毎秒送ってくるので、測定中は RTC が大きくずれていない限り何もしない
+    pub fn on_reference<R: Rtc + ?Sized>(&mut self, reference: &Time, rtc: &mut R) -> bool {
+        let reference_unix = reference.to_unix();
+        let rtc_unix = rtc.now().to_unix();
+        let offset = rtc_unix as i64 - reference_unix as i64;
+
+        let measuring = self.reference_unix != 0 && reference_unix >= self.reference_unix;
+        let elapsed = reference_unix.wrapping_sub(self.reference_unix);
+        if measuring && elapsed < MIN_MEASURE_SECONDS && offset.abs() < RESYNC_SECONDS {
+            return false;
+        }
+
+        let mut updated = false;
+        if measuring && elapsed >= MIN_MEASURE_SECONDS {
+            // 補正しなかった場合のずれ
+            let error = offset + self.corrected_seconds as i64;
+            let ppm_x100 = error * PPM_SCALE / elapsed as i64;
+            if ppm_x100.abs() <= MAX_PPM_X100 as i64 {
+                self.ppm_x100 = ppm_x100 as i32;
+                updated = true;
+            }
+        }
+
+        if offset != 0 {
+            rtc.set(reference);
+        }
+        self.reference_unix = reference_unix;
+        self.corrected_seconds = 0;
+        self.last_correction_unix = reference_unix;
+        updated
+    }
+
+    // 利用者が RTC を手で合わせたときに呼ぶ。合わせた分をずれと数えないように測定をやり直す
+    // 補正は合わせた時刻から続ける
+    pub fn restart<R: Rtc + ?Sized>(&mut self, rtc: &mut R) {
+        self.reference_unix = 0;
+        self.corrected_seconds = 0;
+        self.resume(rtc);
+    }
+
+    // 定期的に呼ぶ。ずれが1秒以上たまったら RTC を1秒単位で補正し、true を返す
+    // RTC の分周器がリセットされるので、秒が変わった直後に呼ぶこと
+    pub fn apply<R: Rtc + ?Sized>(&mut self, rtc: &mut R) -> bool {
+        if self.ppm_x100 == 0 || self.last_correction_unix == 0 {
+            return false;
+        }
+        let now = rtc.now().to_unix();
+        if now <= self.last_correction_unix {
+            return false;
+        }
+        let elapsed = (now - self.last_correction_unix) as i64;
+        let drift = elapsed * self.ppm_x100 as i64 / PPM_SCALE;
+        if drift != 0 {
+            let corrected = (now as i64 - drift) as u32;
+            rtc.set(&Time::from_unix(corrected));
+            self.corrected_seconds += drift as i32;
+            self.last_correction_unix = corrected;
+            return true;
+        }
+        false
+    }
+
+    // SDカードなどに保存するための形式
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&RECORD_MAGIC);
+        bytes[4..8].copy_from_slice(&self.ppm_x100.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.reference_unix.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.corrected_seconds.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < RECORD_SIZE || bytes[0..4] != RECORD_MAGIC {
+            return None;
+        }
+        let ppm_x100 = i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let reference_unix = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+        let corrected_seconds = i32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        if ppm_x100.abs() > MAX_PPM_X100 {
+            return None;
+        }
+        Some(Self {
+            ppm_x100,
+            reference_unix,
+            last_correction_unix: 0, // resume() で再開する
+            corrected_seconds,
+        })
+    }
+
+    // 起動後、RTC の現在時刻から補正を再開する
+    // 電源断の間の分は補正できないので、その分は次の基準時刻で測り直される
+    pub fn resume<R: Rtc + ?Sized>(&mut self, rtc: &mut R) {
+        self.last_correction_unix = rtc.now().to_unix();
+    }
+}
+
+impl Default for DriftTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// UART から受け取った1行を基準時刻として解釈する（UTC）
+// "2024-06-25T09:42:00" または GPS の "$GPRMC,..." / "$GNRMC,..."
+pub fn parse_reference(line: &str) -> Option<Time> {
+    let line = line.trim();
+    if line.starts_with('$') {
+        parse_nmea_rmc(line)
+    } else {
+        Time::from_iso8601(line)
+    }
+}
+
+pub fn parse_nmea_rmc(sentence: &str) -> Option<Time> {
+    let (body, checksum) = match sentence.trim().strip_prefix('$')?.split_once('*') {
+        Some((body, checksum)) => (body, Some(checksum)),
+        None => (sentence.trim().strip_prefix('$')?, None),
+    };
+    if let Some(checksum) = checksum {
+        let expected = u8::from_str_radix(checksum.get(0..2)?, 16).ok()?;
+        let sum = body.bytes().fold(0u8, |acc, b| acc ^ b);
+        if sum != expected {
+            return None;
+        }
+    }
+
+    let mut fields = body.split(',');
+    let talker = fields.next()?;
+    if talker.len() != 5 || !talker.ends_with("RMC") {
+        return None;
+    }
+    let time = fields.next()?.as_bytes();
+    let status = fields.next()?;
+    if status != "A" || time.len() < 6 {
+        return None; // 測位できていない
+    }
+    let date = fields.nth(6)?.as_bytes();
+    if date.len() != 6 {
+        return None;
+    }
+
+    let t = Time::new(
+        2000 + two_digits(&date[4..6])? as u16,
+        two_digits(&date[2..4])?,
+        two_digits(&date[0..2])?,
+        two_digits(&time[0..2])?,
+        two_digits(&time[2..4])?,
+        two_digits(&time[4..6])?,
+    );
+    if t.is_valid() {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+fn two_digits(b: &[u8]) -> Option<u8> {
+    if b[0].is_ascii_digit() && b[1].is_ascii_digit() {
+        Some((b[0] - b'0') * 10 + (b[1] - b'0'))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::RtcStatus;
+
+    struct MockRtc {
+        unix: u32,
+        sets: u32,
+    }
+
+    impl Rtc for MockRtc {
+        fn now(&mut self) -> Time {
+            Time::from_unix(self.unix)
+        }
+
+        fn set(&mut self, time: &Time) {
+            self.unix = time.to_unix();
+            self.sets += 1;
+        }
+
+        fn status(&mut self) -> RtcStatus {
+            RtcStatus::Running
+        }
+    }
+
+    const START: u32 = 1_700_000_000;
+
+    #[test]
+    fn references_within_the_window_are_ignored() {
+        let mut rtc = MockRtc {
+            unix: START + 5,
+            sets: 0,
+        };
+        let mut drift = DriftTracker::new();
+        assert!(!drift.on_reference(&Time::from_unix(START), &mut rtc));
+        assert_eq!((rtc.unix, rtc.sets), (START, 1));
+
+        // 毎秒の基準時刻。RTC が1秒ずれても測定の区切りまでは合わせない
+        for t in 1..3600 {
+            rtc.unix = START + t + 1;
+            assert!(!drift.on_reference(&Time::from_unix(START + t), &mut rtc));
+        }
+        assert_eq!(rtc.sets, 1);
+    }
+
+    #[test]
+    fn large_offset_restarts_the_measurement() {
+        let mut rtc = MockRtc {
+            unix: START,
+            sets: 0,
+        };
+        let mut drift = DriftTracker::new();
+        drift.on_reference(&Time::from_unix(START), &mut rtc);
+        rtc.unix = START + 100 + RESYNC_SECONDS as u32;
+        assert!(!drift.on_reference(&Time::from_unix(START + 100), &mut rtc));
+        assert_eq!((rtc.unix, rtc.sets), (START + 100, 1));
+        assert_eq!(drift.reference_unix, START + 100);
+    }
+
+    #[test]
+    fn ppm_is_measured_after_the_window() {
+        let mut rtc = MockRtc {
+            unix: START,
+            sets: 0,
+        };
+        let mut drift = DriftTracker::new();
+        drift.on_reference(&Time::from_unix(START), &mut rtc);
+
+        // 50000秒で1秒進む = 20ppm
+        let elapsed = 50_000;
+        rtc.unix = START + elapsed + 1;
+        assert!(drift.on_reference(&Time::from_unix(START + elapsed), &mut rtc));
+        assert_eq!(drift.ppm_x100(), 2000);
+        assert_eq!(rtc.unix, START + elapsed);
+
+        // 補正すると RTC を1秒戻す
+        rtc.unix += 50_000;
+        assert!(drift.apply(&mut rtc));
+        assert_eq!(rtc.unix, START + 2 * elapsed - 1);
+    }
+
+    #[test]
+    fn manual_set_restarts_the_measurement() {
+        let mut rtc = MockRtc {
+            unix: START,
+            sets: 0,
+        };
+        let mut drift = DriftTracker::new();
+        drift.on_reference(&Time::from_unix(START), &mut rtc);
+
+        // 6時間後に利用者が5秒合わせた。基準時刻と比べても 231ppm とは数えない
+        let elapsed = MIN_MEASURE_SECONDS;
+        rtc.unix = START + elapsed + 5;
+        rtc.set(&Time::from_unix(START + elapsed));
+        drift.restart(&mut rtc);
+        assert_eq!((drift.reference_unix, drift.corrected_seconds), (0, 0));
+        assert!(!drift.on_reference(&Time::from_unix(START + elapsed), &mut rtc));
+        assert_eq!(drift.ppm_x100(), 0);
+        assert_eq!(drift.reference_unix, START + elapsed);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut drift = DriftTracker::new();
+        drift.ppm_x100 = -1234;
+        drift.reference_unix = START;
+        drift.corrected_seconds = -3;
+        let restored = DriftTracker::from_bytes(&drift.to_bytes()).unwrap();
+        assert_eq!(restored.ppm_x100, -1234);
+        assert_eq!(restored.reference_unix, START);
+        assert_eq!(restored.corrected_seconds, -3);
+        assert!(DriftTracker::from_bytes(&[0; RECORD_SIZE]).is_none());
+    }
+
+    #[test]
+    fn nmea_rmc() {
+        let t = parse_reference("$GPRMC,094200.00,A,3539.0,N,13945.0,E,0.0,0.0,250624,,,A*6E");
+        assert!(t.is_none(), "checksum must be verified");
+        let t =
+            parse_reference("$GPRMC,094200.00,A,3539.0,N,13945.0,E,0.0,0.0,250624,,,A").unwrap();
+        assert_eq!((t.year, t.month, t.day), (2024, 6, 25));
+        assert_eq!((t.hour, t.minute, t.second), (9, 42, 0));
+        assert!(parse_reference("$GPRMC,094200.00,V,,,,,,,250624,,,N").is_none());
+    }
+
+    #[test]
+    fn nmea_rmc_with_checksum() {
+        let t = parse_reference("$GPRMC,094200.00,A,3539.0,N,13945.0,E,0.0,0.0,250624,,,A*50\r\n")
+            .unwrap();
+        assert_eq!((t.year, t.month, t.day), (2024, 6, 25));
+        assert_eq!((t.hour, t.minute, t.second), (9, 42, 0));
+        let t =
+            parse_reference("$GNRMC,235959.00,A,3539.0,N,13945.0,E,0.0,0.0,311224,,,A*40").unwrap();
+        assert_eq!((t.year, t.month, t.day), (2024, 12, 31));
+        assert_eq!((t.hour, t.minute, t.second), (23, 59, 59));
+        assert!(
+            parse_reference("$GNRMC,235959.00,A,3539.0,N,13945.0,E,0.0,0.0,311224,,,A*4").is_none()
+        );
+    }
+
+    #[test]
+    fn nmea_rmc_with_non_digits() {
+        // チェックサムは合っているが、時刻や日付に数字でない文字がある
+        assert!(
+            parse_reference("$GPRMC,09x200.00,A,3539.0,N,13945.0,E,0.0,0.0,250624,,,A*1C")
+                .is_none()
+        );
+        assert!(
+            parse_reference("$GPRMC,094200.00,A,3539.0,N,13945.0,E,0.0,0.0,25O624,,,A*2F")
+                .is_none()
+        );
+        assert_eq!(two_digits(b"42"), Some(42));
+        assert_eq!(two_digits(b"4-"), None);
+        assert_eq!(two_digits(b" 4"), None);
+    }
+}
@@ -0,0 +1,163 @@
+use embedded_hal::i2c::I2c;
+
+use crate::rtc::bcd_to_bin;
+use crate::rtc::bin_to_bcd;
+use crate::rtc::Rtc;
+use crate::rtc::RtcStatus;
+use crate::rtc8564::weekday;
+use crate::rtc8564::Time;
+
+pub const DS3231_DEVICE_ADDRESS: u8 = 0x68;
+
+pub const SECONDS_REG: u8 = 0x00;
+pub const MINUTES_REG: u8 = 0x01;
+pub const HOURS_REG: u8 = 0x02;
+pub const DAY_REG: u8 = 0x03;
+pub const DATE_REG: u8 = 0x04;
+pub const MONTH_CENTURY_REG: u8 = 0x05;
+pub const YEAR_REG: u8 = 0x06;
+pub const CONTROL_REG: u8 = 0x0e;
+pub const STATUS_REG: u8 = 0x0f;
+pub const TEMP_MSB_REG: u8 = 0x11;
+
+const EOSC_N: u8 = 0x0; // 0 で発振器を動作させる
+const INTCN: u8 = 0x1; // INT/SQW ピンを割り込み出力にする
+const RS2_RS1: u8 = 0x3;
+const CONTROL_WRITE_DATA: u8 = EOSC_N << 7 | RS2_RS1 << 3 | INTCN << 2;
+
+const OSF: u8 = 0x80; // 1 で発振器が停止したことがある
+const BSY: u8 = 0x04; // 1 で温度変換中
+
+const HOURS_12_24: u8 = 0x40; // 1 で12時間表記
+
+// 年レジスタが 99 から 00 に桁上がりすると CENTURY ビットが 1 になる
+const CENTURY_BIT: u8 = 0x80;
+const BASE_YEAR: u16 = 2000;
+
+pub struct DS3231<IF: I2c> {
+    interface: IF,
+    address: u8,
+}
+
+impl<IF: I2c> DS3231<IF> {
+    pub fn new(interface: IF, address: u8) -> Self {
+        Self { interface, address }
+    }
+
+    pub fn init(&mut self) -> bool {
+        self.write_register(CONTROL_REG, CONTROL_WRITE_DATA);
+        let status = self.read_register(STATUS_REG);
+        if (status & OSF) == OSF {
+            // 発振器が停止していたので時刻は信頼できない。フラグだけ落としておく
+            self.write_register(STATUS_REG, status & !OSF);
+            return false;
+        }
+        true
+    }
+
+    pub fn is_present(&mut self) -> bool {
+        let mut buffer: [u8; 1] = [0; 1];
+        self.interface
+            .write_read(self.address, &[STATUS_REG], &mut buffer)
+            .is_ok()
+    }
+
+    fn read_register(&mut self, register: u8) -> u8 {
+        let mut buffer: [u8; 1] = [0; 1];
+        let _ = self
+            .interface
+            .write_read(self.address, &[register], &mut buffer);
+        buffer[0]
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) {
+        let _ = self.interface.write(self.address, &[register, value]);
+    }
+
+    pub fn read_date_time(&mut self) -> Time {
+        // 秒から年までを一度に読むので、途中で桁上がりしても食い違わない
+        let mut buffer: [u8; 7] = [0; 7];
+        let _ = self
+            .interface
+            .write_read(self.address, &[SECONDS_REG], &mut buffer);
+
+        let hours = buffer[2];
+        let h = if (hours & HOURS_12_24) == HOURS_12_24 {
+            let h12 = bcd_to_bin(hours & 0x1f) % 12;
+            if (hours & 0x20) == 0x20 {
+                h12 + 12 // PM
+            } else {
+                h12
+            }
+        } else {
+            bcd_to_bin(hours & 0x3f)
+        };
+
+        let mut y = bcd_to_bin(buffer[6]) as u16 + BASE_YEAR;
+        if (buffer[5] & CENTURY_BIT) == CENTURY_BIT {
+            y += 100;
+        }
+
+        Time::new(
+            y,
+            bcd_to_bin(buffer[5] & 0x1f),
+            bcd_to_bin(buffer[4] & 0x3f),
+            h,
+            bcd_to_bin(buffer[1] & 0x7f),
+            bcd_to_bin(buffer[0] & 0x7f),
+        )
+    }
+
+    // 内蔵温度センサーの値(℃)、分解能は 0.25℃
+    pub fn temperature(&mut self) -> f64 {
+        let mut buffer: [u8; 2] = [0; 2];
+        let _ = self
+            .interface
+            .write_read(self.address, &[TEMP_MSB_REG], &mut buffer);
+        let msb = buffer[0] as i8;
+        (msb as f64) + ((buffer[1] >> 6) as f64) * 0.25
+    }
+
+    pub fn temperature_busy(&mut self) -> bool {
+        (self.read_register(STATUS_REG) & BSY) == BSY
+    }
+}
+
+impl<IF: I2c> Rtc for DS3231<IF> {
+    fn now(&mut self) -> Time {
+        self.read_date_time()
+    }
+
+    fn set(&mut self, time: &Time) {
+        let mut month = bin_to_bcd(time.month);
+        if time.year >= BASE_YEAR + 100 {
+            month |= CENTURY_BIT;
+        }
+        let _ = self.interface.write(
+            self.address,
+            &[
+                SECONDS_REG,
+                bin_to_bcd(time.second),
+                bin_to_bcd(time.minute),
+                bin_to_bcd(time.hour),                        // 24時間表記
+                weekday(time.year, time.month, time.day) + 1, // 1..7
+                bin_to_bcd(time.day),
+                month,
+                bin_to_bcd((time.year % 100) as u8),
+            ],
+        );
+        let status = self.read_register(STATUS_REG);
+        self.write_register(STATUS_REG, status & !OSF);
+    }
+
+    fn status(&mut self) -> RtcStatus {
+        if (self.read_register(STATUS_REG) & OSF) == OSF {
+            return RtcStatus::VoltageLow;
+        }
+        if (self.read_register(CONTROL_REG) & 0x80) == 0x80 {
+            // EOSC が 1 だとバックアップ電源では計時しない
+            return RtcStatus::Stopped;
+        }
+        RtcStatus::Running
+    }
+}
@@ -0,0 +1,172 @@
+// LCD の CGRAM に置く自作の文字
+// HD44780 の CGRAM には8文字しか置けないので、画面ごとに GlyphSet を選んで Frame::set_glyphs() で指定する
+// 置いていない字形は ROM の似た文字で代用する
+
+use crate::sc2004::Frame;
+use crate::sc2004::POCHI_CODE;
+
+// CGRAM に置ける文字の数
+pub const CGRAM_GLYPHS: usize = 8;
+
+// 5x8 ドットの字形。1行目から順に、下位5ビットが左から右
+pub type Pattern = [u8; 8];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Glyph {
+    Degree,
+    TrendUp,
+    TrendDown,
+    Sd,
+    Battery,
+    Alarm,
+    Bar(u8), // 下から n/8 を塗った棒 (0〜8)
+}
+
+// 計測値の画面など、普段使う字形
+pub static STATUS: [Glyph; 6] = [
+    Glyph::Degree,
+    Glyph::TrendUp,
+    Glyph::TrendDown,
+    Glyph::Sd,
+    Glyph::Battery,
+    Glyph::Alarm,
+];
+
+// 計測値の画面用。STATUS に棒を2段だけ足し、スパークラインは ROM の全部塗った棒と合わせて3段で描く
+pub static ELEMENTS: [Glyph; 8] = [
+    Glyph::Degree,
+    Glyph::TrendUp,
+    Glyph::TrendDown,
+    Glyph::Sd,
+    Glyph::Battery,
+    Glyph::Alarm,
+    Glyph::Bar(2),
+    Glyph::Bar(5),
+];
+
+// 棒グラフ用。空白と全部塗った棒は ROM の文字を使うので 1/8〜7/8 だけを置く
+pub static BARS: [Glyph; 8] = [
+    Glyph::Bar(1),
+    Glyph::Bar(2),
+    Glyph::Bar(3),
+    Glyph::Bar(4),
+    Glyph::Bar(5),
+    Glyph::Bar(6),
+    Glyph::Bar(7),
+    Glyph::Degree,
+];
+
+const FULL_BLOCK_CODE: u8 = 0xff; // 全部塗った文字のコード
+
+impl Glyph {
+    pub fn pattern(&self) -> Pattern {
+        match *self {
+            Glyph::Degree => [
+                0b00110, 0b01001, 0b01001, 0b00110, 0b00000, 0b00000, 0b00000, 0b00000,
+            ],
+            Glyph::TrendUp => [
+                0b00100, 0b01110, 0b10101, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000,
+            ],
+            Glyph::TrendDown => [
+                0b00100, 0b00100, 0b00100, 0b00100, 0b10101, 0b01110, 0b00100, 0b00000,
+            ],
+            Glyph::Sd => [
+                0b00111, 0b01011, 0b11111, 0b10001, 0b10001, 0b10001, 0b11111, 0b00000,
+            ],
+            Glyph::Battery => [
+                0b01110, 0b11011, 0b10001, 0b10001, 0b11111, 0b11111, 0b11111, 0b00000,
+            ],
+            Glyph::Alarm => [
+                0b00100, 0b01110, 0b01110, 0b01110, 0b11111, 0b00000, 0b00100, 0b00000,
+            ],
+            Glyph::Bar(n) => {
+                let mut p = [0; 8];
+                for (i, row) in p.iter_mut().enumerate() {
+                    if i + n.min(8) as usize >= 8 {
+                        *row = 0b11111;
+                    }
+                }
+                p
+            }
+        }
+    }
+
+    // CGRAM に無いときに使う ROM の文字
+    pub fn fallback(&self) -> u8 {
+        match *self {
+            Glyph::Degree => POCHI_CODE,
+            Glyph::TrendUp => b'^',
+            Glyph::TrendDown => b'v',
+            Glyph::Sd => b'S',
+            Glyph::Battery => b'B',
+            Glyph::Alarm => b'!',
+            Glyph::Bar(0) => b' ',
+            Glyph::Bar(n) if n < 4 => b'_',
+            Glyph::Bar(_) => FULL_BLOCK_CODE,
+        }
+    }
+}
+
+// values を1文字1つの棒グラフにして col 桁目から並べる
+// 一番小さい値を 1/8、一番大きい値を 8/8 の高さにする（全部同じなら半分）
+// CGRAM に無い高さは、置いてある棒のうち一番近いもので描く
+pub fn sparkline(frame: &mut Frame, col: u8, row: u8, values: &[f64]) {
+    let (min, max) = values
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+    for (i, &v) in values.iter().enumerate() {
+        let level = if max > min {
+            1 + libm::round((v - min) / (max - min) * 7.0) as u8
+        } else {
+            4
+        };
+        frame.put_glyph(col.saturating_add(i as u8), row, bar(frame, level));
+    }
+}
+
+// 高さ level の棒。CGRAM に1つも棒が無ければ fallback() の文字に任せる
+fn bar(frame: &Frame, level: u8) -> Glyph {
+    let loaded = |n: u8| frame.glyph_code(Glyph::Bar(n)) < CGRAM_GLYPHS as u8;
+    if loaded(level) || !(1..8).any(loaded) {
+        return Glyph::Bar(level);
+    }
+    // 全部塗った棒は ROM にあるので常に使える
+    let n = (1..8)
+        .filter(|&n| loaded(n))
+        .chain(core::iter::once(8))
+        .min_by_key(|n| n.abs_diff(level))
+        .unwrap_or(level);
+    Glyph::Bar(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sc2004::Geometry;
+
+    #[test]
+    fn bar_uses_exact_level_when_loaded() {
+        let mut frame = Frame::new(Geometry::LCD2004);
+        frame.set_glyphs(&BARS);
+        for level in 1..=8 {
+            assert!(bar(&frame, level) == Glyph::Bar(level));
+        }
+    }
+
+    #[test]
+    fn bar_picks_nearest_loaded_level() {
+        let mut frame = Frame::new(Geometry::LCD2004);
+        frame.set_glyphs(&ELEMENTS);
+        let expected = [2, 2, 2, 5, 5, 5, 8, 8];
+        for (level, &n) in (1..=8).zip(expected.iter()) {
+            assert!(bar(&frame, level) == Glyph::Bar(n));
+        }
+    }
+
+    #[test]
+    fn bar_falls_back_to_rom_without_bars() {
+        let frame = Frame::new(Geometry::LCD2004);
+        assert!(bar(&frame, 2) == Glyph::Bar(2));
+        assert!(frame.glyph_code(bar(&frame, 2)) == b'_');
+    }
+}
@@ -0,0 +1,194 @@
+// 計測値の履歴を RAM に持つ（電源を切ると消える）
+// SAMPLE_MINUTES 分ごとの平均を24時間分、古いものから上書きして残す
+
+use crate::rtc8564::Time;
+
+// 1つの記録にまとめる時間（分）
+pub const SAMPLE_MINUTES: u32 = 3;
+// 24時間分の記録の数
+pub const HISTORY_LEN: usize = (24 * 60 / SAMPLE_MINUTES) as usize;
+// 温度・湿度・気圧
+pub const CHANNELS: usize = 3;
+
+pub struct History {
+    samples: [[f32; CHANNELS]; HISTORY_LEN],
+    head: usize, // 次に書く位置
+    len: usize,
+    slot: Option<u32>,    // まとめている最中の時間帯
+    sum: [f64; CHANNELS], // まとめている最中の合計
+    count: u32,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self {
+            samples: [[0.0; CHANNELS]; HISTORY_LEN],
+            head: 0,
+            len: 0,
+            slot: None,
+            sum: [0.0; CHANNELS],
+            count: 0,
+        }
+    }
+
+    // 計測するたびに呼ぶ。時間帯が変わったら、それまでの平均を1つの記録にする
+    pub fn record(&mut self, time: &Time, elements: (f64, f64, f64)) {
+        let minutes = (time.day as u32 * 24 + time.hour as u32) * 60 + time.minute as u32;
+        let slot = minutes / SAMPLE_MINUTES;
+        if self.slot != Some(slot) {
+            if self.count > 0 {
+                let mut sample = [0.0; CHANNELS];
+                for (s, sum) in sample.iter_mut().zip(self.sum.iter()) {
+                    *s = (sum / self.count as f64) as f32;
+                }
+                self.samples[self.head] = sample;
+                self.head = (self.head + 1) % HISTORY_LEN;
+                self.len = (self.len + 1).min(HISTORY_LEN);
+            }
+            self.slot = Some(slot);
+            self.sum = [0.0; CHANNELS];
+            self.count = 0;
+        }
+        self.sum[0] += elements.0;
+        self.sum[1] += elements.1;
+        self.sum[2] += elements.2;
+        self.count += 1;
+    }
+
+    // まとめている最中のものも含めた記録の数
+    pub fn len(&self) -> usize {
+        self.len + (self.count > 0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 新しい方から age 番目 (0 が最新) の channel の値
+    pub fn get(&self, age: usize, channel: usize) -> Option<f64> {
+        let age = if self.count > 0 {
+            if age == 0 {
+                return Some(self.sum[channel] / self.count as f64);
+            }
+            age - 1
+        } else {
+            age
+        };
+        if age >= self.len {
+            return None;
+        }
+        let index = (self.head + HISTORY_LEN - 1 - age) % HISTORY_LEN;
+        Some(self.samples[index][channel] as f64)
+    }
+
+    // 直近 minutes 分を out の長さに分けて平均し、古い方から out の先頭に入れる
+    // 記録の無い古い分は省くので、入れた数を返す
+    pub fn series(&self, channel: usize, minutes: u32, out: &mut [f64]) -> usize {
+        let width = out.len();
+        let samples = ((minutes / SAMPLE_MINUTES) as usize).min(HISTORY_LEN);
+        let mut filled = 0;
+        // 新しい桁から順に埋める
+        for column in (0..width).rev() {
+            // この桁にまとめる記録の範囲 (古い方から数えた位置)
+            let from = column * samples / width;
+            let to = ((column + 1) * samples / width).max(from + 1);
+            let mut sum = 0.0;
+            let mut count = 0;
+            for position in from..to {
+                if let Some(v) = self.get(samples - 1 - position, channel) {
+                    sum += v;
+                    count += 1;
+                }
+            }
+            if count == 0 {
+                break;
+            }
+            out[column] = sum / count as f64;
+            filled += 1;
+        }
+        out.copy_within(width - filled.., 0);
+        filled
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u32 = 1_719_273_600; // 2024-06-25 00:00:00
+
+    // i 番目の時間帯に値 i を1回ずつ記録する
+    fn filled(slots: u32) -> History {
+        let mut history = History::new();
+        for i in 0..slots {
+            let time = Time::from_unix(START + i * SAMPLE_MINUTES * 60);
+            history.record(&time, (i as f64, 50.0, 1000.0 + i as f64));
+        }
+        history
+    }
+
+    #[test]
+    fn empty() {
+        let history = History::new();
+        assert!(history.is_empty());
+        assert_eq!(history.get(0, 0), None);
+        let mut out = [0.0; 4];
+        assert_eq!(history.series(0, 60, &mut out), 0);
+    }
+
+    #[test]
+    fn averages_within_a_slot() {
+        let mut history = History::new();
+        for (minute, t) in [(0, 20.0), (1, 21.0), (2, 25.0)] {
+            let time = Time::from_unix(START + minute * 60);
+            history.record(&time, (t, 40.0 + t, 1000.0));
+        }
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get(0, 0), Some(22.0));
+        assert_eq!(history.get(0, 1), Some(62.0));
+
+        // 次の時間帯に入ると平均を1つの記録にする
+        history.record(&Time::from_unix(START + 3 * 60), (30.0, 0.0, 0.0));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0, 0), Some(30.0));
+        assert_eq!(history.get(1, 0), Some(22.0));
+        assert_eq!(history.get(2, 0), None);
+    }
+
+    #[test]
+    fn ring_wraps_after_24_hours() {
+        let slots = HISTORY_LEN as u32 + 10;
+        let history = filled(slots);
+        // HISTORY_LEN 個の記録と、まとめている最中の1つ
+        assert_eq!(history.len(), HISTORY_LEN + 1);
+        let newest = (slots - 1) as f64;
+        assert_eq!(history.get(0, 0), Some(newest));
+        assert_eq!(history.get(1, 0), Some(newest - 1.0));
+        assert_eq!(
+            history.get(HISTORY_LEN, 0),
+            Some(newest - HISTORY_LEN as f64)
+        );
+        assert_eq!(history.get(HISTORY_LEN + 1, 0), None);
+        assert_eq!(history.get(5, 2), Some(1000.0 + newest - 5.0));
+    }
+
+    #[test]
+    fn series_averages_columns() {
+        let history = filled(6); // 0..5、最新が 5
+        let mut out = [0.0; 2];
+        // 12分 = 4個を2桁に
+        assert_eq!(history.series(0, 12, &mut out), 2);
+        assert_eq!(out, [2.5, 4.5]);
+
+        // 60分 = 20個を4桁に。記録のある新しい2桁だけを先頭に詰める
+        let mut out = [-1.0; 4];
+        assert_eq!(history.series(0, 60, &mut out), 2);
+        assert_eq!(out[..2], [0.0, 3.0]);
+    }
+}
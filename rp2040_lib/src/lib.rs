@@ -1,23 +1,35 @@
 #![no_std]
 
 pub mod bme280;
+pub mod display_power;
+pub mod drift;
+pub mod ds3231;
+pub mod glyph;
+pub mod history;
+pub mod logger;
+pub mod min_max;
 pub mod my_macro;
+#[cfg(not(test))] // ホストでのテストでは std の panic を使う
 pub mod panic;
+pub mod preferences;
+pub mod rp2040_rtc;
+pub mod rtc;
 pub mod rtc8564;
 pub mod sc2004;
+pub mod schedule;
+pub mod sd;
+pub mod settings;
+pub mod tz;
+pub mod ui;
 
-pub enum ScreenState {
-    Top,
-    Elements,
-    SetDateTime,
-}
-
+#[derive(Clone, Copy, PartialEq)]
 pub enum SW {
     Center,
     Down,
     Left,
     Right,
     Up,
+    CenterLong, // Center の長押し
     None,
 }
 
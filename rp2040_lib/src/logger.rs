@@ -0,0 +1,1764 @@
+// 計測値を SDカードに保存する
+// BlockDevice と TimeSource を差し替えられるので、ホスト上でもメモリ上のブロックデバイスで動かせる
+
+use core::fmt;
+use core::fmt::Write;
+
+use embedded_sdmmc::{
+    BlockDevice, DirEntry, Error, Mode, RawDirectory, RawFile, RawVolume, ShortFileName,
+    TimeSource, VolumeIdx, VolumeManager,
+};
+
+use binlog::{
+    crc16_update, FileHeader, Record, CRC16_INIT, HEADER_SIZE, RECORD_SIZE, TORN_LINE_MARKER,
+};
+
+use crate::bme280::absolute_humidity;
+use crate::bme280::dew_point;
+use crate::rtc8564::Time;
+use crate::tz::UtcOffset;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text, // 2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013.2
+    Csv,
+    Tsv,
+    Binary, // binlog クレートの形式。ホストでは binlog_decode で CSV / JSON に変換する
+}
+
+impl LogFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "txt",
+            LogFormat::Csv => "csv",
+            LogFormat::Tsv => "tsv",
+            LogFormat::Binary => "bin",
+        }
+    }
+
+    fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(LogFormat::Text),
+            1 => Some(LogFormat::Csv),
+            2 => Some(LogFormat::Tsv),
+            3 => Some(LogFormat::Binary),
+            _ => None,
+        }
+    }
+
+    fn separator(&self) -> char {
+        match self {
+            LogFormat::Tsv => '\t',
+            _ => ',',
+        }
+    }
+}
+
+// CSV / TSV に追加する列
+#[derive(Clone, Copy, Default)]
+pub struct LogColumns {
+    pub local_time: bool,
+    pub dew_point: bool,
+    pub absolute_humidity: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Measurement {
+    pub time: Time,       // UTC
+    pub utc_offset: i16,  // 記録した時点の地方時と UTC のずれ（分）
+    pub temperature: f64, // ℃
+    pub humidity: f64,    // %
+    pub pressure: f64,    // hPa
+    pub status: u8,       // binlog::FLAG_* の組み合わせ
+}
+
+// SDカードに書けない間に RAM に溜めておく計測値の数（1分毎なら4時間分）
+pub const BACKLOG_SIZE: usize = 240;
+
+// SDカードのブロックの大きさ。書き込みはなるべくこの単位にまとめる
+pub const BLOCK_SIZE: usize = 512;
+const CACHE_SIZE: usize = 4 * BLOCK_SIZE;
+
+// ブロックが埋まらなくても、この間隔（秒）で書き出す
+pub const DEFAULT_FLUSH_INTERVAL: u32 = 15 * 60;
+
+// ログは /LOG/YYYY/MM/DD.CSV に置く（日付は UTC）
+// 1日のファイルが max_file_size を超えたら DD_1.CSV, DD_2.CSV ... に分ける
+pub const LOG_DIR: &str = "LOG";
+const MAX_PARTS: u8 = 99;
+
+// 空き容量の見積もりで、ファイル1つが少なくとも占めるとみなす大きさ（FAT のクラスタ）
+const CLUSTER_ESTIMATE: u64 = 32 * 1024;
+const MAX_SUBDIRS: usize = 32;
+
+// ログファイルの長さは閉じたときにしかディレクトリに書かれないので、書き込む前に
+// 同じデータをジャーナルに追記しておき、電源が切れたら次にマウントしたときに書き直す
+// ファイルを閉じて長さが確定したら (コミット) ジャーナルを空にする
+pub const JOURNAL_FILE: &str = "JOURNAL.BIN";
+const JOURNAL_MAGIC: [u8; 4] = *b"BMEJ";
+const JOURNAL_HEADER_SIZE: usize = 24;
+const JOURNAL_COMMIT: u8 = 0;
+const JOURNAL_DATA: u8 = 1;
+// ジャーナルがこの大きさを超えたら、flush_interval を待たずにコミットする
+const JOURNAL_COMMIT_SIZE: usize = 8 * BLOCK_SIZE;
+
+// マウントしたときにジャーナルから書き直した記録の数と、書き直せずに失った記録の数
+#[derive(Clone, Copy, Default)]
+pub struct Recovery {
+    pub replayed: u32,
+    pub lost: u32,
+}
+
+pub struct Logger<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    vol_man: VolumeManager<D, T>,
+    mounted: Option<(RawVolume, RawDirectory)>, // Volume 0 とそのルートディレクトリ
+    file: Option<RawFile>, // 追記中のファイル。書き込みの合間も開いたままにする
+    path: Option<LogPath>, // cache の中身を書き込むファイル
+    format: LogFormat,
+    columns: LogColumns,
+    backlog: Backlog<BACKLOG_SIZE>,
+    cache: ByteRing<CACHE_SIZE>,
+    flush_interval: u32,
+    last_flush_unix: u32,
+    max_file_size: u32,  // 0 なら分けない
+    min_free_bytes: u64, // 空きがこれを下回ったら古い日のファイルから消す。0 なら消さない
+    seq: u32,            // 最後にジャーナルに書いたものの通し番号
+    journal_len: usize,  // コミットしてからジャーナルに書いた大きさ
+    recovery: Recovery,
+}
+
+impl<D, T> Logger<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    // カードが無くても作れる。mount() に成功するまでは計測値を RAM に溜める
+    pub fn new(vol_man: VolumeManager<D, T>) -> Self {
+        Self {
+            vol_man,
+            mounted: None,
+            file: None,
+            path: None,
+            format: LogFormat::Text,
+            columns: LogColumns::default(),
+            backlog: Backlog::new(),
+            cache: ByteRing::new(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            last_flush_unix: 0,
+            max_file_size: 0,
+            min_free_bytes: 0,
+            seq: 0,
+            journal_len: 0,
+            recovery: Recovery::default(),
+        }
+    }
+
+    // Volume 0 のルートディレクトリを開き、前回書きかけだったものをジャーナルから書き直す
+    pub fn mount(&mut self) -> Result<(), Error<D::Error>> {
+        self.unmount();
+        let vol = self.vol_man.open_raw_volume(VolumeIdx(0))?;
+        match self.vol_man.open_root_dir(vol) {
+            Ok(dir) => {
+                self.mounted = Some((vol, dir));
+                self.recovery = Recovery::default();
+                match self.recover() {
+                    Ok(recovery) => {
+                        self.recovery = recovery;
+                        Ok(())
+                    }
+                    Err(e @ Error::DeviceError(_)) => {
+                        self.unmount();
+                        Err(e)
+                    }
+                    // ジャーナルを書けなくても記録は続ける
+                    Err(_) => Ok(()),
+                }
+            }
+            Err(e) => {
+                let _ = self.vol_man.close_volume(vol);
+                Err(e)
+            }
+        }
+    }
+
+    // 書き出していないものは RAM に残るので、mount() し直せば続きから書ける
+    pub fn unmount(&mut self) {
+        if let Some((vol, dir)) = self.mounted.take() {
+            if let Some(file) = self.file.take() {
+                let _ = self.vol_man.close_file(file);
+            }
+            let _ = self.vol_man.close_dir(dir);
+            let _ = self.vol_man.close_volume(vol);
+        }
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.mounted.is_some()
+    }
+
+    // まだ SDカードに書いていない計測値の数と、溢れて捨てた数
+    pub fn pending(&self) -> usize {
+        self.backlog.len()
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.backlog.dropped
+    }
+
+    // 最後に mount() したときに書き直した記録の数
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    pub fn set_format(&mut self, format: LogFormat, columns: LogColumns) {
+        self.format = format;
+        self.columns = columns;
+    }
+
+    pub fn set_flush_interval(&mut self, seconds: u32) {
+        self.flush_interval = seconds;
+    }
+
+    pub fn set_max_file_size(&mut self, bytes: u32) {
+        self.max_file_size = bytes;
+    }
+
+    pub fn set_min_free_bytes(&mut self, bytes: u64) {
+        self.min_free_bytes = bytes;
+    }
+
+    pub fn volume_manager(&mut self) -> &mut VolumeManager<D, T> {
+        &mut self.vol_man
+    }
+
+    // ルートディレクトリの一覧
+    pub fn iterate_dir<F: FnMut(&DirEntry)>(&mut self, func: F) -> Result<(), Error<D::Error>> {
+        let root = self.root()?;
+        let result = self.vol_man.iterate_dir(root, func);
+        self.check(result)
+    }
+
+    // 計測値を RAM のキャッシュに溜め、ブロック単位にまとまった分だけ書き出す
+    // flush_interval を過ぎたら端数も書き出す
+    pub fn append(&mut self, measurement: &Measurement) -> Result<(), Error<D::Error>> {
+        self.backlog.push(*measurement);
+        let now = measurement.time.to_unix();
+        if self.last_flush_unix == 0 {
+            self.last_flush_unix = now;
+        }
+        let all = now < self.last_flush_unix || now - self.last_flush_unix >= self.flush_interval;
+        let mut result = self.fill_cache();
+        if result.is_ok() {
+            result = self.write_cache(all);
+        }
+        if result.is_ok() && all {
+            self.last_flush_unix = now;
+        }
+        self.check(result)
+    }
+
+    // 溜まっている分をすべて書き出してコミットする
+    pub fn flush(&mut self) -> Result<(), Error<D::Error>> {
+        let mut result = self.fill_cache();
+        if result.is_ok() {
+            result = self.write_cache(true);
+        }
+        self.check(result)
+    }
+
+    // すべて書き出してファイルを閉じる。ファイルの長さは閉じたときに確定するので、
+    // 電源を切る前やカードを抜く前に呼ぶ
+    pub fn sync(&mut self) -> Result<(), Error<D::Error>> {
+        let mut result = self.flush();
+        if result.is_ok() && self.file.is_some() {
+            result = self.commit();
+        }
+        self.close_file();
+        self.check(result)
+    }
+
+    // backlog の計測値を1行ずつ cache に移す。新しいファイルには見出しを付ける
+    fn fill_cache(&mut self) -> Result<(), Error<D::Error>> {
+        while let Some(m) = self.backlog.front() {
+            let path = LogPath::new(&m.time);
+            if !self.path.is_some_and(|p| p.same_day(&path)) {
+                // 日付が変わったら前のファイルを書き終えてから切り替える
+                self.write_cache(true)?;
+                self.close_file();
+                self.path = Some(path);
+                if let Err(e @ Error::DeviceError(_)) = self.enforce_retention() {
+                    return Err(e);
+                }
+            }
+
+            let mut line: LineBuffer<LINE_SIZE> = LineBuffer::new();
+            let record = binary_record(&m);
+            let data = if self.format == LogFormat::Binary {
+                &record[..]
+            } else {
+                let _ = write_record(&mut line, &m, self.format, &self.columns);
+                line.as_bytes()
+            };
+
+            if self.file.is_some() && self.max_file_size != 0 {
+                let length = self.file_length()? as usize;
+                if length + self.cache.len() + data.len() > self.max_file_size as usize {
+                    self.write_cache(true)?;
+                    self.close_file();
+                    self.next_part();
+                }
+            }
+            if self.file.is_none() {
+                match self.open_file(data.len()) {
+                    Ok(created) => {
+                        if created && self.cache.is_empty() {
+                            self.push_header(m.time.to_unix());
+                        }
+                    }
+                    Err(e @ (Error::DeviceError(_) | Error::NoSuchVolume)) => return Err(e),
+                    Err(e) => {
+                        // ディレクトリを作れないなど、やり直しても直らないものは捨てる
+                        self.backlog.pop();
+                        return Err(e);
+                    }
+                }
+            }
+
+            if self.cache.free() < data.len() {
+                self.write_cache(false)?;
+            }
+            if self.cache.free() < data.len() {
+                self.write_cache(true)?;
+            }
+            self.cache.push(data);
+            self.backlog.pop();
+        }
+        Ok(())
+    }
+
+    fn push_header(&mut self, created_unix: u32) {
+        if self.format == LogFormat::Binary {
+            self.cache.push(&FileHeader::new(created_unix).encode());
+        } else if self.format != LogFormat::Text {
+            let mut header: LineBuffer<LINE_SIZE> = LineBuffer::new();
+            let _ = write_header(&mut header, self.format, &self.columns);
+            self.cache.push(header.as_bytes());
+        }
+    }
+
+    // cache をファイルに書く。all でなければファイルの終わりがブロックの境界に揃う分だけ書く
+    // all ならコミットしてファイルの長さを確定させる
+    fn write_cache(&mut self, all: bool) -> Result<(), Error<D::Error>> {
+        if self.cache.is_empty() {
+            return Ok(());
+        }
+        if self.file.is_none() {
+            self.open_file(0)?;
+        }
+        let (file, path) = match (self.file, self.path) {
+            (Some(file), Some(path)) => (file, path),
+            _ => return Ok(()),
+        };
+        let root = self.root()?;
+
+        let length = self.vol_man.file_length(file)? as usize;
+        let mut n = self.cache.len();
+        if !all {
+            let head = (BLOCK_SIZE - length % BLOCK_SIZE) % BLOCK_SIZE;
+            n = if n < head {
+                0
+            } else {
+                head + (n - head) / BLOCK_SIZE * BLOCK_SIZE
+            };
+        }
+        if n == 0 {
+            return Ok(());
+        }
+
+        let entry = JournalEntry {
+            kind: JOURNAL_DATA,
+            format: self.format,
+            path,
+            seq: self.seq.wrapping_add(1),
+            offset: length as u32,
+            len: n as u16,
+        };
+        let (first, second) = self.cache.peek(n);
+        let mut result = Self::write_journal(
+            &mut self.vol_man,
+            root,
+            Mode::ReadWriteCreateOrAppend,
+            &entry,
+            first,
+            second,
+        );
+        let journaled = result.is_ok();
+        if journaled {
+            self.seq = entry.seq;
+            self.journal_len += JOURNAL_HEADER_SIZE + n;
+            result = self.vol_man.write(file, first).map(|_| ());
+            if result.is_ok() && !second.is_empty() {
+                result = self.vol_man.write(file, second).map(|_| ());
+            }
+        }
+        match result {
+            Ok(()) => self.cache.consume(n),
+            // ジャーナルに書けていれば、マウントし直したときに書き直される
+            Err(e @ Error::DeviceError(_)) => {
+                if journaled {
+                    self.cache.consume(n);
+                }
+                return Err(e);
+            }
+            Err(e) => {
+                // 空き容量が無いなど、書き直しても直らないものは捨てる
+                self.cache.clear();
+                return Err(e);
+            }
+        }
+        if all || self.journal_len >= JOURNAL_COMMIT_SIZE {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    // ファイルを閉じて長さを確定させ、ジャーナルを空にする
+    fn commit(&mut self) -> Result<(), Error<D::Error>> {
+        if let Some(file) = self.file.take() {
+            self.vol_man.close_file(file)?;
+        }
+        self.reset_journal()
+    }
+
+    // ジャーナルをコミットの印だけにする。通し番号は続きから使う
+    fn reset_journal(&mut self) -> Result<(), Error<D::Error>> {
+        let root = self.root()?;
+        let entry = JournalEntry {
+            kind: JOURNAL_COMMIT,
+            format: self.format,
+            path: self.path.unwrap_or(LogPath::new(&Time::from_unix(0))),
+            seq: self.seq,
+            offset: 0,
+            len: 0,
+        };
+        Self::write_journal(
+            &mut self.vol_man,
+            root,
+            Mode::ReadWriteCreateOrTruncate,
+            &entry,
+            &[],
+            &[],
+        )?;
+        self.journal_len = JOURNAL_HEADER_SIZE;
+        Ok(())
+    }
+
+    // ジャーナルに1件書いて閉じる。閉じればジャーナルの長さは確定する
+    fn write_journal(
+        vol_man: &mut VolumeManager<D, T>,
+        root: RawDirectory,
+        mode: Mode,
+        entry: &JournalEntry,
+        first: &[u8],
+        second: &[u8],
+    ) -> Result<(), Error<D::Error>> {
+        let file = vol_man.open_file_in_dir(root, JOURNAL_FILE, mode)?;
+        let mut result = vol_man
+            .write(file, &entry.encode(first, second))
+            .map(|_| ());
+        for data in [first, second] {
+            if result.is_ok() && !data.is_empty() {
+                result = vol_man.write(file, data).map(|_| ());
+            }
+        }
+        let closed = vol_man.close_file(file);
+        result?;
+        closed
+    }
+
+    // ジャーナルに残っているものをログファイルに書き直し、ジャーナルを空にする
+    // 記録の途中でファイルが終わっていれば、続きは失われているので区切っておく
+    fn recover(&mut self) -> Result<Recovery, Error<D::Error>> {
+        let root = self.root()?;
+        let mut recovery = Recovery::default();
+        let tail = match self
+            .vol_man
+            .open_file_in_dir(root, JOURNAL_FILE, Mode::ReadOnly)
+        {
+            Ok(journal) => {
+                let result = self.replay(journal, &mut recovery);
+                let _ = self.vol_man.close_file(journal);
+                result
+            }
+            Err(e) => Err(e),
+        };
+        match tail {
+            // 続きがまだ cache にあるなら（カードを差し直したとき）そのまま書けばよい
+            Ok(Some(tail)) if tail.partial && self.cache.is_empty() => {
+                self.terminate(&tail)?;
+                recovery.lost += 1;
+            }
+            Err(e @ Error::DeviceError(_)) => return Err(e),
+            // ジャーナルが無いか読めなければ、書き直すものは無いとみなす
+            _ => (),
+        }
+        self.reset_journal()?;
+        Ok(recovery)
+    }
+
+    // ジャーナルを先頭から読み、最後にコミットしてからの分を書き直す
+    // 書きかけで CRC が合わないものがあれば、そこに入っていた記録は失われている
+    fn replay(
+        &mut self,
+        journal: RawFile,
+        recovery: &mut Recovery,
+    ) -> Result<Option<Tail>, Error<D::Error>> {
+        let mut header = [0; JOURNAL_HEADER_SIZE];
+        let mut data = [0; CACHE_SIZE];
+        let mut tail = None;
+        let mut next_seq = None;
+        loop {
+            let n = self.vol_man.read(journal, &mut header)?;
+            if n == 0 {
+                break;
+            }
+            let (entry, crc) = match JournalEntry::decode(&header[..n]) {
+                Some(decoded) if decoded.0.len as usize <= data.len() => decoded,
+                _ => break,
+            };
+            let len = entry.len as usize;
+            let m = self.vol_man.read(journal, &mut data[..len])?;
+            if m < len || entry.crc(&data[..len]) != crc {
+                let mut lost = count_records(&data[..m], entry.format, entry.offset);
+                // 書き直した分が記録の途中で終わっていれば、その記録は recover() で数える
+                if tail.as_ref().is_some_and(|t: &Tail| t.partial) {
+                    lost = lost.saturating_sub(1);
+                }
+                recovery.lost += lost;
+                break;
+            }
+            // 通し番号が続いていなければ、前に使ったジャーナルの残り
+            if next_seq.is_some_and(|seq| seq != entry.seq) {
+                break;
+            }
+            next_seq = Some(entry.seq.wrapping_add(1));
+            self.seq = entry.seq;
+            if entry.kind == JOURNAL_DATA {
+                tail = Some(self.replay_entry(&entry, &data[..len], recovery)?);
+            }
+        }
+        Ok(tail)
+    }
+
+    // ジャーナルの1件のうち、ファイルに入っていない部分を書く
+    fn replay_entry(
+        &mut self,
+        entry: &JournalEntry,
+        data: &[u8],
+        recovery: &mut Recovery,
+    ) -> Result<Tail, Error<D::Error>> {
+        let file = self.open_path(entry.path, entry.format)?;
+        let length = match self.vol_man.file_length(file) {
+            Ok(length) => length,
+            Err(e) => {
+                let _ = self.vol_man.close_file(file);
+                return Err(e);
+            }
+        };
+        let end = entry.offset + data.len() as u32;
+        let mut result = Ok(());
+        if length < end {
+            let skip = length.saturating_sub(entry.offset) as usize;
+            result = self.vol_man.write(file, &data[skip..]).map(|_| ());
+            if result.is_ok() {
+                let start = entry.offset + skip as u32;
+                recovery.replayed += count_records(&data[skip..], entry.format, start);
+            }
+        }
+        let closed = self.vol_man.close_file(file);
+        result?;
+        closed?;
+        let partial = length <= end
+            && match entry.format {
+                LogFormat::Binary => !end.is_multiple_of(RECORD_SIZE as u32),
+                _ => data.last().is_some_and(|&c| c != b'\n'),
+            };
+        Ok(Tail {
+            path: entry.path,
+            format: entry.format,
+            end,
+            partial,
+        })
+    }
+
+    // 途中で終わっている記録を区切る。テキストは TORN_LINE_MARKER と改行を足し、バイナリは
+    // CRC の合わないレコードになるよう 0 で埋める。どちらも読むときには読み飛ばされる
+    fn terminate(&mut self, tail: &Tail) -> Result<(), Error<D::Error>> {
+        let zeros = [0; RECORD_SIZE];
+        let data: &[u8] = match tail.format {
+            LogFormat::Binary => &zeros[..RECORD_SIZE - tail.end as usize % RECORD_SIZE],
+            _ => TORN_LINE_MARKER.as_bytes(),
+        };
+        let file = self.open_path(tail.path, tail.format)?;
+        let mut result = self.vol_man.write(file, data).map(|_| ());
+        if result.is_ok() && tail.format != LogFormat::Binary {
+            result = self.vol_man.write(file, b"\r\n").map(|_| ());
+        }
+        let closed = self.vol_man.close_file(file);
+        result?;
+        closed
+    }
+
+    // path のファイルを追記用に開く。ディレクトリが無ければ作る
+    // さらに additional バイト書くと max_file_size を超えるなら次のファイルにする
+    // 新しく作ったら true
+    fn open_file(&mut self, additional: usize) -> Result<bool, Error<D::Error>> {
+        loop {
+            let path = match self.path {
+                Some(path) => path,
+                None => return Err(Error::NotFound),
+            };
+            let file = self.open_path(path, self.format)?;
+            let length = match self.vol_man.file_length(file) {
+                Ok(length) => length as usize,
+                Err(e) => {
+                    let _ = self.vol_man.close_file(file);
+                    return Err(e);
+                }
+            };
+            let full = self.max_file_size != 0
+                && length + additional > self.max_file_size as usize
+                && path.part < MAX_PARTS;
+            if full {
+                let _ = self.vol_man.close_file(file);
+                self.next_part();
+                continue;
+            }
+            self.file = Some(file);
+            return Ok(length == 0);
+        }
+    }
+
+    // /LOG/YYYY/MM/DD.CSV を追記用に開く。ディレクトリが無ければ作る
+    fn open_path(&mut self, path: LogPath, format: LogFormat) -> Result<RawFile, Error<D::Error>> {
+        let root = self.root()?;
+        let name = path.file_name(format);
+        let year = number_name(path.year, 4);
+        let month = number_name(path.month as u16, 2);
+        self.in_dir(root, LOG_DIR, true, |s, log| {
+            s.in_dir(log, year.as_str(), true, |s, y| {
+                s.in_dir(y, month.as_str(), true, |s, m| {
+                    s.vol_man
+                        .open_file_in_dir(m, name.as_str(), Mode::ReadWriteCreateOrAppend)
+                })
+            })
+        })
+    }
+
+    fn next_part(&mut self) {
+        if let Some(path) = self.path.as_mut() {
+            if path.part < MAX_PARTS {
+                path.part += 1;
+            }
+        }
+    }
+
+    fn file_length(&mut self) -> Result<u32, Error<D::Error>> {
+        match self.file {
+            Some(file) => self.vol_man.file_length(file),
+            None => Ok(0),
+        }
+    }
+
+    fn close_file(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = self.vol_man.close_file(file);
+        }
+    }
+
+    fn root(&self) -> Result<RawDirectory, Error<D::Error>> {
+        self.mounted.map(|(_, dir)| dir).ok_or(Error::NoSuchVolume)
+    }
+
+    // parent の中の name を開いて f を呼び、閉じる。create なら無いときに作る
+    fn in_dir<R, F>(
+        &mut self,
+        parent: RawDirectory,
+        name: &str,
+        create: bool,
+        f: F,
+    ) -> Result<R, Error<D::Error>>
+    where
+        F: FnOnce(&mut Self, RawDirectory) -> Result<R, Error<D::Error>>,
+    {
+        let dir = match self.vol_man.open_dir(parent, name) {
+            Err(Error::NotFound) if create => {
+                self.vol_man.make_dir_in_dir(parent, name)?;
+                self.vol_man.open_dir(parent, name)?
+            }
+            result => result?,
+        };
+        let result = f(self, dir);
+        let _ = self.vol_man.close_dir(dir);
+        result
+    }
+
+    // 空きが min_free_bytes を下回っていたら、古い日のファイルから消す
+    fn enforce_retention(&mut self) -> Result<(), Error<D::Error>> {
+        if self.min_free_bytes == 0 {
+            return Ok(());
+        }
+        let capacity = match self.vol_man.device().num_blocks() {
+            Ok(blocks) => blocks.0 as u64 * BLOCK_SIZE as u64,
+            Err(e) => return Err(Error::DeviceError(e)),
+        };
+        let mut used = self.used_bytes()?;
+        while capacity.saturating_sub(used) < self.min_free_bytes {
+            match self.delete_oldest()? {
+                Some(size) => used = used.saturating_sub(cluster_round(size)),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    // ルートディレクトリと /LOG 以下のファイルの大きさの合計（クラスタ単位に切り上げた見積もり）
+    fn used_bytes(&mut self) -> Result<u64, Error<D::Error>> {
+        let root = self.root()?;
+        let mut used = self.file_bytes(root)?;
+        let logs = self.in_dir(root, LOG_DIR, false, |s, log| {
+            let mut total = 0;
+            for &year in s.numbered_dirs(log)?.as_slice() {
+                let year = number_name(year, 4);
+                total += s.in_dir(log, year.as_str(), false, |s, y| {
+                    let mut total = 0;
+                    for &month in s.numbered_dirs(y)?.as_slice() {
+                        let month = number_name(month, 2);
+                        total += s.in_dir(y, month.as_str(), false, |s, m| s.file_bytes(m))?;
+                    }
+                    Ok(total)
+                })?;
+            }
+            Ok(total)
+        });
+        match logs {
+            Ok(bytes) => used += bytes,
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        Ok(used)
+    }
+
+    fn file_bytes(&mut self, dir: RawDirectory) -> Result<u64, Error<D::Error>> {
+        let mut total = 0;
+        self.vol_man.iterate_dir(dir, |entry| {
+            if !entry.attributes.is_directory() {
+                total += cluster_round(entry.size);
+            }
+        })?;
+        Ok(total)
+    }
+
+    // 名前が数字のサブディレクトリを小さい順に並べる
+    fn numbered_dirs(&mut self, dir: RawDirectory) -> Result<DirList, Error<D::Error>> {
+        let mut list = DirList {
+            numbers: [0; MAX_SUBDIRS],
+            len: 0,
+        };
+        self.vol_man.iterate_dir(dir, |entry| {
+            if entry.attributes.is_directory() && list.len < MAX_SUBDIRS {
+                if let Some(n) = parse_number(entry.name.base_name()) {
+                    list.numbers[list.len] = n;
+                    list.len += 1;
+                }
+            }
+        })?;
+        list.numbers[..list.len].sort_unstable();
+        Ok(list)
+    }
+
+    // 一番古い日のファイルを1つ消し、その大きさを返す。書き込み中のファイルは消さない
+    fn delete_oldest(&mut self) -> Result<Option<u32>, Error<D::Error>> {
+        let root = self.root()?;
+        let current = self.path;
+        self.in_dir(root, LOG_DIR, false, |s, log| {
+            for &year in s.numbered_dirs(log)?.as_slice() {
+                let year_name = number_name(year, 4);
+                let deleted = s.in_dir(log, year_name.as_str(), false, |s, y| {
+                    for &month in s.numbered_dirs(y)?.as_slice() {
+                        let month_name = number_name(month, 2);
+                        let deleted = s.in_dir(y, month_name.as_str(), false, |s, m| {
+                            let skip = match current {
+                                Some(p) if p.year == year && p.month as u16 == month => {
+                                    Some(p.day as u16 * 100 + p.part as u16)
+                                }
+                                _ => None,
+                            };
+                            s.delete_oldest_file(m, skip)
+                        })?;
+                        if deleted.is_some() {
+                            return Ok(deleted);
+                        }
+                    }
+                    Ok(None)
+                })?;
+                if deleted.is_some() {
+                    return Ok(deleted);
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    fn delete_oldest_file(
+        &mut self,
+        dir: RawDirectory,
+        skip: Option<u16>,
+    ) -> Result<Option<u32>, Error<D::Error>> {
+        let mut oldest: Option<(u16, ShortFileName, u32)> = None;
+        self.vol_man.iterate_dir(dir, |entry| {
+            if entry.attributes.is_directory() {
+                return;
+            }
+            if let Some(key) = parse_day_part(entry.name.base_name()) {
+                let older = match &oldest {
+                    Some(o) => key < o.0,
+                    None => true,
+                };
+                if Some(key) != skip && older {
+                    oldest = Some((key, entry.name.clone(), entry.size));
+                }
+            }
+        })?;
+        match oldest {
+            Some((_, name, size)) => {
+                let mut buf: LineBuffer<12> = LineBuffer::new();
+                let _ = write!(buf, "{}", name);
+                self.vol_man.delete_file_in_dir(dir, buf.as_str())?;
+                Ok(Some(size))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write_to(&mut self, name: &str, mode: Mode, data: &[u8]) -> Result<(), Error<D::Error>> {
+        let root = self.root()?;
+        let file = self.vol_man.open_file_in_dir(root, name, mode)?;
+        let result = self.vol_man.write(file, data).map(|_| ());
+        let closed = self.vol_man.close_file(file);
+        result?;
+        closed
+    }
+
+    // ルートディレクトリのファイルに追記する
+    pub fn append_bytes(&mut self, name: &str, data: &[u8]) -> Result<(), Error<D::Error>> {
+        let result = self.write_to(name, Mode::ReadWriteCreateOrAppend, data);
+        self.check(result)
+    }
+
+    // 設定などの小さなファイルをルートディレクトリで丸ごと読み書きする
+    pub fn read_file(&mut self, name: &str, buf: &mut [u8]) -> Result<usize, Error<D::Error>> {
+        let root = self.root()?;
+        let result = match self.vol_man.open_file_in_dir(root, name, Mode::ReadOnly) {
+            Ok(file) => {
+                let result = self.vol_man.read(file, buf);
+                self.vol_man.close_file(file).and(result)
+            }
+            Err(e) => Err(e),
+        };
+        self.check(result)
+    }
+
+    pub fn write_file(&mut self, name: &str, data: &[u8]) -> Result<(), Error<D::Error>> {
+        let result = self.write_to(name, Mode::ReadWriteCreateOrTruncate, data);
+        self.check(result)
+    }
+
+    // カードが抜かれたなど、デバイスのエラーならマウントし直すまで書き込まない
+    fn check<R>(&mut self, result: Result<R, Error<D::Error>>) -> Result<R, Error<D::Error>> {
+        if let Err(Error::DeviceError(_)) = result {
+            self.unmount();
+        }
+        result
+    }
+}
+
+// ログファイルの場所。/LOG/YYYY/MM/DD.CSV、分けたものは DD_1.CSV
+#[derive(Clone, Copy)]
+struct LogPath {
+    year: u16,
+    month: u8,
+    day: u8,
+    part: u8,
+}
+
+impl LogPath {
+    fn new(t: &Time) -> Self {
+        Self {
+            year: t.year,
+            month: t.month,
+            day: t.day,
+            part: 0,
+        }
+    }
+
+    fn same_day(&self, other: &LogPath) -> bool {
+        self.year == other.year && self.month == other.month && self.day == other.day
+    }
+
+    fn file_name(&self, format: LogFormat) -> LineBuffer<12> {
+        let mut name: LineBuffer<12> = LineBuffer::new();
+        let _ = write!(name, "{:02}", self.day);
+        if self.part > 0 {
+            let _ = write!(name, "_{}", self.part);
+        }
+        let _ = write!(name, ".{}", format.extension());
+        name
+    }
+}
+
+// ジャーナルの1件。ヘッダーの後ろに書き込むデータが続く。数値はリトルエンディアン
+//   0..4   "BMEJ"
+//   4      種類 (0: コミット, 1: データ)
+//   5      ログの形式
+//   6..11  ログファイルの年 (2バイト)、月、日、分割番号
+//   11     予約 (0)
+//   12..16 通し番号
+//   16..20 書き込む前のログファイルの長さ
+//   20..22 データの長さ
+//   22..24 0..22 とデータの CRC-16
+struct JournalEntry {
+    kind: u8,
+    format: LogFormat,
+    path: LogPath,
+    seq: u32,
+    offset: u32,
+    len: u16,
+}
+
+impl JournalEntry {
+    fn header(&self) -> [u8; JOURNAL_HEADER_SIZE] {
+        let mut b = [0; JOURNAL_HEADER_SIZE];
+        b[0..4].copy_from_slice(&JOURNAL_MAGIC);
+        b[4] = self.kind;
+        b[5] = self.format.code();
+        b[6..8].copy_from_slice(&self.path.year.to_le_bytes());
+        b[8] = self.path.month;
+        b[9] = self.path.day;
+        b[10] = self.path.part;
+        b[12..16].copy_from_slice(&self.seq.to_le_bytes());
+        b[16..20].copy_from_slice(&self.offset.to_le_bytes());
+        b[20..22].copy_from_slice(&self.len.to_le_bytes());
+        b
+    }
+
+    fn encode(&self, first: &[u8], second: &[u8]) -> [u8; JOURNAL_HEADER_SIZE] {
+        let mut b = self.header();
+        let crc = crc16_update(
+            crc16_update(crc16_update(CRC16_INIT, &b[0..22]), first),
+            second,
+        );
+        b[22..24].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    fn crc(&self, data: &[u8]) -> u16 {
+        crc16_update(crc16_update(CRC16_INIT, &self.header()[0..22]), data)
+    }
+
+    // ヘッダーと、そこに書かれている CRC
+    fn decode(b: &[u8]) -> Option<(Self, u16)> {
+        if b.len() < JOURNAL_HEADER_SIZE || b[0..4] != JOURNAL_MAGIC {
+            return None;
+        }
+        let kind = b[4];
+        if kind != JOURNAL_COMMIT && kind != JOURNAL_DATA {
+            return None;
+        }
+        let entry = JournalEntry {
+            kind,
+            format: LogFormat::from_code(b[5])?,
+            path: LogPath {
+                year: u16::from_le_bytes([b[6], b[7]]),
+                month: b[8],
+                day: b[9],
+                part: b[10],
+            },
+            seq: u32::from_le_bytes([b[12], b[13], b[14], b[15]]),
+            offset: u32::from_le_bytes([b[16], b[17], b[18], b[19]]),
+            len: u16::from_le_bytes([b[20], b[21]]),
+        };
+        Some((entry, u16::from_le_bytes([b[22], b[23]])))
+    }
+}
+
+// 書き直した最後のファイルと、その終わりが記録の途中かどうか
+struct Tail {
+    path: LogPath,
+    format: LogFormat,
+    end: u32,
+    partial: bool,
+}
+
+// start の位置から始まる data の中で終わっている記録の数。見出しは数えない
+// バイナリのヘッダーはレコードと同じ大きさなので、レコード1つ分として扱う
+fn count_records(data: &[u8], format: LogFormat, start: u32) -> u32 {
+    let end = start + data.len() as u32;
+    if format == LogFormat::Binary {
+        let size = RECORD_SIZE as u32;
+        let header = (start < HEADER_SIZE as u32 && end >= HEADER_SIZE as u32) as u32;
+        end / size - start / size - header
+    } else {
+        let lines = data.iter().filter(|&&c| c == b'\n').count() as u32;
+        let header = (start == 0 && format != LogFormat::Text && lines > 0) as u32;
+        lines - header
+    }
+}
+
+struct DirList {
+    numbers: [u16; MAX_SUBDIRS],
+    len: usize,
+}
+
+impl DirList {
+    fn as_slice(&self) -> &[u16] {
+        &self.numbers[..self.len]
+    }
+}
+
+fn number_name(n: u16, width: usize) -> LineBuffer<12> {
+    let mut name: LineBuffer<12> = LineBuffer::new();
+    let _ = write!(name, "{:0width$}", n, width = width);
+    name
+}
+
+fn parse_number(s: &[u8]) -> Option<u16> {
+    if s.is_empty() || s.len() > 4 {
+        return None;
+    }
+    let mut n: u16 = 0;
+    for &c in s {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n * 10 + (c - b'0') as u16;
+    }
+    Some(n)
+}
+
+// "25" -> 2500, "25_3" -> 2503
+fn parse_day_part(s: &[u8]) -> Option<u16> {
+    let (day, part) = match s.iter().position(|&c| c == b'_') {
+        Some(i) => (&s[..i], parse_number(&s[i + 1..])?),
+        None => (s, 0),
+    };
+    if day.len() != 2 || part > MAX_PARTS as u16 {
+        return None;
+    }
+    Some(parse_number(day)? * 100 + part)
+}
+
+fn cluster_round(size: u32) -> u64 {
+    let size = size as u64;
+    size.div_ceil(CLUSTER_ESTIMATE).max(1) * CLUSTER_ESTIMATE
+}
+
+// 書き出していない計測値のリングバッファ。一杯になったら古いものから捨てる
+struct Backlog<const N: usize> {
+    buf: [Option<Measurement>; N],
+    head: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const N: usize> Backlog<N> {
+    fn new() -> Self {
+        Self {
+            buf: [None; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push(&mut self, m: Measurement) {
+        if self.len == N {
+            self.pop();
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        self.buf[(self.head + self.len) % N] = Some(m);
+        self.len += 1;
+    }
+
+    fn front(&self) -> Option<Measurement> {
+        if self.len == 0 {
+            None
+        } else {
+            self.buf[self.head]
+        }
+    }
+
+    fn pop(&mut self) {
+        if self.len > 0 {
+            self.buf[self.head] = None;
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+    }
+}
+
+// SDカードに書く前のバイト列を溜めるリングバッファ
+struct ByteRing<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn free(&self) -> usize {
+        N - self.len
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    // 入りきらなければ何もせずに false を返す
+    fn push(&mut self, data: &[u8]) -> bool {
+        if data.len() > self.free() {
+            return false;
+        }
+        for &b in data {
+            self.buf[(self.head + self.len) % N] = b;
+            self.len += 1;
+        }
+        true
+    }
+
+    // 先頭から n バイト。折り返している場合は2つに分かれる
+    fn peek(&self, n: usize) -> (&[u8], &[u8]) {
+        let end = self.head + n.min(self.len);
+        if end <= N {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - N])
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        let n = n.min(self.len);
+        self.head = (self.head + n) % N;
+        self.len -= n;
+    }
+}
+
+const LINE_SIZE: usize = 192;
+
+pub fn binary_record(m: &Measurement) -> [u8; binlog::RECORD_SIZE] {
+    Record::from_values(
+        m.time.to_unix(),
+        m.utc_offset,
+        m.temperature,
+        m.humidity,
+        m.pressure,
+        m.status,
+    )
+    .encode()
+}
+
+pub fn write_header<W: Write>(w: &mut W, format: LogFormat, columns: &LogColumns) -> fmt::Result {
+    let sep = format.separator();
+    write!(
+        w,
+        "time_utc{0}utc_offset{0}temperature_c{0}humidity_pct{0}pressure_hpa",
+        sep
+    )?;
+    if columns.local_time {
+        write!(w, "{}time_local", sep)?;
+    }
+    if columns.dew_point {
+        write!(w, "{}dew_point_c", sep)?;
+    }
+    if columns.absolute_humidity {
+        write!(w, "{}absolute_humidity_gm3", sep)?;
+    }
+    w.write_str("\r\n")
+}
+
+// CSV / TSV の1行。日時は ISO-8601、値は丸めずにそのまま出力する
+// 例: "2024-06-25T09:42:00Z,+09:00,23.45,45.6240234375,1013.25\r\n"
+pub fn write_record<W: Write>(
+    w: &mut W,
+    m: &Measurement,
+    format: LogFormat,
+    columns: &LogColumns,
+) -> fmt::Result {
+    if format == LogFormat::Text {
+        return write_line(w, m);
+    }
+    let sep = format.separator();
+    write!(
+        w,
+        "{1}Z{0}{2}{0}{3}{0}{4}{0}{5}",
+        sep,
+        m.time,
+        UtcOffset(m.utc_offset),
+        m.temperature,
+        m.humidity,
+        m.pressure
+    )?;
+    if columns.local_time {
+        let local = Time::from_unix((m.time.to_unix() as i64 + m.utc_offset as i64 * 60) as u32);
+        write!(w, "{}{}", sep, local)?;
+    }
+    if columns.dew_point {
+        write!(w, "{}{:.2}", sep, dew_point(m.temperature, m.humidity))?;
+    }
+    if columns.absolute_humidity {
+        write!(
+            w,
+            "{}{:.2}",
+            sep,
+            absolute_humidity(m.temperature, m.humidity)
+        )?;
+    }
+    w.write_str("\r\n")
+}
+
+// 例: "2024/06/25 09:42Z +09:00 T: 23.4, H: 45.6, P: 1013.2\r\n"
+// 日時は UTC、その後ろは地方時とのずれ
+pub fn write_line<W: Write>(w: &mut W, m: &Measurement) -> fmt::Result {
+    let t = &m.time;
+    write!(
+        w,
+        "{:04}/{:02}/{:02} {:02}:{:02}Z {} T: {:4.1}, H: {:4.1}, P: {:6.1}\r\n",
+        t.year,
+        t.month,
+        t.day,
+        t.hour,
+        t.minute,
+        UtcOffset(m.utc_offset),
+        m.temperature,
+        m.humidity,
+        m.pressure
+    )
+}
+
+// 固定長のバッファに文字列を組み立てる。溢れたら fmt::Error を返す
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // write_str() で受け取った文字列しか入らないので UTF-8 として正しい
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for LineBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > N {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use binlog::{HEADER_SIZE, RECORD_SIZE};
+    use core::cell::RefCell;
+    use embedded_sdmmc::{Block, BlockCount, BlockIdx, Timestamp};
+    use std::vec;
+    use std::vec::Vec;
+
+    // 2024-06-25T00:00:00Z
+    const DAY: u32 = 1719273600;
+
+    // FAT16 でフォーマットした 4MiB のカード。ブロック 0 が MBR、パーティションは 1 から
+    const PARTITION_BLOCKS: u32 = 8192;
+    const FAT_BLOCKS: u16 = 32;
+
+    struct RamDisk {
+        blocks: RefCell<Vec<[u8; BLOCK_SIZE]>>,
+    }
+
+    impl RamDisk {
+        fn new() -> Self {
+            let mut blocks = vec![[0; BLOCK_SIZE]; PARTITION_BLOCKS as usize + 1];
+            let mbr = &mut blocks[0];
+            mbr[446 + 4] = 0x06; // FAT16
+            mbr[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+            mbr[446 + 12..446 + 16].copy_from_slice(&PARTITION_BLOCKS.to_le_bytes());
+            mbr[510] = 0x55;
+            mbr[511] = 0xAA;
+
+            let bpb = &mut blocks[1];
+            bpb[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+            bpb[3..11].copy_from_slice(b"MSDOS5.0");
+            bpb[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+            bpb[13] = 1; // クラスタあたりのブロック数
+            bpb[14..16].copy_from_slice(&1u16.to_le_bytes()); // 予約ブロック
+            bpb[16] = 2; // FAT の数
+            bpb[17..19].copy_from_slice(&512u16.to_le_bytes()); // ルートディレクトリのエントリ数
+            bpb[19..21].copy_from_slice(&(PARTITION_BLOCKS as u16).to_le_bytes());
+            bpb[21] = 0xF8;
+            bpb[22..24].copy_from_slice(&FAT_BLOCKS.to_le_bytes());
+            bpb[28..32].copy_from_slice(&1u32.to_le_bytes());
+            bpb[38] = 0x29;
+            bpb[43..54].copy_from_slice(b"BME280     ");
+            bpb[54..62].copy_from_slice(b"FAT16   ");
+            bpb[510] = 0x55;
+            bpb[511] = 0xAA;
+
+            for fat in 0..2 {
+                let b = &mut blocks[2 + fat * FAT_BLOCKS as usize];
+                b[0..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+            }
+            Self {
+                blocks: RefCell::new(blocks),
+            }
+        }
+    }
+
+    impl BlockDevice for &RamDisk {
+        type Error = ();
+
+        fn read(
+            &self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+            _reason: &str,
+        ) -> Result<(), Self::Error> {
+            let disk = self.blocks.borrow();
+            for (i, block) in blocks.iter_mut().enumerate() {
+                let data = disk.get(start_block_idx.0 as usize + i).ok_or(())?;
+                block.contents.copy_from_slice(data);
+            }
+            Ok(())
+        }
+
+        fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+            let mut disk = self.blocks.borrow_mut();
+            for (i, block) in blocks.iter().enumerate() {
+                let data = disk.get_mut(start_block_idx.0 as usize + i).ok_or(())?;
+                data.copy_from_slice(&block.contents);
+            }
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+            Ok(BlockCount(self.blocks.borrow().len() as u32))
+        }
+    }
+
+    struct Clock;
+
+    impl TimeSource for Clock {
+        fn get_timestamp(&self) -> Timestamp {
+            Time::from_unix(DAY).to_timestamp()
+        }
+    }
+
+    type TestLogger<'a> = Logger<&'a RamDisk, Clock>;
+
+    fn mount(disk: &RamDisk, format: LogFormat) -> TestLogger<'_> {
+        let mut logger = Logger::new(VolumeManager::new(disk, Clock));
+        logger.set_format(format, LogColumns::default());
+        logger.mount().unwrap();
+        logger
+    }
+
+    fn measurement(unix: u32) -> Measurement {
+        Measurement {
+            time: Time::from_unix(unix),
+            utc_offset: 540,
+            temperature: 23.5,
+            humidity: 45.5,
+            pressure: 1013.25,
+            status: 0,
+        }
+    }
+
+    fn csv_line(unix: u32) -> Vec<u8> {
+        let mut line: LineBuffer<LINE_SIZE> = LineBuffer::new();
+        write_record(
+            &mut line,
+            &measurement(unix),
+            LogFormat::Csv,
+            &LogColumns::default(),
+        )
+        .unwrap();
+        line.as_bytes().to_vec()
+    }
+
+    fn csv_header() -> Vec<u8> {
+        let mut line: LineBuffer<LINE_SIZE> = LineBuffer::new();
+        write_header(&mut line, LogFormat::Csv, &LogColumns::default()).unwrap();
+        line.as_bytes().to_vec()
+    }
+
+    // ルートディレクトリのファイルを丸ごと読む。無ければ None
+    fn read(logger: &mut TestLogger, name: &str) -> Option<Vec<u8>> {
+        let mut buf = vec![0; 4 * BLOCK_SIZE];
+        let n = logger.read_file(name, &mut buf).ok()?;
+        buf.truncate(n);
+        Some(buf)
+    }
+
+    // /LOG/year/month/name を丸ごと読む。無ければ None
+    fn read_log(logger: &mut TestLogger, year: &str, month: &str, name: &str) -> Option<Vec<u8>> {
+        let root = logger.root().ok()?;
+        logger
+            .in_dir(root, LOG_DIR, false, |s, log| {
+                s.in_dir(log, year, false, |s, y| {
+                    s.in_dir(y, month, false, |s, m| {
+                        let file = s.vol_man.open_file_in_dir(m, name, Mode::ReadOnly)?;
+                        let mut data = Vec::new();
+                        let mut buf = [0; BLOCK_SIZE];
+                        let result = loop {
+                            match s.vol_man.read(file, &mut buf) {
+                                Ok(0) => break Ok(data),
+                                Ok(n) => data.extend_from_slice(&buf[..n]),
+                                Err(e) => break Err(e),
+                            }
+                        };
+                        let _ = s.vol_man.close_file(file);
+                        result
+                    })
+                })
+            })
+            .ok()
+    }
+
+    #[test]
+    fn append_adds_header_only_to_new_file() {
+        let disk = RamDisk::new();
+        {
+            let mut logger = mount(&disk, LogFormat::Csv);
+            logger.append(&measurement(DAY)).unwrap();
+            logger.append(&measurement(DAY + 60)).unwrap();
+            logger.sync().unwrap();
+        }
+
+        // マウントし直しても、既にあるファイルには追記するだけ
+        let mut logger = mount(&disk, LogFormat::Csv);
+        logger.append(&measurement(DAY + 120)).unwrap();
+        logger.sync().unwrap();
+
+        let expected = [
+            csv_header(),
+            csv_line(DAY),
+            csv_line(DAY + 60),
+            csv_line(DAY + 120),
+        ]
+        .concat();
+        assert_eq!(
+            read_log(&mut logger, "2024", "06", "25.CSV"),
+            Some(expected)
+        );
+        assert_eq!(logger.pending(), 0);
+    }
+
+    #[test]
+    fn binary_log_starts_with_header_and_holds_records() {
+        let disk = RamDisk::new();
+        let mut logger = mount(&disk, LogFormat::Binary);
+        logger.append(&measurement(DAY)).unwrap();
+        logger.append(&measurement(DAY + 60)).unwrap();
+        logger.sync().unwrap();
+
+        let data = read_log(&mut logger, "2024", "06", "25.BIN").unwrap();
+        assert_eq!(data.len(), HEADER_SIZE + 2 * RECORD_SIZE);
+        assert_eq!(FileHeader::decode(&data), Ok(FileHeader::new(DAY)));
+        for (i, b) in data[HEADER_SIZE..].chunks(RECORD_SIZE).enumerate() {
+            let record = Record::decode(b).unwrap();
+            assert_eq!(record.unix, DAY + i as u32 * 60);
+            assert_eq!(record.temperature_c(), 23.5);
+            assert_eq!(record.humidity_pct(), 45.5);
+            assert_eq!(record.pressure_hpa(), 1013.25);
+            assert_eq!(record.utc_offset_minutes(), 540);
+        }
+    }
+
+    #[test]
+    fn append_keeps_lines_in_ram_until_block_or_interval() {
+        // 書き込み中のファイルは開けないので、カードの中身から探す
+        let on_card = |disk: &RamDisk, line: &[u8]| {
+            let blocks = disk.blocks.borrow();
+            blocks.concat().windows(line.len()).any(|w| w == line)
+        };
+        let disk = RamDisk::new();
+        let mut logger = mount(&disk, LogFormat::Csv);
+        logger.append(&measurement(DAY)).unwrap();
+        logger.append(&measurement(DAY + 60)).unwrap();
+        assert!(!on_card(&disk, &csv_line(DAY)));
+
+        // flush_interval を過ぎたら端数も書き出す
+        logger
+            .append(&measurement(DAY + DEFAULT_FLUSH_INTERVAL))
+            .unwrap();
+        assert!(on_card(&disk, &csv_line(DAY)));
+        assert!(on_card(&disk, &csv_line(DAY + DEFAULT_FLUSH_INTERVAL)));
+    }
+
+    #[test]
+    fn rolls_over_by_size_and_day() {
+        let disk = RamDisk::new();
+        let mut logger = mount(&disk, LogFormat::Csv);
+        // 見出しと2行で一杯になる大きさ
+        let limit = (csv_header().len() + 2 * csv_line(DAY).len()) as u32;
+        logger.set_max_file_size(limit);
+        for i in 0..5 {
+            logger.append(&measurement(DAY + i * 60)).unwrap();
+        }
+        logger.append(&measurement(DAY + 86400)).unwrap();
+        logger.sync().unwrap();
+
+        let part = |lines: &[u32]| {
+            let mut data = csv_header();
+            for &t in lines {
+                data.extend(csv_line(t));
+            }
+            Some(data)
+        };
+        let log = |logger: &mut TestLogger, name| read_log(logger, "2024", "06", name);
+        assert_eq!(log(&mut logger, "25.CSV"), part(&[DAY, DAY + 60]));
+        assert_eq!(log(&mut logger, "25_1.CSV"), part(&[DAY + 120, DAY + 180]));
+        assert_eq!(log(&mut logger, "25_2.CSV"), part(&[DAY + 240]));
+        assert_eq!(log(&mut logger, "26.CSV"), part(&[DAY + 86400]));
+    }
+
+    #[test]
+    fn write_file_replaces_contents() {
+        let disk = RamDisk::new();
+        let mut logger = mount(&disk, LogFormat::Csv);
+        assert_eq!(read(&mut logger, "DRIFT.BIN"), None);
+        logger.write_file("DRIFT.BIN", b"first").unwrap();
+        logger.write_file("DRIFT.BIN", b"2nd").unwrap();
+        assert_eq!(read(&mut logger, "DRIFT.BIN"), Some(b"2nd".to_vec()));
+    }
+
+    #[test]
+    fn line_buffer_rejects_overflow() {
+        let mut line: LineBuffer<4> = LineBuffer::new();
+        assert!(write!(line, "abc").is_ok());
+        assert!(write!(line, "de").is_err());
+        assert_eq!(line.as_str(), "abc");
+        line.clear();
+        assert!(line.is_empty());
+    }
+
+    #[test]
+    fn replays_journal_after_power_loss() {
+        let disk = RamDisk::new();
+        let mut logger = mount(&disk, LogFormat::Csv);
+        logger.set_flush_interval(u32::MAX);
+        for i in 0..30 {
+            logger.append(&measurement(DAY + i * 60)).unwrap();
+        }
+        // ブロック単位で書いただけで、ファイルを閉じないまま電源が切れた
+        drop(logger);
+
+        let mut expected = csv_header();
+        let mut lines = 0;
+        while expected.len() + csv_line(DAY).len() <= 2 * BLOCK_SIZE {
+            expected.extend(csv_line(DAY + lines * 60));
+            lines += 1;
+        }
+
+        let mut logger = mount(&disk, LogFormat::Csv);
+        let recovery = logger.recovery();
+        assert_eq!(recovery.replayed, lines);
+        assert_eq!(recovery.lost, 1);
+        // 途中で切れた行には印を付けて区切る
+        let torn = csv_line(DAY + lines * 60);
+        expected.extend(&torn[..2 * BLOCK_SIZE - expected.len()]);
+        expected.extend(TORN_LINE_MARKER.as_bytes());
+        expected.extend(b"\r\n");
+        assert_eq!(
+            read_log(&mut logger, "2024", "06", "25.CSV"),
+            Some(expected)
+        );
+
+        // 書き直したらジャーナルは空になり、もう一度マウントしても何もしない
+        drop(logger);
+        let logger = mount(&disk, LogFormat::Csv);
+        assert_eq!(logger.recovery().replayed, 0);
+        assert_eq!(logger.recovery().lost, 0);
+    }
+
+    #[test]
+    fn counts_lost_records_when_the_last_journal_entry_is_torn() {
+        let disk = RamDisk::new();
+        let mut logger = mount(&disk, LogFormat::Csv);
+        logger.set_flush_interval(u32::MAX);
+        for i in 0..30 {
+            logger.append(&measurement(DAY + i * 60)).unwrap();
+        }
+        drop(logger);
+
+        // 最後にジャーナルに書いたもの（通し番号が一番大きいデータ）を書きかけにする
+        {
+            let mut blocks = disk.blocks.borrow_mut();
+            let bytes = blocks.as_flattened_mut();
+            let mut last: Option<(u32, usize)> = None;
+            for i in 0..bytes.len() - JOURNAL_HEADER_SIZE {
+                if let Some((entry, _)) = JournalEntry::decode(&bytes[i..]) {
+                    if entry.kind == JOURNAL_DATA && last.is_none_or(|(seq, _)| entry.seq > seq) {
+                        last = Some((entry.seq, i));
+                    }
+                }
+            }
+            let (_, i) = last.unwrap();
+            bytes[i + JOURNAL_HEADER_SIZE + 100] ^= 0xff;
+        }
+
+        let mut lines = 0;
+        while csv_header().len() + (lines as usize + 1) * csv_line(DAY).len() <= BLOCK_SIZE {
+            lines += 1;
+        }
+        let mut logger = mount(&disk, LogFormat::Csv);
+        let recovery = logger.recovery();
+        assert_eq!(recovery.replayed, lines);
+        // 2ブロックの中で終わっていた行は、書き直したもの以外すべて失った
+        let total = ((2 * BLOCK_SIZE - csv_header().len()) / csv_line(DAY).len()) as u32;
+        assert_eq!(recovery.lost, total - lines);
+        let data = read_log(&mut logger, "2024", "06", "25.CSV").unwrap();
+        assert!(data.ends_with(b"!TORN\r\n"));
+        assert_eq!(data.len(), BLOCK_SIZE + TORN_LINE_MARKER.len() + 2);
+    }
+
+    #[test]
+    fn journal_entry_round_trip() {
+        let entry = JournalEntry {
+            kind: JOURNAL_DATA,
+            format: LogFormat::Tsv,
+            path: LogPath {
+                year: 2024,
+                month: 6,
+                day: 25,
+                part: 3,
+            },
+            seq: 0x01020304,
+            offset: 1536,
+            len: 7,
+        };
+        let b = entry.encode(b"abc", b"defg");
+        let (decoded, crc) = JournalEntry::decode(&b).unwrap();
+        assert_eq!(decoded.kind, JOURNAL_DATA);
+        assert!(decoded.format == LogFormat::Tsv);
+        assert_eq!(
+            (
+                decoded.path.year,
+                decoded.path.month,
+                decoded.path.day,
+                decoded.path.part
+            ),
+            (2024, 6, 25, 3)
+        );
+        assert_eq!(decoded.seq, 0x01020304);
+        assert_eq!(decoded.offset, 1536);
+        assert_eq!(decoded.len, 7);
+        // 2つに分けて書いたデータも、続けて計算した CRC と合う
+        assert_eq!(decoded.crc(b"abcdefg"), crc);
+        assert_ne!(decoded.crc(b"abcdefh"), crc);
+
+        assert!(JournalEntry::decode(&b[..JOURNAL_HEADER_SIZE - 1]).is_none());
+        let mut bad = b;
+        bad[0] = b'X';
+        assert!(JournalEntry::decode(&bad).is_none());
+        let mut bad = b;
+        bad[4] = 7;
+        assert!(JournalEntry::decode(&bad).is_none());
+        let mut bad = b;
+        bad[5] = 9;
+        assert!(JournalEntry::decode(&bad).is_none());
+    }
+
+    #[test]
+    fn count_records_in_text_and_binary() {
+        // 見出しは数えない
+        assert_eq!(
+            count_records(b"time_utc\r\na\r\nb\r\nc", LogFormat::Csv, 0),
+            2
+        );
+        assert_eq!(count_records(b"a\r\nb\r\nc", LogFormat::Text, 0), 2);
+        assert_eq!(count_records(b"\r\nb\r\nc", LogFormat::Csv, 100), 2);
+        assert_eq!(count_records(b"", LogFormat::Csv, 0), 0);
+
+        let r = RECORD_SIZE as u32;
+        let data = [0; 3 * RECORD_SIZE];
+        // ヘッダーと2つのレコード
+        assert_eq!(count_records(&data, LogFormat::Binary, 0), 2);
+        // 途中から始まり途中で終わる
+        assert_eq!(count_records(&data[..36], LogFormat::Binary, 2 * r + 8), 2);
+        assert_eq!(count_records(&data[..4], LogFormat::Binary, 2 * r + 8), 0);
+    }
+}
@@ -0,0 +1,155 @@
+// 計測値の最小・最大とその日時を覚えておく（RAM だけなので電源を切ると消える）
+// 「リセットしてから」と「今日 (0時から)」の2つを持つ
+
+use crate::history::CHANNELS;
+use crate::rtc8564::Time;
+
+#[derive(Clone, Copy)]
+pub struct Extreme {
+    pub value: f64,
+    pub time: Time, // 地方時
+}
+
+#[derive(Clone, Copy)]
+pub struct MinMax {
+    min: [Option<Extreme>; CHANNELS],
+    max: [Option<Extreme>; CHANNELS],
+}
+
+impl MinMax {
+    pub const fn new() -> Self {
+        Self {
+            min: [None; CHANNELS],
+            max: [None; CHANNELS],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn update(&mut self, time: &Time, values: [f64; CHANNELS]) {
+        for (channel, &value) in values.iter().enumerate() {
+            let extreme = Some(Extreme { value, time: *time });
+            if self.min[channel].is_none_or(|e| value < e.value) {
+                self.min[channel] = extreme;
+            }
+            if self.max[channel].is_none_or(|e| value > e.value) {
+                self.max[channel] = extreme;
+            }
+        }
+    }
+
+    pub fn min(&self, channel: usize) -> Option<Extreme> {
+        self.min[channel]
+    }
+
+    pub fn max(&self, channel: usize) -> Option<Extreme> {
+        self.max[channel]
+    }
+}
+
+impl Default for MinMax {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Extremes {
+    pub since_reset: MinMax,
+    pub today: MinMax,
+    date: Option<(u16, u8, u8)>, // today の日付
+}
+
+impl Extremes {
+    pub const fn new() -> Self {
+        Self {
+            since_reset: MinMax::new(),
+            today: MinMax::new(),
+            date: None,
+        }
+    }
+
+    // 計測するたびに地方時で呼ぶ。日付が変わったら today をやり直す
+    pub fn update(&mut self, time: &Time, elements: (f64, f64, f64)) {
+        let date = (time.year, time.month, time.day);
+        if self.date != Some(date) {
+            self.today.reset();
+            self.date = Some(date);
+        }
+        let values = [elements.0, elements.1, elements.2];
+        self.since_reset.update(time, values);
+        self.today.update(time, values);
+    }
+
+    // 長押しで消すのは「リセットしてから」の方だけ
+    pub fn reset(&mut self) {
+        self.since_reset.reset();
+    }
+}
+
+impl Default for Extremes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minute(t: &Time) -> (u8, u8, u8) {
+        (t.day, t.hour, t.minute)
+    }
+
+    #[test]
+    fn keeps_first_extreme_and_its_time() {
+        let mut m = MinMax::new();
+        assert!(m.min(0).is_none() && m.max(0).is_none());
+        m.update(&Time::new(2024, 6, 25, 10, 0, 0), [20.0, 50.0, 1000.0]);
+        m.update(&Time::new(2024, 6, 25, 11, 0, 0), [25.0, 40.0, 1000.0]);
+        m.update(&Time::new(2024, 6, 25, 12, 0, 0), [25.0, 45.0, 990.0]);
+
+        let max = m.max(0).unwrap();
+        assert_eq!((max.value, minute(&max.time)), (25.0, (25, 11, 0)));
+        assert_eq!(minute(&m.min(0).unwrap().time), (25, 10, 0));
+        assert_eq!(m.min(1).unwrap().value, 40.0);
+        assert_eq!(m.max(1).unwrap().value, 50.0);
+        // 同じ値なら先の日時のまま
+        assert_eq!(minute(&m.max(2).unwrap().time), (25, 10, 0));
+        assert_eq!(minute(&m.min(2).unwrap().time), (25, 12, 0));
+    }
+
+    #[test]
+    fn today_restarts_at_local_midnight() {
+        let mut e = Extremes::new();
+        e.update(&Time::new(2024, 6, 25, 14, 0, 0), (30.0, 60.0, 1010.0));
+        e.update(&Time::new(2024, 6, 25, 23, 59, 59), (18.0, 70.0, 1005.0));
+        e.update(&Time::new(2024, 6, 26, 0, 0, 0), (20.0, 65.0, 1000.0));
+
+        let today_max = e.today.max(0).unwrap();
+        assert_eq!(
+            (today_max.value, minute(&today_max.time)),
+            (20.0, (26, 0, 0))
+        );
+        assert_eq!(e.today.min(0).unwrap().value, 20.0);
+        assert_eq!(e.since_reset.max(0).unwrap().value, 30.0);
+        assert_eq!(e.since_reset.min(0).unwrap().value, 18.0);
+
+        // 月が変わっても日付が変われば今日をやり直す
+        e.update(&Time::new(2024, 7, 26, 9, 0, 0), (22.0, 65.0, 1000.0));
+        assert_eq!(e.today.min(0).unwrap().value, 22.0);
+    }
+
+    #[test]
+    fn reset_clears_only_since_reset() {
+        let mut e = Extremes::new();
+        e.update(&Time::new(2024, 6, 25, 14, 0, 0), (30.0, 60.0, 1010.0));
+        e.reset();
+        assert!(e.since_reset.max(0).is_none());
+        assert_eq!(e.today.max(0).unwrap().value, 30.0);
+        e.update(&Time::new(2024, 6, 25, 15, 0, 0), (25.0, 60.0, 1010.0));
+        assert_eq!(e.since_reset.max(0).unwrap().value, 25.0);
+        assert_eq!(e.today.max(0).unwrap().value, 30.0);
+    }
+}
@@ -0,0 +1,242 @@
+// LCD に表示するときの単位と書式
+// 計測値は °C / % / hPa のまま持ち、表示するときだけここで換算する
+// 計測値の番号 (channel) は 0: 温度, 1: 湿度, 2: 気圧
+
+use core::fmt;
+
+use crate::rtc8564::Time;
+
+const HPA_PER_INHG: f64 = 33.863_886;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PressureUnit {
+    Hpa,
+    InHg,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DateOrder {
+    Ymd,
+    Dmy,
+    Mdy,
+}
+
+// 日付の年の書き方
+#[derive(Clone, Copy, PartialEq)]
+pub enum YearStyle {
+    Full,  // 4桁
+    Short, // 下2桁
+    None,  // 書かない
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Preferences {
+    pub temperature_unit: TemperatureUnit,
+    pub pressure_unit: PressureUnit,
+    pub decimals: u8, // 小数点以下の桁数 (0〜2)。inHg は常に2桁
+    pub clock_24h: bool,
+    pub date_order: DateOrder,
+}
+
+impl Preferences {
+    pub const DEFAULT: Preferences = Preferences {
+        temperature_unit: TemperatureUnit::Celsius,
+        pressure_unit: PressureUnit::Hpa,
+        decimals: 1,
+        clock_24h: true,
+        date_order: DateOrder::Ymd,
+    };
+
+    // °C / % / hPa の値を表示の単位にする
+    pub fn convert(&self, channel: usize, value: f64) -> f64 {
+        match channel {
+            0 if self.temperature_unit == TemperatureUnit::Fahrenheit => value * 1.8 + 32.0,
+            2 if self.pressure_unit == PressureUnit::InHg => value / HPA_PER_INHG,
+            _ => value,
+        }
+    }
+
+    pub fn unit(&self, channel: usize) -> &'static str {
+        match channel {
+            0 => match self.temperature_unit {
+                TemperatureUnit::Celsius => "°C",
+                TemperatureUnit::Fahrenheit => "°F",
+            },
+            1 => "%",
+            _ => match self.pressure_unit {
+                PressureUnit::Hpa => "hPa",
+                PressureUnit::InHg => "inHg",
+            },
+        }
+    }
+
+    pub fn decimals(&self, channel: usize) -> usize {
+        if channel == 2 && self.pressure_unit == PressureUnit::InHg {
+            2
+        } else {
+            self.decimals as usize
+        }
+    }
+
+    // write! で書ける日付。区切りは '/'
+    pub fn date(&self, time: &Time, year: YearStyle) -> DateText {
+        DateText {
+            order: self.date_order,
+            year,
+            time: *time,
+        }
+    }
+
+    // write! で書ける時刻。12時間制では後ろに 'a' / 'p' を付ける
+    pub fn time(&self, time: &Time, seconds: bool) -> TimeText {
+        TimeText {
+            clock_24h: self.clock_24h,
+            seconds,
+            time: *time,
+        }
+    }
+
+    // time() で書いたときの桁数
+    pub fn time_len(&self, seconds: bool) -> u8 {
+        let len = if seconds { 8 } else { 5 };
+        if self.clock_24h {
+            len
+        } else {
+            len + 1
+        }
+    }
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+pub struct DateText {
+    order: DateOrder,
+    year: YearStyle,
+    time: Time,
+}
+
+impl fmt::Display for DateText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let t = &self.time;
+        let (first, second) = match self.order {
+            DateOrder::Ymd | DateOrder::Mdy => (t.month, t.day),
+            DateOrder::Dmy => (t.day, t.month),
+        };
+        let year_first = self.order == DateOrder::Ymd;
+        match self.year {
+            YearStyle::None => write!(f, "{:02}/{:02}", first, second),
+            YearStyle::Full if year_first => write!(f, "{:04}/{:02}/{:02}", t.year, first, second),
+            YearStyle::Full => write!(f, "{:02}/{:02}/{:04}", first, second, t.year),
+            YearStyle::Short if year_first => {
+                write!(f, "{:02}/{:02}/{:02}", t.year % 100, first, second)
+            }
+            YearStyle::Short => write!(f, "{:02}/{:02}/{:02}", first, second, t.year % 100),
+        }
+    }
+}
+
+pub struct TimeText {
+    clock_24h: bool,
+    seconds: bool,
+    time: Time,
+}
+
+impl fmt::Display for TimeText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let t = &self.time;
+        let hour = if self.clock_24h {
+            t.hour
+        } else {
+            (t.hour + 11) % 12 + 1
+        };
+        write!(f, "{:02}:{:02}", hour, t.minute)?;
+        if self.seconds {
+            write!(f, ":{:02}", t.second)?;
+        }
+        if !self.clock_24h {
+            f.write_str(if t.hour < 12 { "a" } else { "p" })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+
+    use super::*;
+
+    fn value(prefs: &Preferences, channel: usize, v: f64) -> std::string::String {
+        format!(
+            "{:.*} {}",
+            prefs.decimals(channel),
+            prefs.convert(channel, v),
+            prefs.unit(channel)
+        )
+    }
+
+    #[test]
+    fn units_and_decimals() {
+        let mut prefs = Preferences::DEFAULT;
+        assert_eq!(value(&prefs, 0, 23.45), "23.4 °C");
+        assert_eq!(value(&prefs, 1, 45.0), "45.0 %");
+        assert_eq!(value(&prefs, 2, 1013.25), "1013.2 hPa");
+
+        prefs.temperature_unit = TemperatureUnit::Fahrenheit;
+        prefs.pressure_unit = PressureUnit::InHg;
+        prefs.decimals = 0;
+        assert_eq!(value(&prefs, 0, 25.0), "77 °F");
+        assert_eq!(value(&prefs, 0, -40.0), "-40 °F");
+        assert_eq!(value(&prefs, 1, 45.4), "45 %");
+        // inHg は常に2桁
+        assert_eq!(value(&prefs, 2, 1013.25), "29.92 inHg");
+    }
+
+    #[test]
+    fn date_orders() {
+        let t = Time::new(2024, 6, 5, 18, 42, 7);
+        let mut prefs = Preferences::DEFAULT;
+        assert_eq!(format!("{}", prefs.date(&t, YearStyle::Full)), "2024/06/05");
+        assert_eq!(format!("{}", prefs.date(&t, YearStyle::Short)), "24/06/05");
+        assert_eq!(format!("{}", prefs.date(&t, YearStyle::None)), "06/05");
+        prefs.date_order = DateOrder::Dmy;
+        assert_eq!(format!("{}", prefs.date(&t, YearStyle::Full)), "05/06/2024");
+        assert_eq!(format!("{}", prefs.date(&t, YearStyle::None)), "05/06");
+        prefs.date_order = DateOrder::Mdy;
+        assert_eq!(format!("{}", prefs.date(&t, YearStyle::Short)), "06/05/24");
+    }
+
+    #[test]
+    fn clock_styles() {
+        let mut prefs = Preferences::DEFAULT;
+        let evening = Time::new(2024, 6, 5, 18, 42, 7);
+        assert_eq!(format!("{}", prefs.time(&evening, true)), "18:42:07");
+        assert_eq!(format!("{}", prefs.time(&evening, false)), "18:42");
+        prefs.clock_24h = false;
+        assert_eq!(format!("{}", prefs.time(&evening, true)), "06:42:07p");
+        let midnight = Time::new(2024, 6, 5, 0, 5, 0);
+        assert_eq!(format!("{}", prefs.time(&midnight, false)), "12:05a");
+        let noon = Time::new(2024, 6, 5, 12, 0, 0);
+        assert_eq!(format!("{}", prefs.time(&noon, false)), "12:00p");
+        // time_len() は書いた桁数と同じ
+        for clock_24h in [true, false] {
+            prefs.clock_24h = clock_24h;
+            for seconds in [true, false] {
+                let text = format!("{}", prefs.time(&evening, seconds));
+                assert_eq!(text.len(), prefs.time_len(seconds) as usize);
+            }
+        }
+    }
+}
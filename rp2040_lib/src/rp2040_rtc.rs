@@ -0,0 +1,64 @@
+// RP2040 内蔵の RTC ペリフェラル。外付けの RTC が無いときの代わりに使う
+// バックアップ電源が無いので、電源を切ると時刻は失われる
+
+use rp2040_hal::rtc::{DateTime, DayOfWeek, RealTimeClock};
+
+use crate::rtc::Rtc;
+use crate::rtc::RtcStatus;
+use crate::rtc8564::weekday;
+use crate::rtc8564::Time;
+
+pub struct InternalRtc {
+    rtc: RealTimeClock,
+}
+
+impl InternalRtc {
+    pub fn new(rtc: RealTimeClock) -> Self {
+        Self { rtc }
+    }
+
+    pub fn free(self) -> RealTimeClock {
+        self.rtc
+    }
+}
+
+impl Rtc for InternalRtc {
+    fn now(&mut self) -> Time {
+        match self.rtc.now() {
+            Ok(dt) => Time::new(dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second),
+            Err(_) => Time::new(2000, 1, 1, 0, 0, 0),
+        }
+    }
+
+    fn set(&mut self, time: &Time) {
+        let _ = self.rtc.set_datetime(DateTime {
+            year: time.year,
+            month: time.month,
+            day: time.day,
+            day_of_week: day_of_week(weekday(time.year, time.month, time.day)),
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
+        });
+    }
+
+    fn status(&mut self) -> RtcStatus {
+        if self.rtc.is_running() {
+            RtcStatus::Running
+        } else {
+            RtcStatus::Stopped
+        }
+    }
+}
+
+fn day_of_week(weekday: u8) -> DayOfWeek {
+    match weekday {
+        1 => DayOfWeek::Monday,
+        2 => DayOfWeek::Tuesday,
+        3 => DayOfWeek::Wednesday,
+        4 => DayOfWeek::Thursday,
+        5 => DayOfWeek::Friday,
+        6 => DayOfWeek::Saturday,
+        _ => DayOfWeek::Sunday,
+    }
+}
@@ -0,0 +1,67 @@
+// RTC の種類（RTC8564, DS3231, RP2040 内蔵RTC）によらず日時を扱うためのトレイト
+
+use core::cell::RefCell;
+
+use embedded_sdmmc::{TimeSource, Timestamp};
+
+use crate::rtc8564::Time;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RtcStatus {
+    Running,
+    VoltageLow, // バックアップ電源の電圧低下などで時刻が信頼できない
+    Stopped,
+}
+
+pub trait Rtc {
+    fn now(&mut self) -> Time;
+    fn set(&mut self, time: &Time);
+    fn status(&mut self) -> RtcStatus;
+}
+
+pub fn bcd_to_bin(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+pub fn bin_to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | (value % 10)
+}
+
+// 起動時に選んだ RTC を表示と SDカードの TimeSource で共有するときは、RefCell に入れて参照を渡す
+impl<R: Rtc + ?Sized> Rtc for &RefCell<R> {
+    fn now(&mut self) -> Time {
+        self.borrow_mut().now()
+    }
+
+    fn set(&mut self, time: &Time) {
+        self.borrow_mut().set(time)
+    }
+
+    fn status(&mut self) -> RtcStatus {
+        self.borrow_mut().status()
+    }
+}
+
+// SDカードのファイルの日時を RTC から取る
+// get_timestamp() は &self なので RefCell で包む
+pub struct RtcTimeSource<R: Rtc> {
+    rtc: RefCell<R>,
+}
+
+impl<R: Rtc> RtcTimeSource<R> {
+    pub fn new(rtc: R) -> Self {
+        Self {
+            rtc: RefCell::new(rtc),
+        }
+    }
+
+    pub fn now(&self) -> Time {
+        self.rtc.borrow_mut().now()
+    }
+}
+
+impl<R: Rtc> TimeSource for RtcTimeSource<R> {
+    fn get_timestamp(&self) -> Timestamp {
+        self.now().to_timestamp()
+    }
+}
@@ -1,4 +1,14 @@
+use core::fmt;
+
 use embedded_hal::i2c::I2c;
+use embedded_sdmmc::Timestamp;
+
+use binlog::civil;
+
+use crate::rtc::bcd_to_bin;
+use crate::rtc::bin_to_bcd;
+use crate::rtc::Rtc;
+use crate::rtc::RtcStatus;
 
 pub const RTC_DEVICE_ADDRESS: u8 = 0x51;
 
@@ -22,6 +32,8 @@ const TIMER_DOWN_COUNTER_REG: u8 = 0x0f;
 const VLOW_STATUS: u8 = 0x80; // 1 で電圧低下あり
 const VLOW_DETECTED: u8 = VLOW_STATUS;
 
+const STOP_STATUS: u8 = 0x20; // CONTROL1 の STOP ビット
+
 const RTC_STOP: u8 = 0x1;
 const RTC_RUN: u8 = 0x0;
 const CONTROL1_WRITE_DATA_RTC_STOP: u8 = RTC_STOP << 5;
@@ -35,10 +47,15 @@ const SECONDS_WRITE_DATA: u8 = 0;
 const MINUTES_WRITE_DATA: u8 = 0x42;
 const HOURS_WRITE_DATA: u8 = 0x18;
 const DAYS_WRITE_DATA: u8 = 0x25; // 25日
-const WEEKDAYS_WRITE_DATA: u8 = 0;
+const WEEKDAYS_WRITE_DATA: u8 = weekday(2024, 6, 25); // 火曜日
 const MONTHS_CENTURY_WRITE_DATA: u8 = 0x06; // 6月
 const YEARS_WRITE_DATA: u8 = 0x24; // 2024年
 
+// 年レジスタが 99 から 00 に桁上がりすると C ビットが反転する
+// C = 0 を 20xx年、C = 1 を 21xx年として扱う
+const CENTURY_BIT: u8 = 0x80;
+const BASE_YEAR: u16 = 2000;
+
 const MINUTE_ALARM_WRITE_DATA: u8 = 0;
 const HOUR_ALARM_WRITE_DATA: u8 = 0;
 const DAY_ALARM_WRITE_DATA: u8 = 0;
@@ -56,15 +73,166 @@ const TIMER_CONTROL_WRITE_DATA_TE_DISABLED: u8 = TE_DISABLED << 7 | TD1_TD0;
 
 const TIMER_DOWN_COUNTER_WRITE_DATA: u8 = 1; // 1sec周期の割り込み用
 
+const WEEKDAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+#[derive(Clone, Copy)]
 pub struct Time {
-    pub year: u8,
+    pub year: u16, // 西暦4桁
     pub month: u8,
     pub day: u8,
+    pub weekday: u8, // 0 = 日曜日 ... 6 = 土曜日
     pub hour: u8,
     pub minute: u8,
     pub second: u8,
 }
 
+impl Time {
+    // 曜日は年月日から計算するので、呼び出し側で指定する必要はない
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        Self {
+            year,
+            month,
+            day,
+            weekday: weekday(year, month, day),
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        WEEKDAY_NAMES[(self.weekday % 7) as usize]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.year >= UNIX_EPOCH_YEAR
+            && (1..=12).contains(&self.month)
+            && 1 <= self.day
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    // 1970/01/01 00:00:00 からの秒数。u32 なので 2106-02-07 06:28:15 まで扱え、それより後は 0
+    // RTC の世紀ビットで 2100〜2199年が読めることがあるので、u64 で計算してから確かめる
+    pub fn to_unix(&self) -> u32 {
+        if !self.is_valid() {
+            return 0;
+        }
+        let days = civil::days_from_civil(self.year as i64, self.month as u32, self.day as u32);
+        let seconds = (days as u64 * SECONDS_PER_DAY as u64)
+            .checked_add(self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64);
+        seconds
+            .and_then(|seconds| u32::try_from(seconds).ok())
+            .unwrap_or(0)
+    }
+
+    pub fn from_unix(seconds: u32) -> Self {
+        let days = seconds / SECONDS_PER_DAY;
+        let rest = seconds % SECONDS_PER_DAY;
+        let (year, month, day) = civil::civil_from_days(days as i64);
+        Self::new(
+            year as u16,
+            month as u8,
+            day as u8,
+            (rest / 3600) as u8,
+            ((rest / 60) % 60) as u8,
+            (rest % 60) as u8,
+        )
+    }
+
+    // SDカードのファイルの作成・更新日時用
+    pub fn to_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: self.year.saturating_sub(UNIX_EPOCH_YEAR) as u8,
+            zero_indexed_month: self.month.saturating_sub(1),
+            zero_indexed_day: self.day.saturating_sub(1),
+            hours: self.hour,
+            minutes: self.minute,
+            seconds: self.second,
+        }
+    }
+
+    pub fn from_timestamp(timestamp: &Timestamp) -> Self {
+        Self::new(
+            UNIX_EPOCH_YEAR + timestamp.year_since_1970 as u16,
+            timestamp.zero_indexed_month + 1,
+            timestamp.zero_indexed_day + 1,
+            timestamp.hours,
+            timestamp.minutes,
+            timestamp.seconds,
+        )
+    }
+
+    // "2024-06-25T18:42:00" 形式。日付と時刻の区切りは空白でもよく、末尾の "Z" は無視する
+    pub fn from_iso8601(s: &str) -> Option<Self> {
+        let b = s.trim().as_bytes();
+        if b.len() < 19
+            || b[4] != b'-'
+            || b[7] != b'-'
+            || (b[10] != b'T' && b[10] != b' ')
+            || b[13] != b':'
+            || b[16] != b':'
+        {
+            return None;
+        }
+        let time = Self::new(
+            parse_digits(&b[0..4])?,
+            parse_digits(&b[5..7])? as u8,
+            parse_digits(&b[8..10])? as u8,
+            parse_digits(&b[11..13])? as u8,
+            parse_digits(&b[14..16])? as u8,
+            parse_digits(&b[17..19])? as u8,
+        );
+        if time.is_valid() {
+            Some(time)
+        } else {
+            None
+        }
+    }
+}
+
+// ISO-8601 形式で出力する（例: 2024-06-25T18:42:00）
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+const UNIX_EPOCH_YEAR: u16 = 1970;
+const SECONDS_PER_DAY: u32 = 86400;
+
+fn parse_digits(b: &[u8]) -> Option<u16> {
+    let mut value: u16 = 0;
+    for c in b {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        value = value * 10 + (c - b'0') as u16;
+    }
+    Some(value)
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    civil::days_in_month(year as i64, month as u32) as u8
+}
+
+// Sakamoto の方法で曜日を求める（0 = 日曜日）
+// Time::new() にはレジスタから読んだままの値が来るので、どんな値でも panic しないようにする
+pub const fn weekday(year: u16, month: u8, day: u8) -> u8 {
+    const T: [u32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    if month < 1 || month > 12 || year == 0 {
+        return 0; // RTC が応答しないときなど、日付として不正な値
+    }
+    let y = year as u32 - if month < 3 { 1 } else { 0 };
+    ((y + y / 4 - y / 100 + y / 400 + T[(month - 1) as usize] + day as u32) % 7) as u8
+}
+
 pub struct RTC8564<IF: I2c> {
     interface: IF,
     address: u8,
@@ -114,6 +282,14 @@ impl<IF: I2c> RTC8564<IF> {
         true
     }
 
+    // デバイスが I2C バス上で応答するかどうか
+    pub fn is_present(&mut self) -> bool {
+        let mut buffer: [u8; 1] = [0; 1];
+        self.interface
+            .write_read(self.address, &[CONTROL1_REG], &mut buffer)
+            .is_ok()
+    }
+
     fn read_register(&mut self, register: u8) -> u8 {
         let mut buffer: [u8; 1] = [0; 1];
         let _ = self
@@ -164,20 +340,16 @@ impl<IF: I2c> RTC8564<IF> {
                 break;
             }
         }
-        let s = ((seconds & 0x7f) >> 4) * 10 + (seconds & 0xf);
-        let mi = ((minutes & 0x7f) >> 4) * 10 + (minutes & 0xf);
-        let h = ((hours & 0x3f) >> 4) * 10 + (hours & 0xf);
-        let mo = ((months & 0x1f) >> 4) * 10 + (months & 0xf);
-        let d = ((days & 0x3f) >> 4) * 10 + (days & 0xf);
-        let y = ((years & 0xff) >> 4) * 10 + (years & 0xf);
-        let mut time = Time {
-            second: s,
-            minute: mi,
-            hour: h,
-            month: mo,
-            day: d,
-            year: y,
-        };
+        let s = bcd_to_bin(seconds & 0x7f);
+        let mi = bcd_to_bin(minutes & 0x7f);
+        let h = bcd_to_bin(hours & 0x3f);
+        let mo = bcd_to_bin(months & 0x1f);
+        let d = bcd_to_bin(days & 0x3f);
+        let mut y = bcd_to_bin(years) as u16 + BASE_YEAR;
+        if (months & CENTURY_BIT) == CENTURY_BIT {
+            y += 100;
+        }
+        let time = Time::new(y, mo, d, h, mi, s);
         self.minutes = time.minute;
         time
     }
@@ -186,3 +358,131 @@ impl<IF: I2c> RTC8564<IF> {
         self.minutes
     }
 }
+
+impl<IF: I2c> Rtc for RTC8564<IF> {
+    fn now(&mut self) -> Time {
+        self.read_date_time()
+    }
+
+    fn set(&mut self, time: &Time) {
+        self.write_register(CONTROL1_REG, CONTROL1_WRITE_DATA_RTC_STOP); // 計時を停止する
+
+        self.write_register(SECONDS_REG, bin_to_bcd(time.second));
+        self.write_register(MINUTES_REG, bin_to_bcd(time.minute));
+        self.write_register(HOURS_REG, bin_to_bcd(time.hour));
+        self.write_register(DAYS_REG, bin_to_bcd(time.day));
+        self.write_register(WEEKDAYS_REG, weekday(time.year, time.month, time.day));
+        let mut months = bin_to_bcd(time.month);
+        if time.year >= BASE_YEAR + 100 {
+            months |= CENTURY_BIT;
+        }
+        self.write_register(MONTHS_CENTURY_REG, months);
+        self.write_register(YEARS_REG, bin_to_bcd((time.year % 100) as u8));
+
+        self.write_register(CONTROL1_REG, CONTROL1_WRITE_DATA_RTC_RUN); // 計時を始める
+        self.minutes = time.minute;
+    }
+
+    fn status(&mut self) -> RtcStatus {
+        if (self.read_register(SECONDS_REG) & VLOW_STATUS) == VLOW_DETECTED {
+            return RtcStatus::VoltageLow;
+        }
+        if (self.read_register(CONTROL1_REG) & STOP_STATUS) == STOP_STATUS {
+            return RtcStatus::Stopped;
+        }
+        RtcStatus::Running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn weekday_of_known_dates() {
+        assert_eq!(weekday(1970, 1, 1), 4); // 木曜日
+        assert_eq!(weekday(2000, 2, 29), 2);
+        assert_eq!(weekday(2024, 3, 5), 2);
+        assert_eq!(weekday(2099, 12, 31), 4);
+    }
+
+    #[test]
+    fn weekday_of_register_garbage() {
+        // I2C が失敗して 0 が読めたときや、BCD が壊れていたとき
+        assert_eq!(weekday(2000, 0, 0), 0);
+        assert_eq!(weekday(2000, 13, 1), 0);
+        assert_eq!(weekday(2165, 165, 165), 0);
+        assert_eq!(weekday(0, 1, 1), 0);
+        assert!(weekday(u16::MAX, 12, u8::MAX) < 7);
+        let t = Time::new(2000, 0, 0, 0, 0, 0);
+        assert!(!t.is_valid());
+        assert_eq!(t.weekday_name(), "Sun");
+    }
+
+    fn same(a: &Time, b: &Time) -> bool {
+        (
+            a.year, a.month, a.day, a.weekday, a.hour, a.minute, a.second,
+        ) == (
+            b.year, b.month, b.day, b.weekday, b.hour, b.minute, b.second,
+        )
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        assert_eq!(Time::new(1970, 1, 1, 0, 0, 0).to_unix(), 0);
+        assert_eq!(Time::new(2000, 3, 1, 0, 0, 0).to_unix(), 951_868_800);
+        assert_eq!(Time::new(2024, 6, 25, 18, 42, 0).to_unix(), 1_719_340_920);
+        assert_eq!(Time::new(2038, 1, 19, 3, 14, 8).to_unix(), 1 << 31);
+        for &seconds in &[0, 951_782_399, 1_719_340_920, 4_102_444_800, u32::MAX] {
+            assert_eq!(Time::from_unix(seconds).to_unix(), seconds);
+        }
+        let t = Time::from_unix(951_782_400); // 閏日
+        assert!(same(&t, &Time::new(2000, 2, 29, 0, 0, 0)));
+        assert_eq!(t.weekday, 2);
+    }
+
+    #[test]
+    fn unix_range_ends_in_2106() {
+        assert_eq!(Time::new(2106, 2, 7, 6, 28, 15).to_unix(), u32::MAX);
+        assert_eq!(Time::new(2106, 2, 7, 6, 28, 16).to_unix(), 0);
+        assert_eq!(Time::new(2199, 12, 31, 23, 59, 59).to_unix(), 0);
+        assert_eq!(Time::new(1969, 12, 31, 23, 59, 59).to_unix(), 0);
+        assert_eq!(Time::new(2024, 2, 30, 0, 0, 0).to_unix(), 0);
+    }
+
+    #[test]
+    fn fat_timestamp_round_trip() {
+        let t = Time::new(2024, 6, 25, 18, 42, 7);
+        let ts = t.to_timestamp();
+        assert_eq!(ts.year_since_1970, 54);
+        assert_eq!(ts.zero_indexed_month, 5);
+        assert_eq!(ts.zero_indexed_day, 24);
+        assert_eq!((ts.hours, ts.minutes, ts.seconds), (18, 42, 7));
+        assert!(same(&Time::from_timestamp(&ts), &t));
+        // FAT のディレクトリエントリに詰めた形 (時刻・日付の順に little endian)。秒は2秒単位
+        assert_eq!(ts.serialize_to_fat(), [0x43, 0x95, 0xd9, 0x58]);
+        let packed = Time::from_timestamp(&Timestamp::from_fat(0x58d9, 0x9543));
+        assert!(same(&packed, &Time::new(2024, 6, 25, 18, 42, 6)));
+    }
+
+    #[test]
+    fn iso8601() {
+        let t = Time::new(2024, 6, 25, 18, 42, 0);
+        assert!(same(
+            &Time::from_iso8601("2024-06-25T18:42:00").unwrap(),
+            &t
+        ));
+        assert!(same(
+            &Time::from_iso8601(" 2024-06-25 18:42:00Z\r\n").unwrap(),
+            &t
+        ));
+        assert!(Time::from_iso8601("2024-06-25T18:42").is_none());
+        assert!(Time::from_iso8601("2024/06/25T18:42:00").is_none());
+        assert!(Time::from_iso8601("2024-06-2xT18:42:00").is_none());
+        assert!(Time::from_iso8601("2023-02-29T00:00:00").is_none());
+        assert!(Time::from_iso8601("2024-06-25T24:00:00").is_none());
+        assert_eq!(std::format!("{}", t), "2024-06-25T18:42:00");
+    }
+}
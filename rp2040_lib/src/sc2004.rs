@@ -1,795 +1,491 @@
+use core::fmt;
+use core::fmt::Write;
+
 use embedded_hal_0_2::blocking::delay::{DelayMs, DelayUs};
-use hd44780_driver::{bus::FourBitBus, Cursor, CursorBlink, Display, DisplayMode, HD44780}; // embedded-hal ver0.2.x
-
-use rp2040_hal::gpio::bank0::Gpio20;
-use rp2040_hal::gpio::bank0::Gpio21;
-use rp2040_hal::gpio::bank0::Gpio22;
-use rp2040_hal::gpio::bank0::Gpio26;
-use rp2040_hal::gpio::bank0::Gpio27;
-use rp2040_hal::gpio::bank0::Gpio28;
-use rp2040_hal::gpio::FunctionSio;
-use rp2040_hal::gpio::Pin;
-use rp2040_hal::gpio::PullDown;
-use rp2040_hal::gpio::PullUp;
-use rp2040_hal::gpio::SioOutput;
-
-//use crate::rtc8564::Time;
-
-use crate::rtc8564::RTC8564;
-use rp2040_hal::gpio::bank0::Gpio16;
-use rp2040_hal::gpio::bank0::Gpio17;
-use rp2040_hal::gpio::FunctionI2c;
-use rp2040_hal::pac::I2C0;
-use rp2040_hal::I2C;
-
-use crate::ScreenState;
-use crate::SW;
-use crate::SWITCH;
-
-const DDRAM_ADDRESS_FIRST: u8 = 0;
-const DDRAM_ADDRESS_SECOND: u8 = 0x40;
-const DDRAM_ADDRESS_THIRD: u8 = 0x14;
-const DDRAM_ADDRESS_FOURTH: u8 = 0x54;
-
-const DDRAM_ADDRESS_SET_DATE_TIME_TOP: u8 = DDRAM_ADDRESS_THIRD + 2;
-
-const POCHI_CODE: u8 = 0xdf; // °のコード
-
-use crate::rtc8564::CONTROL1_REG;
-//use crate::rtc8564::CONTROL2_REG;
-use crate::rtc8564::DAYS_REG;
-use crate::rtc8564::HOURS_REG;
-use crate::rtc8564::MINUTES_REG;
-use crate::rtc8564::MONTHS_CENTURY_REG;
-use crate::rtc8564::SECONDS_REG;
-use crate::rtc8564::WEEKDAYS_REG;
-use crate::rtc8564::YEARS_REG;
-
-const STOP_THE_CLOCK: u8 = 0x20;
-const START_THE_CLOCK: u8 = 0x00;
-
-pub struct SC2004 {
-    interface: HD44780<
-        FourBitBus<
-            Pin<Gpio28, FunctionSio<SioOutput>, PullDown>,
-            Pin<Gpio27, FunctionSio<SioOutput>, PullDown>,
-            Pin<Gpio26, FunctionSio<SioOutput>, PullDown>,
-            Pin<Gpio22, FunctionSio<SioOutput>, PullDown>,
-            Pin<Gpio21, FunctionSio<SioOutput>, PullDown>,
-            Pin<Gpio20, FunctionSio<SioOutput>, PullDown>,
-        >,
-    >,
-    address: u8,
-    position: u8,
-    top_initialized: bool,
-    elements_initialized: bool,
-    date_time_initialized: bool,
-    set_date_time_initialized: bool,
-    set_position: i32,
-    set_date_time_up_down: bool,
-    y10: u8,
-    y1: u8,
-    mo10: u8,
-    mo1: u8,
-    d10: u8,
-    d1: u8,
-    h10: u8,
-    h1: u8,
-    mi10: u8,
-    mi1: u8,
-    s10: u8,
-    s1: u8,
-    buf2: [u8; 20],
-    buf3: [u8; 20],
-    buf4: [u8; 20],
+use embedded_hal_0_2::blocking::i2c;
+use embedded_hal_0_2::digital::v2::OutputPin;
+use hd44780_driver::bus::{DataBus, EightBitBus, FourBitBus, I2CBus}; // embedded-hal ver0.2.x
+use hd44780_driver::Display;
+
+use crate::glyph;
+use crate::glyph::Glyph;
+use crate::glyph::CGRAM_GLYPHS;
+
+// 扱える表示器の最大の桁数と行数
+pub const MAX_COLS: usize = 40;
+pub const MAX_ROWS: usize = 4;
+
+pub const POCHI_CODE: u8 = 0xdf; // °のコード
+
+// "YYYY/MM/DD hh:mm:ss" の長さ。これより狭い表示器では日付と時刻を分ける
+const DATE_TIME_LEN: u8 = 19;
+
+// キャラクタ表示器の大きさと、各行の先頭の DDRAM アドレス
+#[derive(Clone, Copy, PartialEq)]
+pub struct Geometry {
+    pub cols: u8,
+    pub rows: u8,
+    pub row_offsets: [u8; MAX_ROWS],
+}
+
+impl Geometry {
+    pub const LCD1602: Geometry = Geometry {
+        cols: 16,
+        rows: 2,
+        row_offsets: [0x00, 0x40, 0x10, 0x50],
+    };
+    pub const LCD2002: Geometry = Geometry {
+        cols: 20,
+        rows: 2,
+        row_offsets: [0x00, 0x40, 0x14, 0x54],
+    };
+    pub const LCD2004: Geometry = Geometry {
+        cols: 20,
+        rows: 4,
+        row_offsets: [0x00, 0x40, 0x14, 0x54],
+    };
+    pub const LCD4002: Geometry = Geometry {
+        cols: 40,
+        rows: 2,
+        row_offsets: [0x00, 0x40, 0x00, 0x40],
+    };
+
+    // "16x2" のような名前から探す
+    pub fn from_name(name: &str) -> Option<Geometry> {
+        match name {
+            "16x2" => Some(Geometry::LCD1602),
+            "20x2" => Some(Geometry::LCD2002),
+            "20x4" => Some(Geometry::LCD2004),
+            "40x2" => Some(Geometry::LCD4002),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match (self.cols, self.rows) {
+            (16, 2) => "16x2",
+            (20, 2) => "20x2",
+            (40, 2) => "40x2",
+            _ => "20x4",
+        }
+    }
+
+    // 桁と行 (0〜) の DDRAM アドレス
+    pub fn address(&self, col: u8, row: u8) -> u8 {
+        self.row_offsets[row as usize % MAX_ROWS] + col
+    }
+
+    // 4行あれば1行目を見出しに使う
+    pub fn has_title(&self) -> bool {
+        self.rows >= 4
+    }
+
+    // 日時を1行に書けるか
+    pub fn is_wide(&self) -> bool {
+        self.cols >= DATE_TIME_LEN
+    }
 }
 
-impl SC2004 {
-    pub fn new(
-        interface: HD44780<
-            FourBitBus<
-                Pin<Gpio28, FunctionSio<SioOutput>, PullDown>,
-                Pin<Gpio27, FunctionSio<SioOutput>, PullDown>,
-                Pin<Gpio26, FunctionSio<SioOutput>, PullDown>,
-                Pin<Gpio22, FunctionSio<SioOutput>, PullDown>,
-                Pin<Gpio21, FunctionSio<SioOutput>, PullDown>,
-                Pin<Gpio20, FunctionSio<SioOutput>, PullDown>,
-            >,
-        >,
-    ) -> Self {
+// 1行分の表示内容
+type Line = [u8; MAX_COLS];
+
+const BLANK_LINE: Line = [b' '; MAX_COLS];
+
+// 画面の内容を RAM 上に持つ。画面 (ui::Screen) はここに描き、SC2004::flush() で表示器に送る
+pub struct Frame {
+    geometry: Geometry,
+    lines: [Line; MAX_ROWS],
+    cursor: Option<(u8, u8)>, // カーソルの位置 (桁, 行)。None なら消す
+    glyphs: &'static [Glyph], // CGRAM に置く字形。文字コード 0〜7 の順
+}
+
+impl Frame {
+    pub const fn new(geometry: Geometry) -> Self {
         Self {
-            interface,
-            address: 0,
-            position: 0,
-            top_initialized: false,
-            elements_initialized: false,
-            date_time_initialized: false,
-            set_date_time_initialized: false,
-            set_position: 0,
-            set_date_time_up_down: false,
-            y10: 0,
-            y1: 0,
-            mo10: 0,
-            mo1: 1,
-            d10: 0,
-            d1: 1,
-            h10: 0,
-            h1: 0,
-            mi10: 0,
-            mi1: 0,
-            s10: 0,
-            s1: 0,
-            buf2: [0; 20],
-            buf3: [0; 20],
-            buf4: [0; 20],
+            geometry,
+            lines: [BLANK_LINE; MAX_ROWS],
+            cursor: None,
+            glyphs: &glyph::STATUS,
         }
     }
-    pub fn init<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D) {
-        let _ = self.interface.reset(delay);
-        let _ = self.interface.clear(delay);
-        let _ = self.interface.set_display_mode(
-            DisplayMode {
-                display: Display::On,
-                cursor_visibility: Cursor::Invisible,
-                cursor_blink: CursorBlink::Off,
-            },
-            delay,
-        );
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
     }
-    pub fn clear_screen<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D) {
-        let _ = self.interface.clear(delay);
-    }
-
-    pub fn set_elements<D: DelayUs<u16> + DelayMs<u8>>(
-        &mut self,
-        delay: &mut D,
-        tup: (f64, f64, f64),
-        rtc: &mut RTC8564<
-            I2C<
-                I2C0,
-                (
-                    Pin<Gpio16, FunctionI2c, PullUp>,
-                    Pin<Gpio17, FunctionI2c, PullUp>,
-                ),
-            >,
-        >,
-        state: &mut ScreenState,
-    ) {
-        if !self.elements_initialized {
-            self.elements_initialized = true;
-
-            let s_temp = "Temp:     .   C     "; // buf2[]の長さに合わせること、さもないと panic する
-            let s_humi = "Humi:     .  %      "; // buf3[]　同上
-            let s_pres = "Pres:     .  hPa    "; // buf4[]　同上
-
-            let ref_temp = s_temp.as_bytes();
-            let ref_humi = s_humi.as_bytes();
-            let ref_pres = s_pres.as_bytes();
-
-            for i in 0..ref_temp.len() {
-                self.buf2[i] = ref_temp[i];
-            }
-            self.buf2[13] = POCHI_CODE; // ° を表示するコード
 
-            for i in 0..ref_humi.len() {
-                self.buf3[i] = ref_humi[i];
-            }
-            for i in 0..ref_pres.len() {
-                self.buf4[i] = ref_pres[i];
-            }
+    // 全部空白にしてカーソルを消し、字形を glyph::STATUS に戻す
+    pub fn clear(&mut self) {
+        self.lines = [BLANK_LINE; MAX_ROWS];
+        self.cursor = None;
+        self.glyphs = &glyph::STATUS;
+    }
+
+    // CGRAM に置く字形を選ぶ。9個目からは無視する
+    pub fn set_glyphs(&mut self, glyphs: &'static [Glyph]) {
+        self.glyphs = &glyphs[..glyphs.len().min(CGRAM_GLYPHS)];
+    }
+
+    // 字形の文字コード。CGRAM に無ければ ROM の似た文字
+    pub fn glyph_code(&self, glyph: Glyph) -> u8 {
+        match self.glyphs.iter().position(|&g| g == glyph) {
+            Some(code) => code as u8,
+            None => glyph.fallback(),
         }
+    }
 
-        self.display_date_time(delay, rtc);
+    pub fn put_glyph(&mut self, col: u8, row: u8, glyph: Glyph) {
+        let code = self.glyph_code(glyph);
+        self.put(col, row, &[code]);
+    }
 
-        let tens_digit: u8 = ((tup.0 as i32 / 10) % 10) as u8 | b'0';
-        let ones_digit: u8 = ((tup.0 as i32) % 10) as u8 | b'0';
-        let tenths_digit: u8 = (((tup.0 * 10.0) as i32) % 10) as u8 | b'0';
+    pub fn clear_row(&mut self, row: u8) {
+        self.lines[row as usize % MAX_ROWS] = BLANK_LINE;
+    }
 
-        self.buf2[8] = tens_digit;
-        self.buf2[9] = ones_digit;
-        self.buf2[11] = tenths_digit;
+    // col 桁目から text を書く。表示器の幅をはみ出た分は捨てる
+    pub fn put(&mut self, col: u8, row: u8, text: &[u8]) {
+        let cols = self.geometry.cols as usize;
+        let col = col as usize;
+        if col < cols {
+            let n = text.len().min(cols - col);
+            self.lines[row as usize % MAX_ROWS][col..col + n].copy_from_slice(&text[..n]);
+        }
+    }
 
-        let tens_digit: u8 = ((tup.1 as i32 / 10) % 10) as u8 | b'0';
-        let ones_digit: u8 = ((tup.1 as i32) % 10) as u8 | b'0';
-        let tenths_digit: u8 = (((tup.1 * 10.0) as i32) % 10) as u8 | b'0';
+    // write! と同じ書式で col 桁目から書く。書いた桁数を返す
+    pub fn print(&mut self, col: u8, row: u8, args: fmt::Arguments) -> u8 {
+        let mut w = FrameWriter {
+            frame: self,
+            col,
+            row,
+        };
+        let _ = w.write_fmt(args);
+        w.col - col
+    }
 
-        self.buf3[8] = tens_digit;
-        self.buf3[9] = ones_digit;
-        self.buf3[11] = tenths_digit;
+    pub fn set_cursor(&mut self, cursor: Option<(u8, u8)>) {
+        self.cursor = cursor;
+    }
+}
 
-        let mut thousands_digit: u8 = ((tup.2 as i32 / 1000) % 10) as u8 | b'0';
-        let hundreds_digit: u8 = ((tup.2 as i32 / 100) % 10) as u8 | b'0';
-        let tens_digit: u8 = ((tup.2 as i32 / 10) % 10) as u8 | b'0';
-        let ones_digit: u8 = ((tup.2 as i32) % 10) as u8 | b'0';
-        let tenths_digit: u8 = (((tup.2 * 10.0) as i32) % 10) as u8 | b'0';
+struct FrameWriter<'a> {
+    frame: &'a mut Frame,
+    col: u8,
+    row: u8,
+}
 
-        if thousands_digit == b'0' {
-            thousands_digit = b' ';
+impl Write for FrameWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // '°' だけは表示器の文字コードに直す
+        for c in s.chars() {
+            let code = match c {
+                '°' => self.frame.glyph_code(Glyph::Degree),
+                c if c.is_ascii() => c as u8,
+                _ => b'?',
+            };
+            self.frame.put(self.col, self.row, &[code]);
+            self.col = self.col.saturating_add(1);
         }
+        Ok(())
+    }
+}
+
+// HD44780 のバス。4ビット接続 (I2C バックパックも4ビット) と8ビット接続では起動の手順が違う
+pub trait Hd44780Bus: DataBus {
+    const FOUR_BIT: bool;
+}
+
+impl<RS, EN, D4, D5, D6, D7> Hd44780Bus for FourBitBus<RS, EN, D4, D5, D6, D7>
+where
+    RS: OutputPin,
+    EN: OutputPin,
+    D4: OutputPin,
+    D5: OutputPin,
+    D6: OutputPin,
+    D7: OutputPin,
+{
+    const FOUR_BIT: bool = true;
+}
+
+impl<RS, EN, D0, D1, D2, D3, D4, D5, D6, D7> Hd44780Bus
+    for EightBitBus<RS, EN, D0, D1, D2, D3, D4, D5, D6, D7>
+where
+    RS: OutputPin,
+    EN: OutputPin,
+    D0: OutputPin,
+    D1: OutputPin,
+    D2: OutputPin,
+    D3: OutputPin,
+    D4: OutputPin,
+    D5: OutputPin,
+    D6: OutputPin,
+    D7: OutputPin,
+{
+    const FOUR_BIT: bool = false;
+}
+
+impl<I2C: i2c::Write> Hd44780Bus for I2CBus<I2C> {
+    const FOUR_BIT: bool = true;
+}
+
+// HD44780 のコマンド
+// hd44780-driver の HD44780 からは CGRAM に書けないので、コマンドはバスに直接送る
+const CLEAR_DISPLAY: u8 = 0x01;
+const ENTRY_MODE_INCREMENT: u8 = 0x06;
+const DISPLAY_CONTROL: u8 = 0x08;
+const DISPLAY_ON: u8 = 0x04;
+const CURSOR_ON: u8 = 0x02;
+const FUNCTION_SET_8BIT: u8 = 0x30;
+const FUNCTION_SET_8BIT_2LINES: u8 = 0x38;
+const FUNCTION_SET_4BIT_2LINES: u8 = 0x28;
+const SET_CGRAM_ADDRESS: u8 = 0x40;
+const SET_DDRAM_ADDRESS: u8 = 0x80;
+
+// hd44780-driver のバスは EN を上げるたびに delay_ms(2) で待つので、4ビット接続では1文字に 4ms かかり、
+// 20x4 を書き直すと 320ms 止まる。HD44780 の EN のパルス幅は 450ns、書き込みの処理は 37µs なので、
+// 初期化の後はその待ちを ENABLE_PULSE_US に縮める。20x4 の全部を書き直しても 80 x 2 x 50µs ≒ 8ms
+// (I2C バックパックは、これに 1文字あたり I2C の4回の転送が加わる)
+const ENABLE_PULSE_US: u16 = 50;
+
+// バスに渡す delay。delay_ms() を ENABLE_PULSE_US の待ちにする
+// 初期化の手順は ms 単位の待ちが要るので、init() では元の delay をそのまま渡す
+struct BusDelay<'a, D>(&'a mut D);
+
+impl<D: DelayUs<u16>> DelayMs<u8> for BusDelay<'_, D> {
+    fn delay_ms(&mut self, _ms: u8) {
+        self.0.delay_us(ENABLE_PULSE_US);
+    }
+}
 
-        self.buf4[6] = thousands_digit;
-        self.buf4[7] = hundreds_digit;
-        self.buf4[8] = tens_digit;
-        self.buf4[9] = ones_digit;
-        self.buf4[11] = tenths_digit;
+impl<D: DelayUs<u16>> DelayUs<u16> for BusDelay<'_, D> {
+    fn delay_us(&mut self, us: u16) {
+        self.0.delay_us(us);
+    }
+}
 
-        let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_SECOND, delay);
-        for i in 0..20 {
-            let _ = self.interface.write_char(self.buf2[i] as char, delay);
+// バスは FourBitBus / EightBitBus / I2CBus (PCF8574 の I2C バックパック) のどれでもよい
+pub struct SC2004<B: Hd44780Bus> {
+    bus: B,
+    frame: Frame,
+    display_on: bool,
+    shown: [Line; MAX_ROWS],        // 表示器に送った内容
+    shown_cursor: Option<(u8, u8)>, // 文字を送るとずれるので、そのときは None
+    shown_cursor_visible: bool,
+    shown_glyphs: [Option<Glyph>; CGRAM_GLYPHS], // CGRAM に置いた字形。None は不明
+}
+
+impl<B: Hd44780Bus> SC2004<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus,
+            frame: Frame::new(Geometry::LCD2004),
+            display_on: true,
+            shown: [BLANK_LINE; MAX_ROWS],
+            shown_cursor: None,
+            shown_cursor_visible: false,
+            shown_glyphs: [None; CGRAM_GLYPHS],
         }
-        let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_THIRD, delay);
-        for i in 0..20 {
-            let _ = self.interface.write_char(self.buf3[i] as char, delay);
+    }
+    // 表示器の大きさ。init() の前に呼ぶ（既定は 20x4）
+    pub fn set_geometry(&mut self, geometry: Geometry) {
+        self.frame = Frame::new(geometry);
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.frame.geometry
+    }
+
+    // データシートの手順でバスの幅を決めてから、表示を点けて消去する
+    pub fn init<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D) {
+        delay.delay_ms(15u8);
+        if B::FOUR_BIT {
+            // 4ビットのバスは上位・下位の順に送るので、0x33 で 0x3 を2回、0x32 で 0x3 と 0x2 を送る
+            self.command(delay, 0x33);
+            delay.delay_ms(5u8);
+            self.command(delay, 0x32);
+            self.command(delay, FUNCTION_SET_4BIT_2LINES);
+        } else {
+            self.command(delay, FUNCTION_SET_8BIT);
+            delay.delay_ms(5u8);
+            self.command(delay, FUNCTION_SET_8BIT_2LINES);
         }
-        let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_FOURTH, delay);
-        for i in 0..20 {
-            let _ = self.interface.write_char(self.buf4[i] as char, delay);
+        self.display_on = true;
+        self.shown_cursor_visible = false;
+        self.display_control(delay);
+        self.command(delay, ENTRY_MODE_INCREMENT);
+        self.shown_glyphs = [None; CGRAM_GLYPHS];
+        self.clear_screen(delay);
+    }
+
+    pub fn clear_screen<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D) {
+        self.command(delay, CLEAR_DISPLAY);
+        delay.delay_ms(2u8); // 消去は 1.52ms かかる
+        self.frame.clear();
+        self.shown = [BLANK_LINE; MAX_ROWS];
+        self.shown_cursor = None;
+    }
+
+    // 画面はここに描く。表示器に送るのは flush() のとき
+    pub fn frame(&mut self) -> &mut Frame {
+        &mut self.frame
+    }
+
+    // 字形を文字コード 0 から順に CGRAM に置く（最大8個）。置いてあるものは送らない
+    // CGRAM を書き換えると、その文字コードを表示している所もすぐに変わる
+    pub fn load_glyphs<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D, glyphs: &[Glyph]) {
+        self.send_glyphs(&mut BusDelay(delay), glyphs);
+    }
+
+    fn send_glyphs<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D, glyphs: &[Glyph]) {
+        for (code, &glyph) in glyphs.iter().take(CGRAM_GLYPHS).enumerate() {
+            if self.shown_glyphs[code] != Some(glyph) {
+                self.command(delay, SET_CGRAM_ADDRESS | ((code as u8) << 3));
+                self.write_data(delay, &glyph.pattern());
+                self.shown_glyphs[code] = Some(glyph);
+                self.shown_cursor = None;
+            }
         }
+    }
 
-        let _ = self
-            .interface
-            .set_cursor_visibility(Cursor::Invisible, delay);
-        unsafe {
-            match SWITCH {
-                SW::None => (),
-                _ => {
-                    // None 以外（何かのSW押下で）
-                    *state = ScreenState::Top;
-                    SWITCH = SW::None;
-                    self.elements_initialized = false;
-                    self.date_time_initialized = false;
-                    let _ = self.interface.set_display_mode(
-                        DisplayMode {
-                            display: Display::On,
-                            cursor_visibility: Cursor::Visible,
-                            cursor_blink: CursorBlink::Off,
-                        },
-                        delay,
-                    );
+    // フレームバッファのうち表示器と違う所だけを送る
+    // 続けて変わった桁は1回のアドレス指定でまとめて書く
+    pub fn flush<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D) {
+        let delay = &mut BusDelay(delay);
+        let glyphs = self.frame.glyphs;
+        self.send_glyphs(delay, glyphs);
+
+        let g = self.frame.geometry;
+        let cols = g.cols as usize;
+        for row in 0..g.rows as usize {
+            let mut col = 0;
+            while col < cols {
+                let line = &self.frame.lines[row];
+                if line[col] == self.shown[row][col] {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < cols && line[col] != self.shown[row][col] {
+                    col += 1;
+                }
+                self.shown[row][start..col].copy_from_slice(&line[start..col]);
+                self.command(delay, SET_DDRAM_ADDRESS | g.address(start as u8, row as u8));
+                for &byte in &self.shown[row][start..col] {
+                    let _ = self.bus.write(byte, true, delay);
                 }
+                self.shown_cursor = None;
+            }
+        }
+
+        // カーソルは見えているときだけ置き直す
+        let cursor = self.frame.cursor;
+        if cursor.is_some() && self.shown_cursor != cursor {
+            if let Some((col, row)) = cursor {
+                self.command(delay, SET_DDRAM_ADDRESS | g.address(col, row));
             }
+            self.shown_cursor = cursor;
         }
+        if cursor.is_some() != self.shown_cursor_visible {
+            self.shown_cursor_visible = cursor.is_some();
+            self.display_control(delay);
+        }
+    }
+
+    pub fn set_display<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D, display: Display) {
+        self.display_on = matches!(display, Display::On);
+        self.display_control(&mut BusDelay(delay));
     }
 
-    pub fn set_cursor_visibility<D: DelayUs<u16> + DelayMs<u8>>(
-        &mut self,
-        visibility: Cursor,
-        delay: &mut D,
-    ) {
-        let _ = self.interface.set_cursor_visibility(visibility, delay);
-    }
-    pub fn display_date_time<D: DelayUs<u16> + DelayMs<u8>>(
-        &mut self,
-        delay: &mut D,
-        rtc: &mut RTC8564<
-            I2C<
-                I2C0,
-                (
-                    Pin<Gpio16, FunctionI2c, PullUp>,
-                    Pin<Gpio17, FunctionI2c, PullUp>,
-                ),
-            >,
-        >,
-    ) {
-        if !self.date_time_initialized {
-            self.date_time_initialized = true;
+    fn display_control<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D) {
+        let mut command = DISPLAY_CONTROL;
+        if self.display_on {
+            command |= DISPLAY_ON;
         }
-        let t = rtc.read_date_time();
-
-        let s: &mut [u8; 19] = &mut [
-            b'2', b'0', b'2', b'4', b'/', b'0', b'1', b'/', b'0', b'1', b' ', b'0', b'1', b':',
-            b'0', b'0', b':', b'0', b'0',
-        ];
-
-        let _ = self.interface.set_cursor_pos(0x00, delay);
-
-        let mut dig10: u8 = 0;
-        let mut dig1: u8 = 0;
-
-        dig10 = (t.year / 10) | b'0';
-        dig1 = (t.year % 10) | b'0';
-
-        s[2] = dig10;
-        s[3] = dig1;
-
-        dig10 = (t.month / 10) | b'0';
-        dig1 = (t.month % 10) | b'0';
-
-        s[5] = dig10;
-        s[6] = dig1;
-
-        dig10 = (t.day / 10) | b'0';
-        dig1 = (t.day % 10) | b'0';
-
-        s[8] = dig10;
-        s[9] = dig1;
-
-        dig10 = (t.hour / 10) | b'0';
-        dig1 = (t.hour % 10) | b'0';
-
-        s[11] = dig10;
-        s[12] = dig1;
-
-        dig10 = (t.minute / 10) | b'0';
-        dig1 = (t.minute % 10) | b'0';
-
-        s[14] = dig10;
-        s[15] = dig1;
-
-        dig10 = (t.second / 10) | b'0';
-        dig1 = (t.second % 10) | b'0';
-
-        s[17] = dig10;
-        s[18] = dig1;
-
-        let _ = self.interface.write_bytes(s, delay);
-    }
-
-    pub fn set_date_time<D: DelayUs<u16> + DelayMs<u8>>(
-        &mut self,
-        delay: &mut D,
-        rtc: &mut RTC8564<
-            I2C<
-                I2C0,
-                (
-                    Pin<Gpio16, FunctionI2c, PullUp>,
-                    Pin<Gpio17, FunctionI2c, PullUp>,
-                ),
-            >,
-        >,
-        state: &mut ScreenState,
-    ) {
-        if !self.set_date_time_initialized {
-            self.set_date_time_initialized = true;
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_FIRST, delay);
-            let _ = self.interface.write_str(" Set Date Time      ", delay);
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_SECOND, delay);
-            let _ = self.interface.write_str("                    ", delay);
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_FOURTH, delay);
-            let _ = self.interface.write_str("                    ", delay);
-            let _ = self.interface.set_cursor_visibility(Cursor::Visible, delay);
-
-            let t = rtc.read_date_time();
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_THIRD, delay);
-            let _ = self.interface.write_char(b'2' as char, delay);
-            let _ = self.interface.write_char(b'0' as char, delay);
-            let dig10 = (t.year / 10) | b'0';
-            let dig1 = (t.year % 10) | b'0';
-            self.y10 = dig10;
-            self.y1 = dig1;
-            let _ = self.interface.write_char(self.y10 as char, delay);
-            let _ = self.interface.write_char(self.y1 as char, delay);
-            let _ = self.interface.write_char(b'/' as char, delay);
-
-            let dig10 = (t.month / 10) | b'0';
-            let dig1 = (t.month % 10) | b'0';
-            self.mo10 = dig10;
-            self.mo1 = dig1;
-            let _ = self.interface.write_char(self.mo10 as char, delay);
-            let _ = self.interface.write_char(self.mo1 as char, delay);
-            let _ = self.interface.write_char(b'/' as char, delay);
-
-            let dig10 = (t.day / 10) | b'0';
-            let dig1 = (t.day % 10) | b'0';
-            self.d10 = dig10;
-            self.d1 = dig1;
-            let _ = self.interface.write_char(self.d10 as char, delay);
-            let _ = self.interface.write_char(self.d1 as char, delay);
-            let _ = self.interface.write_char(b' ' as char, delay);
-
-            let dig10 = (t.hour / 10) | b'0';
-            let dig1 = (t.hour % 10) | b'0';
-            self.h10 = dig10;
-            self.h1 = dig1;
-            let _ = self.interface.write_char(self.h10 as char, delay);
-            let _ = self.interface.write_char(self.h1 as char, delay);
-            let _ = self.interface.write_char(b':' as char, delay);
-
-            let dig10 = (t.minute / 10) | b'0';
-            let dig1 = (t.minute % 10) | b'0';
-            self.mi10 = dig10;
-            self.mi1 = dig1;
-            let _ = self.interface.write_char(self.mi10 as char, delay);
-            let _ = self.interface.write_char(self.mi1 as char, delay);
-            let _ = self.interface.write_char(b':' as char, delay);
-
-            let dig10 = (t.second / 10) | b'0';
-            let dig1 = (t.second % 10) | b'0';
-            self.s10 = dig10;
-            self.s1 = dig1;
-            let _ = self.interface.write_char(self.s10 as char, delay);
-            let _ = self.interface.write_char(self.s1 as char, delay);
+        if self.shown_cursor_visible {
+            command |= CURSOR_ON;
         }
+        self.command(delay, command);
+    }
+
+    fn command<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D, command: u8) {
+        let _ = self.bus.write(command, false, delay);
+        delay.delay_us(100); // 処理を待つ
+    }
 
-        if self.set_date_time_up_down {
-            self.set_date_time_up_down = false;
-            let _ = self
-                .interface
-                .set_cursor_pos(DDRAM_ADDRESS_THIRD + 2, delay);
-            let _ = self.interface.write_char(self.y10 as char, delay);
-            let _ = self.interface.write_char(self.y1 as char, delay);
-            let _ = self.interface.write_char(b'/' as char, delay);
-            let _ = self.interface.write_char(self.mo10 as char, delay);
-            let _ = self.interface.write_char(self.mo1 as char, delay);
-            let _ = self.interface.write_char(b'/' as char, delay);
-            let _ = self.interface.write_char(self.d10 as char, delay);
-            let _ = self.interface.write_char(self.d1 as char, delay);
-            let _ = self.interface.write_char(b' ' as char, delay);
-            let _ = self.interface.write_char(self.h10 as char, delay);
-            let _ = self.interface.write_char(self.h1 as char, delay);
-            let _ = self.interface.write_char(b':' as char, delay);
-            let _ = self.interface.write_char(self.mi10 as char, delay);
-            let _ = self.interface.write_char(self.mi1 as char, delay);
-            let _ = self.interface.write_char(b':' as char, delay);
-            let _ = self.interface.write_char(self.s10 as char, delay);
-            let _ = self.interface.write_char(self.s1 as char, delay);
+    fn write_data<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D, data: &[u8]) {
+        for &byte in data {
+            let _ = self.bus.write(byte, true, delay);
         }
+    }
+}
 
-        let move_cursor_pos = [0, 1, 3, 4, 6, 7, 9, 10, 12, 13, 15, 16];
-
-        let _ = self.interface.set_cursor_pos(
-            DDRAM_ADDRESS_SET_DATE_TIME_TOP + move_cursor_pos[self.set_position as usize],
-            delay,
-        );
-
-        unsafe {
-            match SWITCH {
-                SW::None => (),
-                SW::Left => {
-                    if self.set_position == 0 {
-                        // 最初に Left 押下でキャンセルしてTopに戻る
-                        *state = ScreenState::Top;
-                        self.set_date_time_initialized = false;
-                    } else {
-                        self.set_position -= 1;
-                        if self.set_position < 0 {
-                            self.set_position = 11;
-                        }
-                    }
-                    SWITCH = SW::None;
-                }
-                SW::Center => {
-                    let mut temp: u8 = 0;
-                    self.set_date_time_initialized = false;
-                    SWITCH = SW::None;
-                    rtc.write_register(CONTROL1_REG, STOP_THE_CLOCK); // 計時を停止する
-
-                    temp = (self.s10 & 0xf) << 4 | (self.s1 & 0xf);
-                    rtc.write_register(SECONDS_REG, temp);
-                    temp = (self.mi10 & 0xf) << 4 | (self.mi1 & 0xf);
-                    rtc.write_register(MINUTES_REG, temp);
-                    temp = (self.h10 & 0xf) << 4 | (self.h1 & 0xf);
-                    rtc.write_register(HOURS_REG, temp);
-                    temp = (self.d10 & 0xf) << 4 | (self.d1 & 0xf);
-                    rtc.write_register(DAYS_REG, temp);
-                    rtc.write_register(WEEKDAYS_REG, 0);
-                    temp = (self.mo10 & 0xf) << 4 | (self.mo1 & 0xf);
-                    rtc.write_register(MONTHS_CENTURY_REG, temp);
-                    temp = (self.y10 & 0xf) << 4 | (self.y1 & 0xf);
-                    rtc.write_register(YEARS_REG, temp);
-
-                    rtc.write_register(CONTROL1_REG, START_THE_CLOCK); // 計時を始める
-                    *state = ScreenState::Top;
-                    SWITCH = SW::None;
-                    self.set_date_time_initialized = false;
-                }
-                SW::Right => {
-                    self.set_position += 1;
-                    if self.set_position > 11 {
-                        self.set_position = 0;
-                    }
-                    SWITCH = SW::None;
-                }
-                SW::Up => {
-                    self.set_date_time_up_down = true;
-                    match self.set_position {
-                        0 => {
-                            self.y10 &= 0xf;
-                            if self.y10 == 9 {
-                                self.y10 = 0;
-                            } else {
-                                self.y10 += 1;
-                            }
-                            self.y10 |= b'0';
-                        }
-                        1 => {
-                            self.y1 &= 0xf;
-                            if self.y1 == 9 {
-                                self.y1 = 0;
-                            } else {
-                                self.y1 += 1;
-                            }
-                            self.y1 |= b'0';
-                        }
-                        2 => {
-                            self.mo10 &= 0xf;
-                            if self.mo10 == 9 {
-                                self.mo10 = 0;
-                            } else {
-                                self.mo10 += 1;
-                            }
-                            self.mo10 |= b'0';
-                        }
-                        3 => {
-                            self.mo1 &= 0xf;
-                            if self.mo1 == 9 {
-                                self.mo1 = 0;
-                            } else {
-                                self.mo1 += 1;
-                            }
-                            self.mo1 |= b'0';
-                        }
-                        4 => {
-                            self.d10 &= 0xf;
-                            if self.d10 == 9 {
-                                self.d10 = 0;
-                            } else {
-                                self.d10 += 1;
-                            }
-                            self.d10 |= b'0';
-                        }
-                        5 => {
-                            self.d1 &= 0xf;
-                            if self.d1 == 9 {
-                                self.d1 = 0;
-                            } else {
-                                self.d1 += 1;
-                            }
-                            self.d1 |= b'0';
-                        }
-                        6 => {
-                            self.h10 &= 0xf;
-                            if self.h10 == 9 {
-                                self.h10 = 0;
-                            } else {
-                                self.h10 += 1;
-                            }
-                            self.h10 |= b'0';
-                        }
-                        7 => {
-                            self.h1 &= 0xf;
-                            if self.h1 == 9 {
-                                self.h1 = 0;
-                            } else {
-                                self.h1 += 1;
-                            }
-                            self.h1 |= b'0';
-                        }
-                        8 => {
-                            self.mi10 &= 0xf;
-                            if self.mi10 == 9 {
-                                self.mi10 = 0;
-                            } else {
-                                self.mi10 += 1;
-                            }
-                            self.mi10 |= b'0';
-                        }
-                        9 => {
-                            self.mi1 &= 0xf;
-                            if self.mi1 == 9 {
-                                self.mi1 = 0;
-                            } else {
-                                self.mi1 += 1;
-                            }
-                            self.mi1 |= b'0';
-                        }
-                        10 => {
-                            self.s10 &= 0xf;
-                            if self.s10 == 9 {
-                                self.s10 = 0;
-                            } else {
-                                self.s10 += 1;
-                            }
-                            self.s10 |= b'0';
-                        }
-                        11 => {
-                            self.s1 &= 0xf;
-                            if self.s1 == 9 {
-                                self.s1 = 0;
-                            } else {
-                                self.s1 += 1;
-                            }
-                            self.s1 |= b'0';
-                        }
-                        i32::MIN..=-1_i32 | 12_i32..=i32::MAX => (),
-                    }
-                    SWITCH = SW::None;
-                }
-                SW::Down => {
-                    self.set_date_time_up_down = true;
-                    match self.set_position {
-                        0 => {
-                            self.y10 &= 0xf;
-                            if self.y10 == 0 {
-                                self.y10 = 9;
-                            } else {
-                                self.y10 = (self.y10 as i8 - 1) as u8;
-                            }
-                            self.y10 |= b'0';
-                        }
-                        1 => {
-                            self.y1 &= 0xf;
-                            if self.y1 == 0 {
-                                self.y1 = 9;
-                            } else {
-                                self.y1 = (self.y1 as i8 - 1) as u8;
-                            }
-                            self.y1 |= b'0';
-                        }
-                        2 => {
-                            self.mo10 &= 0xf;
-                            if self.mo10 == 0 {
-                                self.mo10 = 9;
-                            } else {
-                                self.mo10 = (self.mo10 as i8 - 1) as u8;
-                            }
-                            self.mo10 |= b'0';
-                        }
-                        3 => {
-                            self.mo1 &= 0xf;
-                            if self.mo1 == 0 {
-                                self.mo1 = 9;
-                            } else {
-                                self.mo1 = (self.mo1 as i8 - 1) as u8;
-                            }
-                            self.mo1 |= b'0';
-                        }
-                        4 => {
-                            self.d10 &= 0xf;
-                            if self.d10 == 0 {
-                                self.d10 = 9;
-                            } else {
-                                self.d10 = (self.d10 as i8 - 1) as u8;
-                            }
-                            self.d10 |= b'0';
-                        }
-                        5 => {
-                            self.d1 &= 0xf;
-                            if self.d1 == 0 {
-                                self.d1 = 9;
-                            } else {
-                                self.d1 = (self.d1 as i8 - 1) as u8;
-                            }
-                            self.d1 |= b'0';
-                        }
-                        6 => {
-                            self.h10 &= 0xf;
-                            if self.h10 == 0 {
-                                self.h10 = 9;
-                            } else {
-                                self.h10 = (self.h10 as i8 - 1) as u8;
-                            }
-                            self.h10 |= b'0';
-                        }
-                        7 => {
-                            self.h1 &= 0xf;
-                            if self.h1 == 0 {
-                                self.h1 = 9;
-                            } else {
-                                self.h1 = (self.h1 as i8 - 1) as u8;
-                            }
-                            self.h1 |= b'0';
-                        }
-                        8 => {
-                            self.mi10 &= 0xf;
-                            if self.mi10 == 0 {
-                                self.mi10 = 9;
-                            } else {
-                                self.mi10 = (self.mi10 as i8 - 1) as u8;
-                            }
-                            self.mi10 |= b'0';
-                        }
-                        9 => {
-                            self.mi1 &= 0xf;
-                            if self.mi1 == 0 {
-                                self.mi1 = 9;
-                            } else {
-                                self.mi1 = (self.mi1 as i8 - 1) as u8;
-                            }
-                            self.mi1 |= b'0';
-                        }
-                        10 => {
-                            self.s10 &= 0xf;
-                            if self.s10 == 0 {
-                                self.s10 = 9;
-                            } else {
-                                self.s10 = (self.s10 as i8 - 1) as u8;
-                            }
-                            self.s10 |= b'0';
-                        }
-                        11 => {
-                            self.s1 &= 0xf;
-                            if self.s1 == 0 {
-                                self.s1 = 9;
-                            } else {
-                                self.s1 = (self.s1 as i8 - 1) as u8;
-                            }
-                            self.s1 |= b'0';
-                        }
-                        i32::MIN..=-1_i32 | 12_i32..=i32::MAX => (),
-                    }
-                    SWITCH = SW::None;
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hd44780_driver::error::Result;
+
+    // hd44780-driver の FourBitBus と同じように、1バイトに EN のパルスを2回出すバス
+    struct MockBus {
+        writes: u32,
+    }
+
+    impl DataBus for MockBus {
+        fn write<D: DelayUs<u16> + DelayMs<u8>>(
+            &mut self,
+            _byte: u8,
+            _data: bool,
+            delay: &mut D,
+        ) -> Result<()> {
+            delay.delay_ms(2);
+            delay.delay_ms(2);
+            self.writes += 1;
+            Ok(())
         }
     }
 
-    pub fn set_top<D: DelayUs<u16> + DelayMs<u8>>(
-        &mut self,
-        delay: &mut D,
-        state: &mut ScreenState,
-    ) {
-        if !self.top_initialized {
-            self.top_initialized = true;
-            self.address = DDRAM_ADDRESS_SECOND;
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_FIRST, delay);
-            let _ = self.interface.write_str(" Select Item        ", delay);
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_SECOND, delay);
-            let _ = self.interface.write_str("1.Display elements  ", delay);
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_THIRD, delay);
-            let _ = self.interface.write_str("2.Set Date Time     ", delay);
-            let _ = self.interface.set_cursor_pos(DDRAM_ADDRESS_FOURTH, delay);
-            let _ = self.interface.write_str("                    ", delay);
-            let _ = self.interface.set_cursor_pos(self.address, delay);
-
-            let _ = self.interface.set_cursor_visibility(Cursor::Visible, delay);
+    impl Hd44780Bus for MockBus {
+        const FOUR_BIT: bool = true;
+    }
+
+    // 待った時間の合計 (µs)
+    struct MockDelay {
+        us: u32,
+    }
+
+    impl DelayUs<u16> for MockDelay {
+        fn delay_us(&mut self, us: u16) {
+            self.us += us as u32;
         }
-        unsafe {
-            match SWITCH {
-                SW::None => {
-                    if self.position == 0 {
-                        self.address = DDRAM_ADDRESS_SECOND;
-                    } else if self.position == 1 {
-                        self.address = DDRAM_ADDRESS_THIRD;
-                    }
-                    let _ = self.interface.set_cursor_pos(self.address, delay);
-                }
-                SW::Center => {
-                    if self.position == 0 {
-                        *state = ScreenState::Elements;
-                    } else {
-                        *state = ScreenState::SetDateTime;
-                    }
-                    SWITCH = SW::None;
-                    self.top_initialized = false;
-                }
-                SW::Left => {
-                    self.set_position -= 1;
-                    if self.set_position < 0 {
-                        self.set_position = 11;
-                    }
-                }
-                SW::Right => {
-                    self.set_position += 1;
-                    if self.set_position > 11 {
-                        self.set_position = 0;
-                    }
-                }
-                SW::Down => {
-                    self.position ^= 1;
-                    if self.position == 0 {
-                        self.address = DDRAM_ADDRESS_SECOND;
-                    } else {
-                        self.address = DDRAM_ADDRESS_THIRD;
-                    }
-                    let _ = self.interface.set_cursor_pos(self.address, delay);
-                    SWITCH = SW::None;
-                }
-                SW::Up => {
-                    self.position ^= 1;
-                    if self.position == 0 {
-                        self.address = DDRAM_ADDRESS_SECOND;
-                    } else {
-                        self.address = DDRAM_ADDRESS_THIRD;
-                    }
-                    let _ = self.interface.set_cursor_pos(self.address, delay);
-                    SWITCH = SW::None;
-                }
-            }
+    }
+
+    impl DelayMs<u8> for MockDelay {
+        fn delay_ms(&mut self, ms: u8) {
+            self.us += ms as u32 * 1000;
         }
     }
 
-    pub fn set_display<D: DelayUs<u16> + DelayMs<u8>>(&mut self, delay: &mut D, display: Display) {
-        let _ = self.interface.set_display(display, delay);
+    #[test]
+    fn full_redraw_does_not_stall() {
+        let mut lcd = SC2004::new(MockBus { writes: 0 });
+        let mut delay = MockDelay { us: 0 };
+        lcd.init(&mut delay);
+        assert!(delay.us >= 15_000, "init keeps the millisecond waits");
+
+        lcd.flush(&mut delay); // 字形を CGRAM に置く
+        for row in 0..4 {
+            lcd.frame().put(0, row, &[b'8'; 20]);
+        }
+        delay.us = 0;
+        lcd.bus.writes = 0;
+        lcd.flush(&mut delay);
+        // 80文字と4回のアドレス指定 (コマンドの後は 100µs 待つ)
+        assert_eq!(lcd.bus.writes, 84);
+        assert_eq!(delay.us, 84 * 2 * ENABLE_PULSE_US as u32 + 4 * 100);
+
+        // 変わっていなければ何も送らない
+        delay.us = 0;
+        lcd.bus.writes = 0;
+        lcd.flush(&mut delay);
+        assert_eq!((lcd.bus.writes, delay.us), (0, 0));
     }
 }
@@ -0,0 +1,188 @@
+// 計測値を記録する間隔を決める
+// 記録する時刻は地方時の区切り（10分毎なら 00, 10, 20 ... 分）に揃える
+
+use crate::rtc8564::Time;
+
+pub const MIN_INTERVAL: u32 = 1; // 秒
+pub const MAX_INTERVAL: u32 = 24 * 3600;
+
+pub struct LogSchedule {
+    interval: u32,       // 通常の間隔（秒）
+    alarm_interval: u32, // 警報中の間隔（秒）
+    alarm: bool,
+    next_unix: u32, // 次に記録する時刻 (UTC)。0 なら未定
+}
+
+impl LogSchedule {
+    pub fn new(interval: u32) -> Self {
+        let interval = clamp_interval(interval);
+        Self {
+            interval,
+            alarm_interval: interval,
+            alarm: false,
+            next_unix: 0,
+        }
+    }
+
+    // 間隔を変えても決まっている次の時刻は残し、due() で新しい区切りと早い方にする
+    pub fn set_interval(&mut self, seconds: u32) {
+        self.interval = clamp_interval(seconds);
+    }
+
+    pub fn set_alarm_interval(&mut self, seconds: u32) {
+        self.alarm_interval = clamp_interval(seconds);
+    }
+
+    // 警報中は alarm_interval で記録する
+    pub fn set_alarm(&mut self, alarm: bool) {
+        self.alarm = alarm;
+    }
+
+    pub fn is_alarm(&self) -> bool {
+        self.alarm
+    }
+
+    // いま使っている間隔（秒）
+    pub fn interval(&self) -> u32 {
+        if self.alarm {
+            self.alarm_interval
+        } else {
+            self.interval
+        }
+    }
+
+    // 記録する時刻になっていれば true を返し、次の時刻に進める
+    // 間に合わなかった分はまとめて1回とする
+    pub fn due(&mut self, now: &Time, utc_offset_minutes: i16) -> bool {
+        let now_unix = now.to_unix();
+        if now_unix == 0 {
+            return false; // RTC の日時が正しくない
+        }
+        let interval = self.interval();
+        let next = next_boundary(now_unix, interval, utc_offset_minutes);
+        // 初回と、間隔を短くしたときや RTC を戻したときで次の時刻が遠すぎれば決め直す
+        if self.next_unix == 0 || self.next_unix > next {
+            self.next_unix = next;
+            return false;
+        }
+        if now_unix >= self.next_unix {
+            self.next_unix = next;
+            return true;
+        }
+        false
+    }
+}
+
+// now より後で、地方時が interval の倍数になる最初の時刻
+pub fn next_boundary(now_unix: u32, interval: u32, utc_offset_minutes: i16) -> u32 {
+    let offset = utc_offset_minutes as i64 * 60;
+    let local = now_unix as i64 + offset;
+    let interval = interval as i64;
+    let next_local = (local.div_euclid(interval) + 1) * interval;
+    (next_local - offset) as u32
+}
+
+fn clamp_interval(seconds: u32) -> u32 {
+    seconds.clamp(MIN_INTERVAL, MAX_INTERVAL)
+}
+
+// 警報を解除するには、範囲の内側にこれだけ戻る必要がある
+// 境界の近くで値が揺れても、警報と記録の間隔が頻繁に切り替わらないようにする
+pub const TEMPERATURE_HYSTERESIS: f64 = 0.5; // ℃
+pub const HUMIDITY_HYSTERESIS: f64 = 2.0; // %
+
+// 警報とみなす範囲。範囲を外れている間は記録の間隔を短くする
+#[derive(Clone, Copy)]
+pub struct AlarmLimits {
+    pub temperature_min: f64, // ℃
+    pub temperature_max: f64, // ℃
+    pub humidity_max: f64,    // %
+}
+
+impl AlarmLimits {
+    // active はいま警報中かどうか。警報中なら範囲をヒステリシスの分だけ狭めて判定する
+    pub fn exceeded(&self, temperature: f64, humidity: f64, active: bool) -> bool {
+        let (t, h) = if active {
+            (TEMPERATURE_HYSTERESIS, HUMIDITY_HYSTERESIS)
+        } else {
+            (0.0, 0.0)
+        };
+        temperature < self.temperature_min + t
+            || temperature > self.temperature_max - t
+            || humidity > self.humidity_max - h
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-06-25T00:00:00Z
+    const DAY: u32 = 1719273600;
+
+    fn at(unix: u32) -> Time {
+        Time::from_unix(unix)
+    }
+
+    #[test]
+    fn fires_on_local_boundaries() {
+        let mut schedule = LogSchedule::new(600);
+        assert!(!schedule.due(&at(DAY + 30), 540));
+        assert!(!schedule.due(&at(DAY + 599), 540));
+        assert!(schedule.due(&at(DAY + 600), 540));
+        assert!(!schedule.due(&at(DAY + 601), 540));
+        // 間に合わなかった分はまとめて1回
+        assert!(schedule.due(&at(DAY + 3000), 540));
+        assert!(!schedule.due(&at(DAY + 3001), 540));
+        assert_eq!(next_boundary(DAY + 1, 3600, 330), DAY + 1800);
+    }
+
+    #[test]
+    fn alarm_keeps_the_pending_record() {
+        let mut schedule = LogSchedule::new(600);
+        schedule.set_alarm_interval(60);
+        assert!(!schedule.due(&at(DAY + 30), 0));
+        // 記録する時刻に警報が始まっても、その回は記録する
+        schedule.set_alarm(true);
+        assert!(schedule.due(&at(DAY + 600), 0));
+        assert!(!schedule.due(&at(DAY + 630), 0));
+        assert!(schedule.due(&at(DAY + 660), 0));
+    }
+
+    #[test]
+    fn alarm_brings_the_next_record_forward() {
+        let mut schedule = LogSchedule::new(600);
+        schedule.set_alarm_interval(60);
+        assert!(!schedule.due(&at(DAY + 30), 0));
+        schedule.set_alarm(true);
+        assert!(!schedule.due(&at(DAY + 90), 0));
+        assert!(schedule.due(&at(DAY + 120), 0));
+        assert_eq!(schedule.interval(), 60);
+
+        // 警報が終わっても、決まっている次の時刻には記録する
+        schedule.set_alarm(false);
+        assert!(schedule.due(&at(DAY + 180), 0));
+        assert!(!schedule.due(&at(DAY + 240), 0));
+        assert!(schedule.due(&at(DAY + 600), 0));
+    }
+
+    #[test]
+    fn limits_have_hysteresis() {
+        let limits = AlarmLimits {
+            temperature_min: 5.0,
+            temperature_max: 30.0,
+            humidity_max: 70.0,
+        };
+        assert!(!limits.exceeded(29.9, 69.0, false));
+        assert!(limits.exceeded(30.1, 69.0, false));
+        assert!(limits.exceeded(4.9, 69.0, false));
+        assert!(limits.exceeded(20.0, 70.1, false));
+        // 警報中は内側に戻るまで続く
+        assert!(limits.exceeded(29.9, 50.0, true));
+        assert!(!limits.exceeded(29.4, 50.0, true));
+        assert!(limits.exceeded(5.3, 50.0, true));
+        assert!(!limits.exceeded(5.6, 50.0, true));
+        assert!(limits.exceeded(20.0, 68.5, true));
+        assert!(!limits.exceeded(20.0, 67.9, true));
+    }
+}
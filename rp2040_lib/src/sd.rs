@@ -1 +1,234 @@
+// SDカードを SPI で読み書きする
+// diskio はブロック単位の読み書き (FatFs の diskio に相当) で、embedded_sdmmc の BlockDevice として使える
+// ここにはカードの種類や CID / CSD など、転送の方法によらないものを置く
+
 pub mod diskio;
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SdError {
+    Transport,         // SPI か CS のピンのエラー
+    NoCard,            // CMD0 に応答しない
+    Timeout,           // 応答やデータが来ない、書き込みが終わらない
+    Command(u8, u8),   // コマンドの番号と R1 応答
+    Crc,               // データの CRC が合わない
+    WriteRejected(u8), // データ応答トークン
+    ReadError(u8),     // データエラートークン
+    Unsupported,       // SD ver1 より前のカードや MMC
+    NotInitialized,    // init() していない
+    OutOfRange,        // カードの大きさを超えている
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CardType {
+    Sd1,  // SD ver1.x (標準容量)
+    Sd2,  // SD ver2.0 以降の標準容量
+    Sdhc, // SDHC / SDXC (ブロック単位でアドレスを指定する)
+}
+
+#[derive(Clone, Copy)]
+pub struct CardInfo {
+    pub card_type: CardType,
+    pub num_blocks: u32,
+    pub cid: Cid,
+    pub csd: Csd,
+}
+
+impl CardInfo {
+    pub fn num_bytes(&self) -> u64 {
+        self.num_blocks as u64 * BLOCK_SIZE as u64
+    }
+}
+
+// CID レジスタ。製造元や製造年月など
+#[derive(Clone, Copy)]
+pub struct Cid {
+    pub raw: [u8; 16],
+}
+
+impl Cid {
+    pub fn manufacturer_id(&self) -> u8 {
+        self.raw[0]
+    }
+
+    pub fn oem_id(&self) -> [u8; 2] {
+        [self.raw[1], self.raw[2]]
+    }
+
+    pub fn product_name(&self) -> [u8; 5] {
+        [
+            self.raw[3],
+            self.raw[4],
+            self.raw[5],
+            self.raw[6],
+            self.raw[7],
+        ]
+    }
+
+    // 上位4ビットが大きな版、下位4ビットが小さな版
+    pub fn product_revision(&self) -> u8 {
+        self.raw[8]
+    }
+
+    pub fn serial_number(&self) -> u32 {
+        u32::from_be_bytes([self.raw[9], self.raw[10], self.raw[11], self.raw[12]])
+    }
+
+    // 製造年月 (年, 月)
+    pub fn manufacturing_date(&self) -> (u16, u8) {
+        let year = ((self.raw[13] & 0x0f) << 4 | self.raw[14] >> 4) as u16 + 2000;
+        (year, self.raw[14] & 0x0f)
+    }
+}
+
+// CSD レジスタ。容量や転送速度
+#[derive(Clone, Copy)]
+pub struct Csd {
+    pub raw: [u8; 16],
+}
+
+impl Csd {
+    // 0: ver1 (標準容量) 1: ver2 (SDHC / SDXC)
+    pub fn version(&self) -> u8 {
+        self.raw[0] >> 6
+    }
+
+    // 容量 (ブロック数)
+    pub fn num_blocks(&self) -> u32 {
+        if self.version() == 1 {
+            // C_SIZE [69:48]。(C_SIZE + 1) x 512KiB
+            let c_size = (self.bits(69, 48) + 1) as u64;
+            (c_size * 1024).min(u32::MAX as u64) as u32
+        } else {
+            // C_SIZE [73:62], C_SIZE_MULT [49:47], READ_BL_LEN [83:80]
+            let c_size = (self.bits(73, 62) + 1) as u64;
+            let mult = 1u64 << (self.bits(49, 47) + 2);
+            let block_len = 1u64 << self.bits(83, 80);
+            (c_size * mult * block_len / BLOCK_SIZE as u64) as u32
+        }
+    }
+
+    // 最大の転送速度 (Hz)。TRAN_SPEED [103:96]
+    pub fn max_transfer_rate(&self) -> u32 {
+        const UNIT: [u32; 4] = [100_000, 1_000_000, 10_000_000, 100_000_000];
+        const VALUE_X10: [u32; 16] = [
+            0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+        ];
+        let tran_speed = self.raw[3];
+        let unit = UNIT[((tran_speed & 0x07) as usize).min(UNIT.len() - 1)];
+        // unit はどれも 10 で割り切れる。先に掛けると 100MHz 単位で u32 をはみ出す
+        unit / 10 * VALUE_X10[((tran_speed >> 3) & 0x0f) as usize]
+    }
+
+    // 上位 (MSB 側) から数えた high..=low ビット。CSD のビット番号は 127 が先頭
+    fn bits(&self, high: u32, low: u32) -> u32 {
+        let mut value = 0;
+        for bit in (low..=high).rev() {
+            let byte = self.raw[15 - (bit / 8) as usize];
+            value = value << 1 | ((byte >> (bit % 8)) & 1) as u32;
+        }
+        value
+    }
+}
+
+// コマンドの CRC7 (多項式 x^7 + x^3 + 1)。最下位ビットの 1 (終了ビット) を付けて返す
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in data {
+        let mut b = byte;
+        for _ in 0..8 {
+            crc <<= 1;
+            if (b ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+            b <<= 1;
+        }
+    }
+    (crc << 1) | 1
+}
+
+// データの CRC16 (CRC-16/XMODEM, 多項式 0x1021, 初期値 0)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc7_of_commands() {
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]), 0x95); // CMD0
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xaa]), 0x87); // CMD8
+        assert_eq!(crc7(&[0x77, 0, 0, 0, 0]), 0x65); // CMD55
+        assert_eq!(crc7(&[0x69, 0x40, 0, 0, 0]), 0x77); // ACMD41 (HCS)
+    }
+
+    #[test]
+    fn crc16_of_data() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(&[0xff; BLOCK_SIZE]), 0x7fa1);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn csd_version2() {
+        // SDHC, TRAN_SPEED 0x32 (25MHz), C_SIZE 0x3b37
+        let csd = Csd {
+            raw: [
+                0x40, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0x00, 0x3b, 0x37, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00,
+            ],
+        };
+        assert_eq!(csd.version(), 1);
+        assert_eq!(csd.bits(69, 48), 0x3b37);
+        assert_eq!(csd.bits(127, 126), 1);
+        assert_eq!(csd.num_blocks(), (0x3b37 + 1) * 1024);
+        assert_eq!(csd.max_transfer_rate(), 25_000_000);
+    }
+
+    #[test]
+    fn csd_version1() {
+        // 2GB, READ_BL_LEN 10, C_SIZE 4095, C_SIZE_MULT 7
+        let csd = Csd {
+            raw: [
+                0x00, 0x00, 0x00, 0x32, 0x00, 0x0a, 0x03, 0xff, 0xc0, 0x03, 0x80, 0x00, 0x00, 0x00,
+                0x00, 0x00,
+            ],
+        };
+        assert_eq!(csd.version(), 0);
+        assert_eq!(csd.bits(83, 80), 10);
+        assert_eq!(csd.bits(73, 62), 4095);
+        assert_eq!(csd.bits(49, 47), 7);
+        assert_eq!(csd.num_blocks(), 4096 * 512 * 1024 / 512);
+    }
+
+    #[test]
+    fn unknown_transfer_rate_is_zero() {
+        // main の mount_sd() で初期化の速さに切り上げる
+        let csd = Csd { raw: [0; 16] };
+        assert_eq!(csd.max_transfer_rate(), 0);
+    }
+
+    #[test]
+    fn fast_transfer_rate_does_not_overflow() {
+        // TRAN_SPEED 0x5b: 5.0 x 100MHz
+        let mut csd = Csd { raw: [0; 16] };
+        csd.raw[3] = 0x5b;
+        assert_eq!(csd.max_transfer_rate(), 500_000_000);
+        csd.raw[3] = 0x7b; // 8.0 x 100MHz
+        assert_eq!(csd.max_transfer_rate(), 800_000_000);
+    }
+}
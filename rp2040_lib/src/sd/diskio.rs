@@ -0,0 +1,515 @@
+// SDカードの SPI モードでのブロックの読み書き
+// init() でカードを初期化してから read_blocks() / write_blocks() を使う
+// 複数ブロックは CMD18 / CMD25 でまとめて転送する
+//
+// 初期化は 400kHz 以下で行い、その後で spi() を使ってクロックを上げる
+//
+//   let card = SdSpi::new(spi, cs, timer);
+//   card.spi(|spi| spi.set_baudrate(freq, 400.kHz()));
+//   let info = card.init()?;
+//   card.spi(|spi| spi.set_baudrate(freq, 16.MHz()));
+
+use core::cell::RefCell;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+use crate::sd::crc16;
+use crate::sd::crc7;
+use crate::sd::CardInfo;
+use crate::sd::CardType;
+use crate::sd::Cid;
+use crate::sd::Csd;
+use crate::sd::SdError;
+use crate::sd::BLOCK_SIZE;
+
+const CMD0: u8 = 0; // GO_IDLE_STATE
+const CMD8: u8 = 8; // SEND_IF_COND
+const CMD9: u8 = 9; // SEND_CSD
+const CMD10: u8 = 10; // SEND_CID
+const CMD12: u8 = 12; // STOP_TRANSMISSION
+const CMD13: u8 = 13; // SEND_STATUS
+const CMD16: u8 = 16; // SET_BLOCKLEN
+const CMD17: u8 = 17; // READ_SINGLE_BLOCK
+const CMD18: u8 = 18; // READ_MULTIPLE_BLOCK
+const CMD24: u8 = 24; // WRITE_BLOCK
+const CMD25: u8 = 25; // WRITE_MULTIPLE_BLOCK
+const CMD55: u8 = 55; // APP_CMD
+const CMD58: u8 = 58; // READ_OCR
+const CMD59: u8 = 59; // CRC_ON_OFF
+const ACMD23: u8 = 23; // SET_WR_BLK_ERASE_COUNT
+const ACMD41: u8 = 41; // SD_SEND_OP_COND
+
+// R1 応答
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+// データトークン
+const TOKEN_START: u8 = 0xfe; // CMD17 / CMD18 / CMD24 のデータの始まり
+const TOKEN_START_MULTI: u8 = 0xfc; // CMD25 のデータの始まり
+const TOKEN_STOP_MULTI: u8 = 0xfd; // CMD25 の終わり
+const DATA_RESPONSE_MASK: u8 = 0x1f;
+const DATA_ACCEPTED: u8 = 0x05;
+
+const IF_COND_ARG: u32 = 0x1aa; // 2.7-3.6V、チェックパターン 0xaa
+const OCR_CCS: u8 = 0x40; // OCR の最上位バイトのビット。1 なら SDHC / SDXC
+const ACMD41_HCS: u32 = 0x4000_0000;
+
+// タイムアウト (msec)
+const INIT_TIMEOUT_MS: u32 = 1000;
+const READ_TIMEOUT_MS: u32 = 200;
+const WRITE_TIMEOUT_MS: u32 = 500;
+const POLL_INTERVAL_US: u32 = 10;
+
+const CMD0_RETRIES: u8 = 10;
+
+// ブロックのバッファ。embedded_sdmmc の Block でも [u8; 512] でも読み書きできるようにする
+pub trait BlockBuffer {
+    fn bytes(&self) -> &[u8; BLOCK_SIZE];
+    fn bytes_mut(&mut self) -> &mut [u8; BLOCK_SIZE];
+}
+
+impl BlockBuffer for [u8; BLOCK_SIZE] {
+    fn bytes(&self) -> &[u8; BLOCK_SIZE] {
+        self
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8; BLOCK_SIZE] {
+        self
+    }
+}
+
+impl BlockBuffer for Block {
+    fn bytes(&self) -> &[u8; BLOCK_SIZE] {
+        &self.contents
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8; BLOCK_SIZE] {
+        &mut self.contents
+    }
+}
+
+// BlockDevice は &self で読み書きするので、中身は RefCell に入れておく
+pub struct SdSpi<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    inner: RefCell<Inner<SPI, CS, DELAY>>,
+}
+
+struct Inner<SPI, CS, DELAY> {
+    spi: SPI,
+    cs: CS,
+    delay: DELAY,
+    crc: bool,              // データの CRC を確かめる
+    card: Option<CardInfo>, // None なら初期化していない
+}
+
+impl<SPI, CS, DELAY> SdSpi<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> Self {
+        Self {
+            inner: RefCell::new(Inner {
+                spi,
+                cs,
+                delay,
+                crc: false,
+                card: None,
+            }),
+        }
+    }
+
+    // CRC を使うかどうか。次の init() から有効になる
+    pub fn set_crc(&self, crc: bool) {
+        self.inner.borrow_mut().crc = crc;
+    }
+
+    // クロックを変えるときなどに SPI を直接使う
+    pub fn spi<R, F: FnOnce(&mut SPI) -> R>(&self, f: F) -> R {
+        f(&mut self.inner.borrow_mut().spi)
+    }
+
+    // カードを初期化して CID / CSD を読む。カードを差し直したら呼び直す
+    pub fn init(&self) -> Result<CardInfo, SdError> {
+        let mut inner = self.inner.borrow_mut();
+        inner.card = None;
+        let result = inner.init();
+        inner.deselect();
+        let info = result?;
+        inner.card = Some(info);
+        Ok(info)
+    }
+
+    // 次の読み書きの前に init() し直させる
+    pub fn mark_uninit(&self) {
+        self.inner.borrow_mut().card = None;
+    }
+
+    pub fn card_info(&self) -> Option<CardInfo> {
+        self.inner.borrow().card
+    }
+
+    pub fn read_blocks<B: BlockBuffer>(&self, start: u32, blocks: &mut [B]) -> Result<(), SdError> {
+        let mut inner = self.inner.borrow_mut();
+        let address = inner.address(start, blocks.len())?;
+        let result = if blocks.len() == 1 {
+            inner.read_single(address, blocks[0].bytes_mut())
+        } else {
+            inner.read_multi(address, blocks)
+        };
+        inner.deselect();
+        result
+    }
+
+    pub fn write_blocks<B: BlockBuffer>(&self, start: u32, blocks: &[B]) -> Result<(), SdError> {
+        let mut inner = self.inner.borrow_mut();
+        let address = inner.address(start, blocks.len())?;
+        let result = if blocks.len() == 1 {
+            inner.write_single(address, blocks[0].bytes())
+        } else {
+            inner.write_multi(address, blocks)
+        };
+        inner.deselect();
+        result
+    }
+
+    // 書き込みが終わるのを待ち、エラーが無かったか確かめる
+    pub fn sync(&self) -> Result<(), SdError> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.card.is_none() {
+            return Err(SdError::NotInitialized);
+        }
+        inner.select()?;
+        let result = inner
+            .wait_ready(WRITE_TIMEOUT_MS)
+            .and_then(|_| inner.check_status());
+        inner.deselect();
+        result
+    }
+}
+
+impl<SPI, CS, DELAY> Inner<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    fn init(&mut self) -> Result<CardInfo, SdError> {
+        // CS を High にしたまま 74 クロック以上送ると SPI モードに入れる
+        self.deselect();
+        for _ in 0..10 {
+            self.transfer(0xff)?;
+        }
+
+        let mut r1 = 0xff;
+        for _ in 0..CMD0_RETRIES {
+            self.select()?;
+            r1 = self.command(CMD0, 0)?;
+            if r1 == R1_IDLE {
+                break;
+            }
+            self.deselect();
+            self.delay.delay_ms(10);
+        }
+        if r1 != R1_IDLE {
+            return Err(SdError::NoCard);
+        }
+
+        // ver2 以降なら CMD8 が通り、送ったチェックパターンが返る
+        let version2 = if self.command(CMD8, IF_COND_ARG)? & R1_ILLEGAL_COMMAND == 0 {
+            let mut r7 = [0xff; 4];
+            self.read_bytes(&mut r7)?;
+            if u32::from_be_bytes(r7) & 0xfff != IF_COND_ARG {
+                return Err(SdError::Unsupported);
+            }
+            true
+        } else {
+            false
+        };
+
+        // アイドル状態の間は R1 が R1_IDLE になる
+        if self.crc {
+            let r1 = self.command(CMD59, 1)?;
+            if r1 & !R1_IDLE != 0 {
+                return Err(SdError::Command(CMD59, r1));
+            }
+        }
+
+        // ACMD41 でアイドル状態を抜けるまで待つ
+        let arg = if version2 { ACMD41_HCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_TIMEOUT_MS {
+            self.command(CMD55, 0)?;
+            let r1 = self.command(ACMD41, arg)?;
+            if r1 == 0 {
+                ready = true;
+                break;
+            }
+            if r1 & R1_ILLEGAL_COMMAND != 0 {
+                return Err(SdError::Unsupported); // MMC は扱わない
+            }
+            self.delay.delay_ms(1);
+        }
+        if !ready {
+            return Err(SdError::Timeout);
+        }
+
+        let card_type = if version2 {
+            self.expect_r1(CMD58, 0)?;
+            let mut ocr = [0xff; 4];
+            self.read_bytes(&mut ocr)?;
+            if ocr[0] & OCR_CCS != 0 {
+                CardType::Sdhc
+            } else {
+                CardType::Sd2
+            }
+        } else {
+            CardType::Sd1
+        };
+        if card_type != CardType::Sdhc {
+            self.expect_r1(CMD16, BLOCK_SIZE as u32)?;
+        }
+
+        let mut csd = [0; 16];
+        self.expect_r1(CMD9, 0)?;
+        self.read_data(&mut csd)?;
+        let mut cid = [0; 16];
+        self.expect_r1(CMD10, 0)?;
+        self.read_data(&mut cid)?;
+        let csd = Csd { raw: csd };
+        Ok(CardInfo {
+            card_type,
+            num_blocks: csd.num_blocks(),
+            cid: Cid { raw: cid },
+            csd,
+        })
+    }
+
+    // ブロック番号をコマンドの引数にする。標準容量のカードはバイト単位で指定する
+    fn address(&self, start: u32, count: usize) -> Result<u32, SdError> {
+        let card = self.card.ok_or(SdError::NotInitialized)?;
+        if start as u64 + count as u64 > card.num_blocks as u64 {
+            return Err(SdError::OutOfRange);
+        }
+        match card.card_type {
+            CardType::Sdhc => Ok(start),
+            // 4GiB を超えるバイト位置は標準容量のカードでは指定できない
+            _ => start
+                .checked_mul(BLOCK_SIZE as u32)
+                .ok_or(SdError::OutOfRange),
+        }
+    }
+
+    fn read_single(&mut self, address: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), SdError> {
+        self.select()?;
+        self.expect_r1(CMD17, address)?;
+        self.read_data(block)
+    }
+
+    fn read_multi<B: BlockBuffer>(
+        &mut self,
+        address: u32,
+        blocks: &mut [B],
+    ) -> Result<(), SdError> {
+        self.select()?;
+        self.expect_r1(CMD18, address)?;
+        let mut result = Ok(());
+        for block in blocks.iter_mut() {
+            result = self.read_data(block.bytes_mut());
+            if result.is_err() {
+                break;
+            }
+        }
+        // CMD12 の直後の1バイトは読み捨てる
+        let stop = self
+            .command(CMD12, 0)
+            .and_then(|_| self.wait_ready(READ_TIMEOUT_MS));
+        result.and(stop)
+    }
+
+    fn write_single(&mut self, address: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), SdError> {
+        self.select()?;
+        self.expect_r1(CMD24, address)?;
+        self.write_data(TOKEN_START, block)?;
+        self.wait_ready(WRITE_TIMEOUT_MS)?;
+        self.check_status()
+    }
+
+    fn write_multi<B: BlockBuffer>(&mut self, address: u32, blocks: &[B]) -> Result<(), SdError> {
+        self.select()?;
+        // 書き込むブロック数を先に伝えておくと、カードがまとめて消去できる
+        self.command(CMD55, 0)?;
+        self.expect_r1(ACMD23, blocks.len() as u32)?;
+        self.expect_r1(CMD25, address)?;
+        let mut result = Ok(());
+        for block in blocks.iter() {
+            result = self.write_data(TOKEN_START_MULTI, block.bytes());
+            if result.is_err() {
+                break;
+            }
+        }
+        // エラーでも終わりのトークンを送ってカードを転送状態から戻す
+        let stop = self
+            .wait_ready(WRITE_TIMEOUT_MS)
+            .and_then(|_| self.transfer(TOKEN_STOP_MULTI))
+            .and_then(|_| self.transfer(0xff))
+            .and_then(|_| self.wait_ready(WRITE_TIMEOUT_MS));
+        result.and(stop)?;
+        self.check_status()
+    }
+
+    // データトークンを待ってデータと CRC を読む
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<(), SdError> {
+        let mut token = 0xff;
+        for _ in 0..READ_TIMEOUT_MS * 1000 / POLL_INTERVAL_US {
+            token = self.transfer(0xff)?;
+            if token != 0xff {
+                break;
+            }
+            self.delay.delay_us(POLL_INTERVAL_US);
+        }
+        match token {
+            TOKEN_START => (),
+            0xff => return Err(SdError::Timeout),
+            _ => return Err(SdError::ReadError(token)),
+        }
+        self.read_bytes(buf)?;
+        let mut crc = [0xff; 2];
+        self.read_bytes(&mut crc)?;
+        if self.crc && u16::from_be_bytes(crc) != crc16(buf) {
+            return Err(SdError::Crc);
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, token: u8, data: &[u8; BLOCK_SIZE]) -> Result<(), SdError> {
+        self.wait_ready(WRITE_TIMEOUT_MS)?;
+        let crc = if self.crc { crc16(data) } else { 0xffff };
+        self.transfer(token)?;
+        self.spi.write(data).map_err(|_| SdError::Transport)?;
+        self.spi
+            .write(&crc.to_be_bytes())
+            .map_err(|_| SdError::Transport)?;
+        let response = self.transfer(0xff)? & DATA_RESPONSE_MASK;
+        if response != DATA_ACCEPTED {
+            return Err(SdError::WriteRejected(response));
+        }
+        Ok(())
+    }
+
+    // 書き込みのエラーは CMD13 の R2 応答の2バイト目に出る
+    fn check_status(&mut self) -> Result<(), SdError> {
+        let r1 = self.command(CMD13, 0)?;
+        let r2 = self.transfer(0xff)?;
+        if r1 != 0 || r2 != 0 {
+            return Err(SdError::Command(CMD13, r1 | r2));
+        }
+        Ok(())
+    }
+
+    // R1 が 0 (エラー無し、アイドルでない) でなければエラー
+    fn expect_r1(&mut self, cmd: u8, arg: u32) -> Result<(), SdError> {
+        match self.command(cmd, arg)? {
+            0 => Ok(()),
+            r1 => Err(SdError::Command(cmd, r1)),
+        }
+    }
+
+    // コマンドを送って R1 応答を返す
+    fn command(&mut self, cmd: u8, arg: u32) -> Result<u8, SdError> {
+        if cmd != CMD0 && cmd != CMD12 {
+            self.wait_ready(READ_TIMEOUT_MS)?;
+        }
+        let mut frame = [0x40 | cmd, 0, 0, 0, 0, 0];
+        frame[1..5].copy_from_slice(&arg.to_be_bytes());
+        frame[5] = crc7(&frame[..5]);
+        self.spi.write(&frame).map_err(|_| SdError::Transport)?;
+        if cmd == CMD12 {
+            self.transfer(0xff)?;
+        }
+        // 応答は最上位ビットが 0 のバイト。8バイト以内に来る
+        for _ in 0..8 {
+            let r1 = self.transfer(0xff)?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    // カードが busy (0x00 を返す) でなくなるのを待つ
+    fn wait_ready(&mut self, timeout_ms: u32) -> Result<(), SdError> {
+        for _ in 0..timeout_ms * 1000 / POLL_INTERVAL_US {
+            if self.transfer(0xff)? == 0xff {
+                return Ok(());
+            }
+            self.delay.delay_us(POLL_INTERVAL_US);
+        }
+        Err(SdError::Timeout)
+    }
+
+    // 読むときは 0xff を送り続ける
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), SdError> {
+        buf.fill(0xff);
+        self.spi
+            .transfer_in_place(buf)
+            .map_err(|_| SdError::Transport)
+    }
+
+    fn transfer(&mut self, byte: u8) -> Result<u8, SdError> {
+        let mut buf = [byte];
+        self.spi
+            .transfer_in_place(&mut buf)
+            .map_err(|_| SdError::Transport)?;
+        Ok(buf[0])
+    }
+
+    fn select(&mut self) -> Result<(), SdError> {
+        self.cs.set_low().map_err(|_| SdError::Transport)
+    }
+
+    // CS を High にした後も1バイト送らないと、カードが DO を離さない
+    fn deselect(&mut self) {
+        let _ = self.cs.set_high();
+        let _ = self.transfer(0xff);
+        let _ = self.spi.flush();
+    }
+}
+
+impl<SPI, CS, DELAY> BlockDevice for SdSpi<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    type Error = SdError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        self.read_blocks(start_block_idx.0, blocks)
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.write_blocks(start_block_idx.0, blocks)
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        match self.card_info() {
+            Some(info) => Ok(BlockCount(info.num_blocks)),
+            None => Err(SdError::NotInitialized),
+        }
+    }
+}
@@ -0,0 +1,718 @@
+// 起動時に SDカードの CONFIG.INI から読む設定
+// 1行に1つ "key = value" を書く。'#' と ';' から行末まではコメント
+// [section] の行は読み飛ばすので、キーはファイル全体で一意にしてある
+//
+//   log_interval = 60        # 秒
+//   log_format = csv         # txt / csv / tsv / bin
+//   utc_offset = +09:00
+//
+// 書かれていないキーと、値が正しくないキーは既定値のままにする
+
+use core::fmt;
+use core::fmt::Write;
+
+use crate::bme280;
+use crate::display_power::PowerConfig;
+use crate::logger::LineBuffer;
+use crate::logger::LogColumns;
+use crate::logger::LogFormat;
+use crate::preferences::DateOrder;
+use crate::preferences::Preferences;
+use crate::preferences::PressureUnit;
+use crate::preferences::TemperatureUnit;
+use crate::sc2004::Geometry;
+use crate::schedule::AlarmLimits;
+use crate::schedule::MAX_INTERVAL;
+use crate::schedule::MIN_INTERVAL;
+use crate::tz::DstRule;
+use crate::tz::TimeZone;
+use crate::tz::UtcOffset;
+use crate::tz::JST;
+use crate::ui::StartScreen;
+
+pub const FILE_NAME: &str = "CONFIG.INI";
+
+// 設定ファイルの最大の大きさ
+pub const MAX_FILE_SIZE: usize = 2048;
+
+const MIN_BAUD: u32 = 300;
+const MAX_BAUD: u32 = 921_600;
+const MIN_LOG_FILE_SIZE: u32 = 4 * 1024;
+const MAX_IDLE_TIMEOUT: u32 = 24 * 60 * 60;
+
+// オーバーサンプリングの回数とレジスタの値（添字）
+const OVERSAMPLING: [u8; 6] = [0, 1, 2, 4, 8, 16];
+// IIR フィルターの係数とレジスタの値（添字）
+const FILTER: [u8; 5] = [0, 2, 4, 8, 16];
+// スタンバイ時間 (msec) とレジスタの値（添字）
+const STANDBY: [&str; 8] = ["0.5", "62.5", "125", "250", "500", "1000", "10", "20"];
+
+// 書き出すときのセクションとキーの並び。update() で書いたキーを覚えるので 64 個まで
+const SECTIONS: [(&str, &[&str]); 6] = [
+    (
+        "log",
+        &[
+            "log_interval",
+            "alarm_log_interval",
+            "log_format",
+            "log_local_time",
+            "log_dew_point",
+            "log_absolute_humidity",
+            "flush_interval",
+            "max_file_size",
+            "min_free_size",
+        ],
+    ),
+    (
+        "alarm",
+        &[
+            "alarm_temperature_min",
+            "alarm_temperature_max",
+            "alarm_humidity_max",
+        ],
+    ),
+    ("time", &["utc_offset", "dst"]),
+    (
+        "display",
+        &[
+            "temperature_unit",
+            "pressure_unit",
+            "decimals",
+            "clock",
+            "date_order",
+        ],
+    ),
+    (
+        "bme280",
+        &[
+            "oversampling_temperature",
+            "oversampling_pressure",
+            "oversampling_humidity",
+            "filter",
+            "standby_ms",
+        ],
+    ),
+    (
+        "system",
+        &[
+            "uart_baud",
+            "start_screen",
+            "lcd_size",
+            "lcd_idle_timeout",
+            "lcd_brightness",
+            "lcd_night_brightness",
+            "lcd_night",
+        ],
+    ),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SettingError {
+    Syntax,       // "key = value" の形になっていない
+    UnknownKey,   // 知らないキー
+    InvalidValue, // 値が読めないか、範囲を外れている
+}
+
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub log_interval: u32,       // 記録の間隔（秒）。地方時の区切りに揃えて記録する
+    pub alarm_log_interval: u32, // 温湿度が alarm_limits を外れている間の記録の間隔（秒）
+    pub alarm_limits: AlarmLimits,
+    pub log_format: LogFormat,
+    pub log_columns: LogColumns,
+    pub flush_interval: u32, // ブロックが埋まらなくても、この間隔（秒）で書き出す
+    pub max_file_size: u32,  // 1日のログがこの大きさを超えたら分ける
+    pub min_free_bytes: u64, // SDカードの空きがこれを下回ったら古い日のログから消す
+    pub time_zone: TimeZone, // LCD とログの地方時
+    pub uart_baud: u32,
+    pub bme280: bme280::Config,
+    pub start_screen: StartScreen,
+    pub lcd_geometry: Geometry,
+    pub display_power: PowerConfig, // LCD を消すまでの時間と明るさ
+    pub preferences: Preferences,   // LCD に表示する単位と書式
+}
+
+impl Settings {
+    pub const DEFAULT: Settings = Settings {
+        log_interval: 60,
+        alarm_log_interval: 10,
+        alarm_limits: AlarmLimits {
+            temperature_min: 0.0,
+            temperature_max: 35.0,
+            humidity_max: 85.0,
+        },
+        log_format: LogFormat::Csv,
+        log_columns: LogColumns {
+            local_time: true,
+            dew_point: true,
+            absolute_humidity: false,
+        },
+        flush_interval: 10 * 60,
+        max_file_size: 1024 * 1024,
+        min_free_bytes: 64 * 1024 * 1024,
+        time_zone: JST,
+        uart_baud: 9600,
+        bme280: bme280::Config::DEFAULT,
+        start_screen: StartScreen::Menu,
+        lcd_geometry: Geometry::LCD2004,
+        display_power: PowerConfig::DEFAULT,
+        preferences: Preferences::DEFAULT,
+    };
+
+    // 設定ファイルの内容を読み込む。エラーのあった行は行番号 (1〜) と共に on_error に渡す
+    // 行をまたぐ矛盾は行番号 0 で渡す
+    pub fn load<F: FnMut(usize, SettingError)>(&mut self, text: &str, mut on_error: F) {
+        for (i, line) in text.lines().enumerate() {
+            let line = match line.find(['#', ';']) {
+                Some(n) => &line[..n],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() || (line.starts_with('[') && line.ends_with(']')) {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()),
+                None => Err(SettingError::Syntax),
+            };
+            if let Err(e) = result {
+                on_error(i + 1, e);
+            }
+        }
+        // 下限が上限を超えていたら警報の範囲は既定値に戻す
+        if self.alarm_limits.temperature_min > self.alarm_limits.temperature_max {
+            self.alarm_limits = Settings::DEFAULT.alarm_limits;
+            on_error(0, SettingError::InvalidValue);
+        }
+    }
+
+    // 1つの設定を変える。エラーなら元の値のまま
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingError> {
+        match key {
+            "log_interval" => self.log_interval = parse_interval(value)?,
+            "alarm_log_interval" => self.alarm_log_interval = parse_interval(value)?,
+            "alarm_temperature_min" => {
+                self.alarm_limits.temperature_min = parse_float(value, -40.0, 85.0)?
+            }
+            "alarm_temperature_max" => {
+                self.alarm_limits.temperature_max = parse_float(value, -40.0, 85.0)?
+            }
+            "alarm_humidity_max" => {
+                self.alarm_limits.humidity_max = parse_float(value, 0.0, 100.0)?
+            }
+            "log_format" => self.log_format = parse_format(value)?,
+            "log_local_time" => self.log_columns.local_time = parse_bool(value)?,
+            "log_dew_point" => self.log_columns.dew_point = parse_bool(value)?,
+            "log_absolute_humidity" => self.log_columns.absolute_humidity = parse_bool(value)?,
+            "flush_interval" => self.flush_interval = parse_interval(value)?,
+            "max_file_size" => {
+                self.max_file_size = match parse_size(value)? {
+                    n if n >= MIN_LOG_FILE_SIZE as u64 && n <= u32::MAX as u64 => n as u32,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "min_free_size" => self.min_free_bytes = parse_size(value)?,
+            "utc_offset" => self.time_zone.offset_minutes = parse_offset(value)?,
+            "dst" => self.time_zone.dst = parse_dst(value)?,
+            "uart_baud" => {
+                self.uart_baud = match value.parse() {
+                    Ok(n) if (MIN_BAUD..=MAX_BAUD).contains(&n) => n,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "oversampling_temperature" => self.bme280.osrs_t = parse_index(value, &OVERSAMPLING)?,
+            "oversampling_pressure" => self.bme280.osrs_p = parse_index(value, &OVERSAMPLING)?,
+            "oversampling_humidity" => self.bme280.osrs_h = parse_index(value, &OVERSAMPLING)?,
+            "filter" => self.bme280.filter = parse_index(value, &FILTER)?,
+            "standby_ms" => {
+                self.bme280.standby = match STANDBY.iter().position(|&s| s == value) {
+                    Some(n) => n as u8,
+                    None => return Err(SettingError::InvalidValue),
+                }
+            }
+            "start_screen" => {
+                self.start_screen = match value {
+                    "menu" => StartScreen::Menu,
+                    "elements" => StartScreen::Elements,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "lcd_size" => {
+                self.lcd_geometry = Geometry::from_name(value).ok_or(SettingError::InvalidValue)?
+            }
+            "lcd_idle_timeout" => {
+                self.display_power.idle_timeout = match value.parse() {
+                    Ok(n) if n <= MAX_IDLE_TIMEOUT => n,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "lcd_brightness" => self.display_power.brightness = parse_percent(value)?,
+            "lcd_night_brightness" => self.display_power.night_brightness = parse_percent(value)?,
+            "lcd_night" => {
+                (self.display_power.night_start, self.display_power.night_end) = parse_night(value)?
+            }
+            "temperature_unit" => {
+                self.preferences.temperature_unit = match value {
+                    "c" | "C" => TemperatureUnit::Celsius,
+                    "f" | "F" => TemperatureUnit::Fahrenheit,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "pressure_unit" => {
+                self.preferences.pressure_unit = match value {
+                    "hpa" | "hPa" => PressureUnit::Hpa,
+                    "inhg" | "inHg" => PressureUnit::InHg,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "decimals" => {
+                self.preferences.decimals = match value.parse() {
+                    Ok(n) if n <= 2 => n,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "clock" => {
+                self.preferences.clock_24h = match value {
+                    "24h" => true,
+                    "12h" => false,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            "date_order" => {
+                self.preferences.date_order = match value {
+                    "ymd" => DateOrder::Ymd,
+                    "dmy" => DateOrder::Dmy,
+                    "mdy" => DateOrder::Mdy,
+                    _ => return Err(SettingError::InvalidValue),
+                }
+            }
+            _ => return Err(SettingError::UnknownKey),
+        }
+        Ok(())
+    }
+
+    // 設定ファイルの形で書き出す。CONFIG.INI が無いときに作るのに使う
+    pub fn write<W: Write>(&self, w: &mut W) -> fmt::Result {
+        for (i, (section, keys)) in SECTIONS.iter().enumerate() {
+            if i > 0 {
+                w.write_str("\r\n")?;
+            }
+            write!(w, "[{}]\r\n", section)?;
+            for key in keys.iter() {
+                write!(w, "{} = ", key)?;
+                self.write_value(key, w)?;
+                w.write_str("\r\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    // old（いまの設定ファイル）の値だけを今の設定に書き換えて書き出す。LCD で変えた設定の保存に使う
+    // コメントや空行、知らないキーの行はそのまま残し、old に無いキーは最後に足す
+    pub fn update<W: Write>(&self, old: &str, w: &mut W) -> fmt::Result {
+        let mut written: u64 = 0;
+        for line in old.lines() {
+            let (body, comment) = match line.find(['#', ';']) {
+                Some(n) => line.split_at(n),
+                None => (line, ""),
+            };
+            let index = body
+                .split_once('=')
+                .and_then(|(key, _)| keys().position(|k| k == key.trim()));
+            let key = match index {
+                Some(i) => {
+                    written |= 1 << i;
+                    keys().nth(i).unwrap_or("")
+                }
+                None => {
+                    write!(w, "{}\r\n", line)?;
+                    continue;
+                }
+            };
+            let mut entry: LineBuffer<64> = LineBuffer::new();
+            write!(entry, "{} = ", key)?;
+            self.write_value(key, &mut entry)?;
+            w.write_str(entry.as_str())?;
+            if !comment.is_empty() {
+                // コメントはなるべく元の桁に揃える
+                let pad = body.len().saturating_sub(entry.len()).max(1);
+                write!(w, "{:pad$}{}", "", comment, pad = pad)?;
+            }
+            w.write_str("\r\n")?;
+        }
+        for (i, key) in keys().enumerate() {
+            if written & (1 << i) == 0 {
+                write!(w, "{} = ", key)?;
+                self.write_value(key, w)?;
+                w.write_str("\r\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    // key の値を設定ファイルの書き方で書く
+    fn write_value<W: Write>(&self, key: &str, w: &mut W) -> fmt::Result {
+        let p = &self.preferences;
+        let c = &self.bme280;
+        let d = &self.display_power;
+        match key {
+            "log_interval" => write!(w, "{}", self.log_interval),
+            "alarm_log_interval" => write!(w, "{}", self.alarm_log_interval),
+            "log_format" => w.write_str(self.log_format.extension()),
+            "log_local_time" => write!(w, "{}", self.log_columns.local_time),
+            "log_dew_point" => write!(w, "{}", self.log_columns.dew_point),
+            "log_absolute_humidity" => write!(w, "{}", self.log_columns.absolute_humidity),
+            "flush_interval" => write!(w, "{}", self.flush_interval),
+            "max_file_size" => write!(w, "{}", Size(self.max_file_size as u64)),
+            "min_free_size" => write!(w, "{}", Size(self.min_free_bytes)),
+            "alarm_temperature_min" => write!(w, "{}", self.alarm_limits.temperature_min),
+            "alarm_temperature_max" => write!(w, "{}", self.alarm_limits.temperature_max),
+            "alarm_humidity_max" => write!(w, "{}", self.alarm_limits.humidity_max),
+            "utc_offset" => write!(w, "{}", UtcOffset(self.time_zone.offset_minutes)),
+            "dst" => w.write_str(match self.time_zone.dst {
+                DstRule::None => "none",
+                DstRule::Eu => "eu",
+                DstRule::Us => "us",
+            }),
+            "temperature_unit" => w.write_str(match p.temperature_unit {
+                TemperatureUnit::Celsius => "c",
+                TemperatureUnit::Fahrenheit => "f",
+            }),
+            "pressure_unit" => w.write_str(match p.pressure_unit {
+                PressureUnit::Hpa => "hpa",
+                PressureUnit::InHg => "inhg",
+            }),
+            "decimals" => write!(w, "{}", p.decimals),
+            "clock" => w.write_str(if p.clock_24h { "24h" } else { "12h" }),
+            "date_order" => w.write_str(match p.date_order {
+                DateOrder::Ymd => "ymd",
+                DateOrder::Dmy => "dmy",
+                DateOrder::Mdy => "mdy",
+            }),
+            "oversampling_temperature" => write!(w, "{}", oversampling(c.osrs_t)),
+            "oversampling_pressure" => write!(w, "{}", oversampling(c.osrs_p)),
+            "oversampling_humidity" => write!(w, "{}", oversampling(c.osrs_h)),
+            "filter" => write!(w, "{}", FILTER[c.filter as usize % FILTER.len()]),
+            "standby_ms" => w.write_str(STANDBY[c.standby as usize % STANDBY.len()]),
+            "uart_baud" => write!(w, "{}", self.uart_baud),
+            "start_screen" => w.write_str(match self.start_screen {
+                StartScreen::Menu => "menu",
+                StartScreen::Elements => "elements",
+            }),
+            "lcd_size" => w.write_str(self.lcd_geometry.name()),
+            "lcd_idle_timeout" => write!(w, "{}", d.idle_timeout),
+            "lcd_brightness" => write!(w, "{}", d.brightness),
+            "lcd_night_brightness" => write!(w, "{}", d.night_brightness),
+            "lcd_night" if d.night_start == d.night_end => w.write_str("none"),
+            "lcd_night" => write!(
+                w,
+                "{:02}:{:02}-{:02}:{:02}",
+                d.night_start / 60,
+                d.night_start % 60,
+                d.night_end / 60,
+                d.night_end % 60
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn parse_interval(value: &str) -> Result<u32, SettingError> {
+    match value.parse() {
+        Ok(n) if (MIN_INTERVAL..=MAX_INTERVAL).contains(&n) => Ok(n),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+fn parse_float(value: &str, min: f64, max: f64) -> Result<f64, SettingError> {
+    match value.parse::<f64>() {
+        Ok(v) if (min..=max).contains(&v) => Ok(v),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, SettingError> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+fn parse_format(value: &str) -> Result<LogFormat, SettingError> {
+    match value {
+        "txt" | "text" => Ok(LogFormat::Text),
+        "csv" => Ok(LogFormat::Csv),
+        "tsv" => Ok(LogFormat::Tsv),
+        "bin" | "binary" => Ok(LogFormat::Binary),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+// "1048576", "512K", "64M", "2G"
+fn parse_size(value: &str) -> Result<u64, SettingError> {
+    let (digits, unit) = match value.as_bytes().last() {
+        Some(b'K' | b'k') => (&value[..value.len() - 1], 1024),
+        Some(b'M' | b'm') => (&value[..value.len() - 1], 1024 * 1024),
+        Some(b'G' | b'g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or(SettingError::InvalidValue)
+}
+
+// "+09:00", "-05:00", "+05:30"。バイナリログに 15分単位で記録するので、分は 15 の倍数に限る
+fn parse_offset(value: &str) -> Result<i16, SettingError> {
+    let (sign, rest) = match value.as_bytes().first() {
+        Some(b'+') => (1, &value[1..]),
+        Some(b'-') => (-1, &value[1..]),
+        _ => return Err(SettingError::InvalidValue),
+    };
+    let (hours, minutes) = rest.split_once(':').ok_or(SettingError::InvalidValue)?;
+    match (hours.parse::<i16>(), minutes.parse::<i16>()) {
+        (Ok(h), Ok(m)) if (0..=14).contains(&h) && (0..60).contains(&m) && m % 15 == 0 => {
+            Ok(sign * (h * 60 + m))
+        }
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+fn parse_percent(value: &str) -> Result<u8, SettingError> {
+    match value.parse() {
+        Ok(n) if n <= 100 => Ok(n),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+// "22:00-06:00" を0時からの分にする。"none" は暗くしない
+fn parse_night(value: &str) -> Result<(u16, u16), SettingError> {
+    if value == "none" {
+        return Ok((0, 0));
+    }
+    let (start, end) = value.split_once('-').ok_or(SettingError::InvalidValue)?;
+    Ok((parse_hh_mm(start.trim())?, parse_hh_mm(end.trim())?))
+}
+
+fn parse_hh_mm(value: &str) -> Result<u16, SettingError> {
+    let (hours, minutes) = value.split_once(':').ok_or(SettingError::InvalidValue)?;
+    match (hours.parse::<u16>(), minutes.parse::<u16>()) {
+        (Ok(h), Ok(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+fn parse_dst(value: &str) -> Result<DstRule, SettingError> {
+    match value {
+        "none" => Ok(DstRule::None),
+        "eu" => Ok(DstRule::Eu),
+        "us" => Ok(DstRule::Us),
+        _ => Err(SettingError::InvalidValue),
+    }
+}
+
+fn keys() -> impl Iterator<Item = &'static str> {
+    SECTIONS.iter().flat_map(|(_, keys)| keys.iter().copied())
+}
+
+fn oversampling(value: u8) -> u8 {
+    OVERSAMPLING[value as usize % OVERSAMPLING.len()]
+}
+
+// 表の中の値を探し、その位置をレジスタの値として返す
+fn parse_index(value: &str, table: &[u8]) -> Result<u8, SettingError> {
+    let v: u8 = value.parse().map_err(|_| SettingError::InvalidValue)?;
+    match table.iter().position(|&t| t == v) {
+        Some(n) => Ok(n as u8),
+        None => Err(SettingError::InvalidValue),
+    }
+}
+
+// 割り切れれば K / M / G を付けて書く
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [(u64, char); 3] =
+            [(1024 * 1024 * 1024, 'G'), (1024 * 1024, 'M'), (1024, 'K')];
+        for (unit, suffix) in UNITS {
+            if self.0 >= unit && self.0.is_multiple_of(unit) {
+                return write!(f, "{}{}", self.0 / unit, suffix);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for SettingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            SettingError::Syntax => "syntax error",
+            SettingError::UnknownKey => "unknown key",
+            SettingError::InvalidValue => "invalid value",
+        };
+        f.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(settings: &Settings) -> LineBuffer<MAX_FILE_SIZE> {
+        let mut buf = LineBuffer::new();
+        settings.write(&mut buf).unwrap();
+        buf
+    }
+
+    fn changed() -> Settings {
+        let mut s = Settings::DEFAULT;
+        for (key, value) in [
+            ("log_interval", "300"),
+            ("log_format", "bin"),
+            ("log_dew_point", "no"),
+            ("max_file_size", "512K"),
+            ("min_free_size", "3000"),
+            ("alarm_temperature_min", "-5.5"),
+            ("utc_offset", "-05:30"),
+            ("dst", "us"),
+            ("temperature_unit", "f"),
+            ("date_order", "dmy"),
+            ("oversampling_humidity", "16"),
+            ("standby_ms", "62.5"),
+            ("start_screen", "elements"),
+            ("lcd_size", "16x2"),
+            ("lcd_night", "22:30-06:15"),
+        ] {
+            assert!(s.set(key, value).is_ok(), "{}", key);
+        }
+        s
+    }
+
+    #[test]
+    fn write_then_load_round_trips() {
+        let saved = changed();
+        let written = text(&saved);
+        let mut loaded = Settings::DEFAULT;
+        loaded.load(written.as_str(), |line, e| panic!("line {}: {}", line, e));
+        assert_eq!(text(&loaded).as_str(), written.as_str());
+        assert_ne!(written.as_str(), text(&Settings::DEFAULT).as_str());
+    }
+
+    #[test]
+    fn errors_are_reported_with_line_numbers() {
+        let mut errors = [(0, SettingError::Syntax); 4];
+        let mut n = 0;
+        let mut s = Settings::DEFAULT;
+        s.load(
+            "[log]\n\
+             log_interval = 0\n\
+             # comment\n\
+             colour = red\n\
+             log_format = csv ; comment\n\
+             nonsense\n\
+             alarm_temperature_min = 40\n\
+             alarm_temperature_max = 30\n",
+            |line, e| {
+                errors[n] = (line, e);
+                n += 1;
+            },
+        );
+        assert!(
+            errors[..n]
+                == [
+                    (2, SettingError::InvalidValue),
+                    (4, SettingError::UnknownKey),
+                    (6, SettingError::Syntax),
+                    (0, SettingError::InvalidValue),
+                ]
+        );
+        // 正しくない値は既定値のまま
+        assert_eq!(s.log_interval, Settings::DEFAULT.log_interval);
+        assert_eq!(s.alarm_limits.temperature_min, 0.0);
+        assert_eq!(s.alarm_limits.temperature_max, 35.0);
+    }
+
+    #[test]
+    fn sizes() {
+        assert!(parse_size("1048576") == Ok(1048576));
+        assert!(parse_size("512K") == Ok(512 * 1024));
+        assert!(parse_size("64m") == Ok(64 * 1024 * 1024));
+        assert!(parse_size("2G") == Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("M") == Err(SettingError::InvalidValue));
+        assert!(parse_size("1T") == Err(SettingError::InvalidValue));
+        assert!(parse_size("99999999999999999999") == Err(SettingError::InvalidValue));
+        assert!(parse_size("17179869184G") == Err(SettingError::InvalidValue));
+        let mut s = Settings::DEFAULT;
+        assert!(s.set("max_file_size", "1K") == Err(SettingError::InvalidValue));
+    }
+
+    #[test]
+    fn offsets() {
+        assert!(parse_offset("+09:00") == Ok(540));
+        assert!(parse_offset("-05:30") == Ok(-330));
+        assert!(parse_offset("+14:00") == Ok(840));
+        assert!(parse_offset("09:00") == Err(SettingError::InvalidValue));
+        assert!(parse_offset("+15:00") == Err(SettingError::InvalidValue));
+        assert!(parse_offset("+09:60") == Err(SettingError::InvalidValue));
+        assert!(parse_offset("+05:45") == Ok(345));
+        assert!(parse_offset("+05:20") == Err(SettingError::InvalidValue));
+        assert!(parse_offset("+09") == Err(SettingError::InvalidValue));
+    }
+
+    #[test]
+    fn night() {
+        assert!(parse_night("none") == Ok((0, 0)));
+        assert!(parse_night("22:00-06:00") == Ok((22 * 60, 6 * 60)));
+        assert!(parse_night("22:00 - 06:30") == Ok((22 * 60, 6 * 60 + 30)));
+        assert!(parse_night("24:00-06:00") == Err(SettingError::InvalidValue));
+        assert!(parse_night("22:00") == Err(SettingError::InvalidValue));
+        assert!(parse_night("22-06") == Err(SettingError::InvalidValue));
+    }
+
+    #[test]
+    fn update_keeps_comments_and_unknown_lines() {
+        let old = "# 居間の温湿度計\r\n\
+                   [log]\r\n\
+                   log_interval = 60          # 記録の間隔（秒）\r\n\
+                   my_note = keep me\r\n\
+                   \r\n\
+                   [time]\r\n\
+                   utc_offset = +09:00\r\n";
+        let mut s = Settings::DEFAULT;
+        s.load(old, |_, _| ());
+        assert!(s.set("log_interval", "600").is_ok());
+
+        let mut out: LineBuffer<MAX_FILE_SIZE> = LineBuffer::new();
+        s.update(old, &mut out).unwrap();
+        let out = out.as_str();
+        assert!(out.starts_with(
+            "# 居間の温湿度計\r\n\
+             [log]\r\n\
+             log_interval = 600         # 記録の間隔（秒）\r\n\
+             my_note = keep me\r\n\
+             \r\n\
+             [time]\r\n\
+             utc_offset = +09:00\r\n"
+        ));
+        // ファイルに無かったキーは最後に足す
+        assert!(out.contains("\r\nlog_format = csv\r\n"));
+        assert_eq!(out.matches("\nlog_interval = ").count(), 1);
+
+        let mut reloaded = Settings::DEFAULT;
+        reloaded.load(out, |line, e| {
+            assert_eq!(line, 4);
+            assert!(e == SettingError::UnknownKey);
+        });
+        assert_eq!(text(&reloaded).as_str(), text(&s).as_str());
+    }
+}
@@ -0,0 +1,213 @@
+// タイムゾーンと夏時間
+// RTC は UTC で計時し、表示するときだけ地方時に直す
+
+use core::fmt;
+
+use crate::rtc8564::days_in_month;
+use crate::rtc8564::weekday;
+use crate::rtc8564::Time;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DstRule {
+    None,
+    Eu, // 3月最終日曜 01:00 UTC から 10月最終日曜 01:00 UTC まで
+    Us, // 3月第2日曜 02:00 から 11月第1日曜 02:00 (地方時) まで
+}
+
+#[derive(Clone, Copy)]
+pub struct TimeZone {
+    pub offset_minutes: i16, // 標準時の UTC からのずれ（分）
+    pub dst: DstRule,
+}
+
+pub const UTC: TimeZone = TimeZone::new(0, DstRule::None);
+pub const JST: TimeZone = TimeZone::new(9 * 60, DstRule::None);
+pub const CET: TimeZone = TimeZone::new(60, DstRule::Eu);
+pub const EST: TimeZone = TimeZone::new(-5 * 60, DstRule::Us);
+
+const DST_SHIFT_MINUTES: i16 = 60;
+
+impl TimeZone {
+    pub const fn new(offset_minutes: i16, dst: DstRule) -> Self {
+        Self {
+            offset_minutes,
+            dst,
+        }
+    }
+
+    // 夏時間を含めた UTC からのずれ（分）
+    pub fn offset_at(&self, utc: &Time) -> i16 {
+        if self.is_dst(utc.to_unix()) {
+            self.offset_minutes + DST_SHIFT_MINUTES
+        } else {
+            self.offset_minutes
+        }
+    }
+
+    pub fn to_local(&self, utc: &Time) -> Time {
+        shift(utc.to_unix(), self.offset_at(utc))
+    }
+
+    // 夏時間から標準時に戻るときの重複する1時間は標準時側として扱う
+    // 夏時間が始まるときの存在しない1時間は標準時として読み、夏時間では1時間後の時刻になる
+    pub fn to_utc(&self, local: &Time) -> Time {
+        let standard = shift(local.to_unix(), -self.offset_minutes);
+        if self.is_dst(standard.to_unix()) {
+            let dst = shift(local.to_unix(), -(self.offset_minutes + DST_SHIFT_MINUTES));
+            if self.is_dst(dst.to_unix()) {
+                return dst;
+            }
+        }
+        standard
+    }
+
+    fn is_dst(&self, utc: u32) -> bool {
+        let year = Time::from_unix(utc).year;
+        let offset = self.offset_minutes as i64 * 60;
+        let (start, end) = match self.dst {
+            DstRule::None => return false,
+            DstRule::Eu => (
+                unix_at(year, 3, last_sunday(year, 3), 1),
+                unix_at(year, 10, last_sunday(year, 10), 1),
+            ),
+            DstRule::Us => (
+                // 標準時 02:00 に始まり、夏時間 02:00 (= 標準時 01:00) に終わる
+                unix_at(year, 3, nth_sunday(year, 3, 2), 2) - offset,
+                unix_at(year, 11, nth_sunday(year, 11, 1), 1) - offset,
+            ),
+        };
+        start <= utc as i64 && (utc as i64) < end
+    }
+}
+
+// "+09:00" のように表示する
+#[derive(Clone, Copy)]
+pub struct UtcOffset(pub i16);
+
+impl fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        let minutes = self.0.unsigned_abs();
+        write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+fn shift(unix: u32, minutes: i16) -> Time {
+    let shifted = unix as i64 + minutes as i64 * 60;
+    Time::from_unix(shifted.max(0) as u32)
+}
+
+fn unix_at(year: u16, month: u8, day: u8, hour: u8) -> i64 {
+    Time::new(year, month, day, hour, 0, 0).to_unix() as i64
+}
+
+fn nth_sunday(year: u16, month: u8, n: u8) -> u8 {
+    let first = weekday(year, month, 1);
+    1 + (7 - first) % 7 + (n - 1) * 7
+}
+
+fn last_sunday(year: u16, month: u8) -> u8 {
+    let last = days_in_month(year, month);
+    last - weekday(year, month, last)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn unix(year: u16, month: u8, day: u8, hour: u8, minute: u8) -> u32 {
+        Time::new(year, month, day, hour, minute, 0).to_unix()
+    }
+
+    fn hm(time: &Time) -> (u8, u8, u8) {
+        (time.day, time.hour, time.minute)
+    }
+
+    #[test]
+    fn sundays() {
+        assert_eq!(nth_sunday(2024, 3, 2), 10);
+        assert_eq!(nth_sunday(2024, 11, 1), 3);
+        assert_eq!(nth_sunday(2026, 3, 2), 8);
+        assert_eq!(nth_sunday(2026, 11, 1), 1);
+        assert_eq!(last_sunday(2024, 3), 31);
+        assert_eq!(last_sunday(2024, 10), 27);
+        assert_eq!(last_sunday(2023, 10), 29);
+        assert_eq!(last_sunday(2026, 2), 22);
+    }
+
+    #[test]
+    fn eu_transitions() {
+        // 2024-03-31 01:00Z と 2024-10-27 01:00Z
+        assert!(!CET.is_dst(unix(2024, 3, 31, 0, 59) + 59));
+        assert!(CET.is_dst(unix(2024, 3, 31, 1, 0)));
+        assert!(CET.is_dst(unix(2024, 10, 27, 0, 59) + 59));
+        assert!(!CET.is_dst(unix(2024, 10, 27, 1, 0)));
+        assert!(!CET.is_dst(unix(2024, 1, 15, 12, 0)));
+        assert!(CET.is_dst(unix(2024, 7, 15, 12, 0)));
+
+        let utc = Time::from_unix(unix(2024, 3, 31, 1, 0));
+        assert_eq!(CET.offset_at(&utc), 120);
+        assert_eq!(hm(&CET.to_local(&utc)), (31, 3, 0));
+    }
+
+    #[test]
+    fn us_transitions() {
+        // 2024-03-10 02:00 EST = 07:00Z、2024-11-03 02:00 EDT = 06:00Z
+        assert!(!EST.is_dst(unix(2024, 3, 10, 6, 59) + 59));
+        assert!(EST.is_dst(unix(2024, 3, 10, 7, 0)));
+        assert!(EST.is_dst(unix(2024, 11, 3, 5, 59) + 59));
+        assert!(!EST.is_dst(unix(2024, 11, 3, 6, 0)));
+
+        let utc = Time::from_unix(unix(2024, 3, 10, 7, 0));
+        assert_eq!(EST.offset_at(&utc), -240);
+        assert_eq!(hm(&EST.to_local(&utc)), (10, 3, 0));
+        assert!(!JST.is_dst(unix(2024, 7, 15, 12, 0)));
+    }
+
+    #[test]
+    fn ambiguous_hour_is_standard_time() {
+        // 01:30 が2回ある。後の EST の方
+        let local = Time::new(2024, 11, 3, 1, 30, 0);
+        assert_eq!(EST.to_utc(&local).to_unix(), unix(2024, 11, 3, 6, 30));
+        // 02:30 CET/CEST も2回ある
+        let local = Time::new(2024, 10, 27, 2, 30, 0);
+        assert_eq!(CET.to_utc(&local).to_unix(), unix(2024, 10, 27, 1, 30));
+    }
+
+    #[test]
+    fn skipped_hour_moves_forward() {
+        // 02:30 は無い。標準時として読むので 03:30 EDT になる
+        let local = Time::new(2024, 3, 10, 2, 30, 0);
+        let utc = EST.to_utc(&local);
+        assert_eq!(utc.to_unix(), unix(2024, 3, 10, 7, 30));
+        assert_eq!(hm(&EST.to_local(&utc)), (10, 3, 30));
+        let local = Time::new(2024, 3, 31, 2, 30, 0);
+        assert_eq!(CET.to_utc(&local).to_unix(), unix(2024, 3, 31, 1, 30));
+    }
+
+    #[test]
+    fn local_round_trip() {
+        for &utc in &[
+            unix(2024, 1, 1, 0, 0),
+            unix(2024, 3, 10, 8, 15),
+            unix(2024, 7, 4, 16, 0),
+            unix(2024, 12, 31, 23, 59),
+        ] {
+            for tz in [UTC, JST, CET, EST] {
+                let local = tz.to_local(&Time::from_unix(utc));
+                assert_eq!(tz.to_utc(&local).to_unix(), utc);
+            }
+        }
+    }
+
+    #[test]
+    fn utc_offset_display() {
+        assert_eq!(std::format!("{}", UtcOffset(9 * 60)), "+09:00");
+        assert_eq!(std::format!("{}", UtcOffset(0)), "+00:00");
+        assert_eq!(std::format!("{}", UtcOffset(-5 * 60)), "-05:00");
+        assert_eq!(std::format!("{}", UtcOffset(5 * 60 + 30)), "+05:30");
+        assert_eq!(std::format!("{}", UtcOffset(-30)), "-00:30");
+    }
+}
@@ -0,0 +1,123 @@
+// LCD の画面の枠組み
+// 画面は Screen を実装し、Navigator が積み重ね (stack) で行き来を管理する
+// 画面どうしは ScreenId（画面を並べた配列の添字）で指すので、画面を足しても他の画面は変えなくてよい
+
+pub mod date_time;
+pub mod editor;
+pub mod elements;
+pub mod fields;
+pub mod history;
+pub mod menu;
+pub mod min_max;
+
+use crate::history::History;
+use crate::history::CHANNELS;
+use crate::min_max::Extremes;
+use crate::rtc::Rtc;
+use crate::sc2004::Frame;
+use crate::settings::Settings;
+use crate::SW;
+
+pub type ScreenId = usize;
+
+// 戻れる深さ
+pub const STACK_DEPTH: usize = 8;
+
+// 温度・湿度・気圧の表示名。単位と桁数は Settings::preferences で決める
+pub const CHANNEL_NAMES: [&str; CHANNELS] = ["Temp", "Humi", "Pres"];
+
+// 起動したときの画面
+#[derive(Clone, Copy, PartialEq)]
+pub enum StartScreen {
+    Menu,
+    Elements,
+}
+
+// 画面から参照・変更できるもの
+pub struct Context<'a> {
+    pub rtc: &'a mut dyn Rtc,
+    pub settings: &'a mut Settings,
+    pub elements: (f64, f64, f64), // 温度・湿度・気圧
+    pub history: &'a History,
+    pub extremes: &'a mut Extremes,
+    pub sd_ok: bool,
+    pub alarm: bool, // 警報中か。AlarmLimits::exceeded() のヒステリシスを含めて判定したもの
+    pub settings_changed: bool, // 画面で設定を変えたら true にする。保存と反映は呼び出し側で行う
+    pub time_set: bool, // 画面で日時を合わせたら true にする。ドリフトの測定は呼び出し側でやり直す
+}
+
+pub enum Action {
+    None,
+    Push(ScreenId), // 次の画面へ
+    Back,           // 前の画面へ戻る
+}
+
+pub trait Screen {
+    // 画面に入るとき（次の画面から戻ってきたときも）に呼ぶ。frame は空白になっている
+    fn enter(&mut self, _ctx: &mut Context) {}
+
+    // 毎回呼ぶ。押されたスイッチ (key) を処理して frame に描く
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action;
+}
+
+pub struct Navigator {
+    stack: [ScreenId; STACK_DEPTH],
+    depth: usize,
+    entered: bool,
+}
+
+impl Navigator {
+    pub fn new(root: ScreenId) -> Self {
+        Self {
+            stack: [root; STACK_DEPTH],
+            depth: 1,
+            entered: false,
+        }
+    }
+
+    pub fn current(&self) -> ScreenId {
+        self.stack[self.depth - 1]
+    }
+
+    // いっぱいなら積まない
+    pub fn push(&mut self, id: ScreenId) {
+        if self.depth < STACK_DEPTH {
+            self.stack[self.depth] = id;
+            self.depth += 1;
+            self.entered = false;
+        }
+    }
+
+    // 最初の画面からは戻らない
+    pub fn back(&mut self) {
+        if self.depth > 1 {
+            self.depth -= 1;
+            self.entered = false;
+        }
+    }
+
+    // 今の画面を動かす。画面が変わったら、新しい画面もすぐに描く
+    pub fn update(
+        &mut self,
+        screens: &mut [&mut dyn Screen],
+        frame: &mut Frame,
+        key: SW,
+        ctx: &mut Context,
+    ) {
+        let mut key = key;
+        for _ in 0..2 {
+            let screen = &mut *screens[self.current()];
+            if !self.entered {
+                self.entered = true;
+                frame.clear();
+                screen.enter(ctx);
+            }
+            match screen.update(frame, key, ctx) {
+                Action::None => return,
+                Action::Push(id) => self.push(id),
+                Action::Back => self.back(),
+            }
+            key = SW::None;
+        }
+    }
+}
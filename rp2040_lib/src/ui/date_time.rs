@@ -0,0 +1,170 @@
+// 日時を設定する画面
+// Left / Right で桁を選び、Up / Down でその桁を 0〜9 で変える
+// Center で RTC に書き込んで戻る。最初の桁で Left を押すと取り消して戻る
+// 日付は preferences の順に並べる。時刻は24時間制で設定する
+
+use crate::preferences::DateOrder;
+use crate::rtc8564::Time;
+use crate::sc2004::Frame;
+use crate::ui::Action;
+use crate::ui::Context;
+use crate::ui::Screen;
+use crate::SW;
+
+// 時刻 (hh:mm:ss) の編集する桁の位置。広い表示器では日付の後ろ、狭い表示器では次の行に書く
+const TIME_COLS_WIDE: [u8; 6] = [11, 12, 14, 15, 17, 18];
+const TIME_COLS_NARROW: [u8; 6] = [0, 1, 3, 4, 6, 7];
+
+// 左から順に編集する桁の digits の添字と、日付の文字列の中での位置
+fn date_layout(order: DateOrder) -> ([usize; 12], [u8; 6]) {
+    match order {
+        DateOrder::Ymd => ([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11], [2, 3, 5, 6, 8, 9]),
+        DateOrder::Dmy => ([4, 5, 2, 3, 0, 1, 6, 7, 8, 9, 10, 11], [0, 1, 3, 4, 8, 9]),
+        DateOrder::Mdy => ([2, 3, 4, 5, 0, 1, 6, 7, 8, 9, 10, 11], [0, 1, 3, 4, 8, 9]),
+    }
+}
+
+pub struct DateTimeScreen {
+    century: u16,     // 世紀は編集しないのでそのまま残す
+    digits: [u8; 12], // 年 (下2桁)・月・日・時・分・秒
+    position: usize,
+}
+
+impl DateTimeScreen {
+    pub const fn new() -> Self {
+        Self {
+            century: 20,
+            digits: [0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0],
+            position: 0,
+        }
+    }
+
+    // 2桁ずつの値
+    fn value(&self, n: usize) -> u8 {
+        self.digits[n * 2] * 10 + self.digits[n * 2 + 1]
+    }
+
+    // 編集中の桁から日時を組み立てる
+    fn edited_time(&self) -> Time {
+        let mut month = self.value(1);
+        if !(1..=12).contains(&month) {
+            month = 1;
+        }
+        Time::new(
+            self.century * 100 + self.value(0) as u16,
+            month,
+            self.value(2),
+            self.value(3),
+            self.value(4),
+            self.value(5),
+        )
+    }
+
+    // 日時を書く行。4行あれば1行目は見出し、2行目は空ける
+    fn edit_row(frame: &Frame) -> u8 {
+        if frame.geometry().has_title() {
+            2
+        } else {
+            0
+        }
+    }
+
+    // 編集中の桁の表示位置 (桁, 行)
+    fn edit_position(&self, frame: &Frame, date_cols: &[u8; 6]) -> (u8, u8) {
+        let row = Self::edit_row(frame);
+        if self.position < 6 {
+            (date_cols[self.position], row)
+        } else if frame.geometry().is_wide() {
+            (TIME_COLS_WIDE[self.position - 6], row)
+        } else {
+            (TIME_COLS_NARROW[self.position - 6], row + 1)
+        }
+    }
+}
+
+impl Default for DateTimeScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen for DateTimeScreen {
+    fn enter(&mut self, ctx: &mut Context) {
+        let t = ctx.settings.time_zone.to_local(&ctx.rtc.now());
+        self.century = t.year / 100;
+        let values = [
+            (t.year % 100) as u8,
+            t.month,
+            t.day,
+            t.hour,
+            t.minute,
+            t.second,
+        ];
+        for (i, v) in values.iter().enumerate() {
+            self.digits[i * 2] = (v / 10) % 10;
+            self.digits[i * 2 + 1] = v % 10;
+        }
+        self.position = 0;
+    }
+
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action {
+        let order = ctx.settings.preferences.date_order;
+        let (digit_order, date_cols) = date_layout(order);
+        let digit = &mut self.digits[digit_order[self.position]];
+        match key {
+            SW::CenterLong | SW::None => (),
+            SW::Left => {
+                if self.position == 0 {
+                    return Action::Back;
+                }
+                self.position -= 1;
+            }
+            SW::Right => self.position = (self.position + 1) % 12,
+            SW::Up => *digit = (*digit + 1) % 10,
+            SW::Down => *digit = (*digit + 9) % 10,
+            SW::Center => {
+                // 画面では地方時で設定し、RTC には UTC で書き込む
+                let time = ctx.settings.time_zone.to_utc(&self.edited_time());
+                ctx.rtc.set(&time);
+                ctx.time_set = true;
+                return Action::Back;
+            }
+        }
+
+        // 広い表示器では日時の次の行に曜日、狭い表示器では日付の後ろに曜日、次の行に時刻
+        let row = Self::edit_row(frame);
+        if frame.geometry().has_title() {
+            frame.put(0, 0, b" Set Date Time");
+        }
+        let d = self.digits.map(|d| d | b'0');
+        let time = [d[6], d[7], b':', d[8], d[9], b':', d[10], d[11]];
+        let weekday = self.edited_time().weekday_name().as_bytes();
+        frame.clear_row(row);
+        frame.clear_row(row + 1);
+        let (year, month, day) = (
+            self.century * 100 + self.value(0) as u16,
+            self.value(1),
+            self.value(2),
+        );
+        match order {
+            DateOrder::Ymd => {
+                frame.print(0, row, format_args!("{:04}/{:02}/{:02}", year, month, day))
+            }
+            DateOrder::Dmy => {
+                frame.print(0, row, format_args!("{:02}/{:02}/{:04}", day, month, year))
+            }
+            DateOrder::Mdy => {
+                frame.print(0, row, format_args!("{:02}/{:02}/{:04}", month, day, year))
+            }
+        };
+        if frame.geometry().is_wide() {
+            frame.put(11, row, &time);
+            frame.put(2, row + 1, weekday);
+        } else {
+            frame.put(11, row, weekday);
+            frame.put(0, row + 1, &time);
+        }
+        frame.set_cursor(Some(self.edit_position(frame, &date_cols)));
+        Action::None
+    }
+}
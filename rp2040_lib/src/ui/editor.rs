@@ -0,0 +1,142 @@
+// 設定の値を変える画面
+// どの設定をどう読み書きするかは NumberField / ChoiceField に書いておく
+// Up / Down で値を変え、Center で確定して戻る。Left は変えずに戻る
+
+use crate::sc2004::Frame;
+use crate::settings::Settings;
+use crate::ui::Action;
+use crate::ui::Context;
+use crate::ui::Screen;
+use crate::SW;
+
+// 数値の設定。値は 10 の decimals 乗倍した整数で扱う
+// channel が Some なら計測値 (0: 温度, 1: 湿度, 2: 気圧) として、preferences の単位に換算して見せる
+pub struct NumberField {
+    pub title: &'static str,
+    pub unit: &'static str,
+    pub channel: Option<usize>,
+    pub min: i32,
+    pub max: i32,
+    pub step: i32,
+    pub decimals: u8,
+    pub get: fn(&Settings) -> i32,
+    pub set: fn(&mut Settings, i32),
+}
+
+// 選択肢から選ぶ設定
+pub struct ChoiceField {
+    pub title: &'static str,
+    pub choices: &'static [&'static str],
+    pub get: fn(&Settings) -> usize,
+    pub set: fn(&mut Settings, usize),
+}
+
+pub struct NumberEditor {
+    field: &'static NumberField,
+    value: i32,
+}
+
+impl NumberEditor {
+    pub const fn new(field: &'static NumberField) -> Self {
+        Self { field, value: 0 }
+    }
+}
+
+impl Screen for NumberEditor {
+    fn enter(&mut self, ctx: &mut Context) {
+        self.value = (self.field.get)(ctx.settings);
+    }
+
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action {
+        let f = self.field;
+        match key {
+            SW::Up => self.value = (self.value + f.step).min(f.max),
+            SW::Down => self.value = (self.value - f.step).max(f.min),
+            SW::Center => {
+                (f.set)(ctx.settings, self.value);
+                ctx.settings_changed = true;
+                return Action::Back;
+            }
+            SW::Left => return Action::Back,
+            _ => (),
+        }
+
+        frame.put(0, 0, f.title.as_bytes());
+        frame.clear_row(1);
+        let n = match f.channel {
+            Some(channel) => {
+                let prefs = &ctx.settings.preferences;
+                let value = self.value as f64 / 10_u32.pow(f.decimals as u32) as f64;
+                let n = frame.print(
+                    2,
+                    1,
+                    format_args!(
+                        "{:.*}",
+                        f.decimals.max(prefs.decimals(channel) as u8) as usize,
+                        prefs.convert(channel, value)
+                    ),
+                );
+                frame.print(2 + n, 1, format_args!(" {}", prefs.unit(channel)));
+                n
+            }
+            None => {
+                let sign = if self.value < 0 { "-" } else { "" };
+                let v = self.value.unsigned_abs();
+                let n = match f.decimals {
+                    0 => frame.print(2, 1, format_args!("{}{}", sign, v)),
+                    d => {
+                        let scale = 10_u32.pow(d as u32);
+                        frame.print(
+                            2,
+                            1,
+                            format_args!("{}{}.{:0w$}", sign, v / scale, v % scale, w = d as usize),
+                        )
+                    }
+                };
+                frame.print(2 + n, 1, format_args!(" {}", f.unit));
+                n
+            }
+        };
+        frame.set_cursor(Some((2 + n - 1, 1)));
+        Action::None
+    }
+}
+
+pub struct ChoiceEditor {
+    field: &'static ChoiceField,
+    index: usize,
+}
+
+impl ChoiceEditor {
+    pub const fn new(field: &'static ChoiceField) -> Self {
+        Self { field, index: 0 }
+    }
+}
+
+impl Screen for ChoiceEditor {
+    fn enter(&mut self, ctx: &mut Context) {
+        self.index = (self.field.get)(ctx.settings) % self.field.choices.len();
+    }
+
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action {
+        let f = self.field;
+        let n = f.choices.len();
+        match key {
+            SW::Up => self.index = (self.index + n - 1) % n,
+            SW::Down => self.index = (self.index + 1) % n,
+            SW::Center => {
+                (f.set)(ctx.settings, self.index);
+                ctx.settings_changed = true;
+                return Action::Back;
+            }
+            SW::Left => return Action::Back,
+            _ => (),
+        }
+
+        frame.put(0, 0, f.title.as_bytes());
+        frame.clear_row(1);
+        frame.put(2, 1, f.choices[self.index].as_bytes());
+        frame.set_cursor(Some((2, 1)));
+        Action::None
+    }
+}
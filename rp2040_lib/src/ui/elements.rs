@@ -0,0 +1,164 @@
+// 計測値の画面。1行目に日時と状態の印、残りに温度・湿度・気圧と、それぞれの直近 SPARK_LEN 個のスパークラインを並べる
+// 入りきらない表示器では PAGE_SECONDS ごとに切り替える。どのスイッチでも戻る
+
+use crate::glyph;
+use crate::glyph::Glyph;
+use crate::history::History;
+use crate::history::CHANNELS;
+use crate::preferences::Preferences;
+use crate::preferences::YearStyle;
+use crate::rtc::RtcStatus;
+use crate::rtc8564::Time;
+use crate::sc2004::Frame;
+use crate::ui::Action;
+use crate::ui::Context;
+use crate::ui::Screen;
+use crate::ui::CHANNEL_NAMES;
+use crate::SW;
+
+// 計測値1つ分の表示の幅。これより狭い表示器では右側が切れる
+const CELL_WIDTH: u8 = 20;
+// 計測値が一度に表示しきれないときに切り替える間隔（秒）
+const PAGE_SECONDS: u8 = 3;
+// 枠に入れるもの。計測値3つの後にスパークライン3つ
+const ITEMS: usize = 2 * CHANNELS;
+// "YYYY/MM/DD" と "YY/MM/DD" と "MM/DD" の長さ
+const FULL_DATE_LEN: u8 = 10;
+const SHORT_DATE_LEN: u8 = 8;
+const NO_YEAR_DATE_LEN: u8 = 5;
+// 1行目の右端に並べる印 (SDカード無し・RTC の電池・警報) の桁数
+const ICON_COLS: u8 = 3;
+// スパークラインに並べる記録の数
+const SPARK_LEN: usize = CELL_WIDTH as usize;
+// 傾向の矢印は今の値を TREND_SAMPLES 個前の記録 (30分前) と比べ、TREND_THRESHOLDS 以上違えば出す
+const TREND_SAMPLES: usize = 10;
+const TREND_THRESHOLDS: [f64; CHANNELS] = [0.5, 2.0, 1.0]; // ℃, %, hPa
+
+pub struct ElementsScreen;
+
+impl ElementsScreen {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for ElementsScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen for ElementsScreen {
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action {
+        if key != SW::None {
+            return Action::Back;
+        }
+
+        let g = frame.geometry();
+        let t = ctx.settings.time_zone.to_local(&ctx.rtc.now());
+        frame.clear();
+        frame.set_glyphs(&glyph::ELEMENTS);
+        let prefs = &ctx.settings.preferences;
+        date_time_line(frame, &t, prefs);
+        // 1行目の最初の枠の右端に、SDカードが無い・RTC の電池が弱い・警報中の印を出す
+        let icons = g.cols.min(CELL_WIDTH) - ICON_COLS;
+        if !ctx.sd_ok {
+            frame.put_glyph(icons, 0, Glyph::Sd);
+        }
+        if ctx.rtc.status() != RtcStatus::Running {
+            frame.put_glyph(icons + 1, 0, Glyph::Battery);
+        }
+        if ctx.alarm {
+            frame.put_glyph(icons + 2, 0, Glyph::Alarm);
+        }
+
+        // 日時の後ろの枠に計測値とスパークラインを1つずつ入れる
+        let per_row = (g.cols / CELL_WIDTH).max(1) as usize;
+        let slots = g.rows as usize * per_row - 1;
+        let (first, count) = if slots >= ITEMS {
+            (0, ITEMS)
+        } else {
+            let pages = ITEMS.div_ceil(slots) as u8;
+            let page = ((t.second / PAGE_SECONDS) % pages) as usize;
+            (page * slots, slots.min(ITEMS - page * slots))
+        };
+        for i in 0..count {
+            let slot = i + 1;
+            let cell = ((slot % per_row) as u8 * CELL_WIDTH, (slot / per_row) as u8);
+            let item = first + i;
+            if item < CHANNELS {
+                element_cell(frame, cell, item, ctx.elements, ctx.history, prefs);
+            } else {
+                spark_cell(frame, cell, item - CHANNELS, ctx.history);
+            }
+        }
+        Action::None
+    }
+}
+
+// 1行目の日時。最初の枠の中に、状態の印のための ICON_COLS 桁を残して書く
+// 入りきらなければ年を短くするか省き、それでも入らなければ秒を省く
+fn date_time_line(frame: &mut Frame, t: &Time, prefs: &Preferences) {
+    let width = frame.geometry().cols.min(CELL_WIDTH) - ICON_COLS;
+    let time_len = prefs.time_len(true);
+    let (year, seconds) = if FULL_DATE_LEN + 1 + time_len <= width {
+        (YearStyle::Full, true)
+    } else if SHORT_DATE_LEN + 1 + time_len <= width {
+        (YearStyle::Short, true)
+    } else {
+        (YearStyle::None, NO_YEAR_DATE_LEN + 1 + time_len <= width)
+    };
+    let col = frame.print(0, 0, format_args!("{} ", prefs.date(t, year)));
+    frame.print(col, 0, format_args!("{}", prefs.time(t, seconds)));
+}
+
+// 計測値1つ分 (0: 温度, 1: 湿度, 2: 気圧) を CELL_WIDTH 桁の枠に書く
+// 30分前より上がっていれば名前の後ろの ':' を上向き、下がっていれば下向きの矢印にする
+fn element_cell(
+    frame: &mut Frame,
+    (col, row): (u8, u8),
+    index: usize,
+    tup: (f64, f64, f64),
+    history: &History,
+    prefs: &Preferences,
+) {
+    let value = [tup.0, tup.1, tup.2][index];
+    frame.print(
+        col,
+        row,
+        format_args!(
+            "{}:{:>7.*} {}",
+            CHANNEL_NAMES[index],
+            prefs.decimals(index),
+            prefs.convert(index, value),
+            prefs.unit(index)
+        ),
+    );
+    if let Some(arrow) = trend(history, index, value) {
+        let name_len = CHANNEL_NAMES[index].len() as u8;
+        frame.put_glyph(col + name_len, row, arrow);
+    }
+}
+
+// 今の値 value を TREND_SAMPLES 個前の記録と比べた矢印。記録が無いか変化が小さければ None
+fn trend(history: &History, index: usize, value: f64) -> Option<Glyph> {
+    let past = history.get(TREND_SAMPLES, index)?;
+    if value - past >= TREND_THRESHOLDS[index] {
+        Some(Glyph::TrendUp)
+    } else if past - value >= TREND_THRESHOLDS[index] {
+        Some(Glyph::TrendDown)
+    } else {
+        None
+    }
+}
+
+// index の直近の記録 (最大 SPARK_LEN 個) を枠の右詰めでスパークラインにする。新しいものが右端
+fn spark_cell(frame: &mut Frame, (col, row): (u8, u8), index: usize, history: &History) {
+    let width = frame.geometry().cols.saturating_sub(col).min(CELL_WIDTH) as usize;
+    let len = history.len().min(SPARK_LEN).min(width);
+    let mut values = [0.0; SPARK_LEN];
+    for (i, v) in values[..len].iter_mut().enumerate() {
+        *v = history.get(len - 1 - i, index).unwrap_or(0.0);
+    }
+    glyph::sparkline(frame, col + (width - len) as u8, row, &values[..len]);
+}
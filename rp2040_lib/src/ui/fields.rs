@@ -0,0 +1,188 @@
+// LCD の設定画面で変えられる設定
+// 項目を足すときはここに NumberField / ChoiceField を書き、main の画面の表に editor を並べる
+
+use crate::logger::LogFormat;
+use crate::preferences::DateOrder;
+use crate::preferences::PressureUnit;
+use crate::preferences::TemperatureUnit;
+use crate::ui::editor::ChoiceField;
+use crate::ui::editor::NumberField;
+use crate::ui::StartScreen;
+
+pub static LOG_INTERVAL: NumberField = NumberField {
+    title: "Log interval",
+    unit: "sec",
+    channel: None,
+    min: 10,
+    max: 3600,
+    step: 10,
+    decimals: 0,
+    get: |s| s.log_interval.min(3600) as i32,
+    set: |s, v| s.log_interval = v as u32,
+};
+
+pub static ALARM_LOG_INTERVAL: NumberField = NumberField {
+    title: "Alarm log interval",
+    unit: "sec",
+    channel: None,
+    min: 10,
+    max: 3600,
+    step: 10,
+    decimals: 0,
+    get: |s| s.alarm_log_interval.min(3600) as i32,
+    set: |s, v| s.alarm_log_interval = v as u32,
+};
+
+// 下限が上限を超えないようにする
+pub static TEMPERATURE_MIN: NumberField = NumberField {
+    title: "Alarm temp min",
+    unit: "°C",
+    channel: Some(0),
+    min: -400,
+    max: 850,
+    step: 5,
+    decimals: 1,
+    get: |s| tenths(s.alarm_limits.temperature_min),
+    set: |s, v| {
+        s.alarm_limits.temperature_min = (v as f64 / 10.0).min(s.alarm_limits.temperature_max)
+    },
+};
+
+pub static TEMPERATURE_MAX: NumberField = NumberField {
+    title: "Alarm temp max",
+    unit: "°C",
+    channel: Some(0),
+    min: -400,
+    max: 850,
+    step: 5,
+    decimals: 1,
+    get: |s| tenths(s.alarm_limits.temperature_max),
+    set: |s, v| {
+        s.alarm_limits.temperature_max = (v as f64 / 10.0).max(s.alarm_limits.temperature_min)
+    },
+};
+
+pub static HUMIDITY_MAX: NumberField = NumberField {
+    title: "Alarm humi max",
+    unit: "%",
+    channel: Some(1),
+    min: 0,
+    max: 1000,
+    step: 10,
+    decimals: 1,
+    get: |s| tenths(s.alarm_limits.humidity_max),
+    set: |s, v| s.alarm_limits.humidity_max = v as f64 / 10.0,
+};
+
+// 0 なら消さない
+pub static LCD_IDLE_TIMEOUT: NumberField = NumberField {
+    title: "LCD off after",
+    unit: "sec",
+    channel: None,
+    min: 0,
+    max: 3600,
+    step: 30,
+    decimals: 0,
+    get: |s| s.display_power.idle_timeout.min(3600) as i32,
+    set: |s, v| s.display_power.idle_timeout = v as u32,
+};
+
+const LOG_FORMATS: [LogFormat; 4] = [
+    LogFormat::Text,
+    LogFormat::Csv,
+    LogFormat::Tsv,
+    LogFormat::Binary,
+];
+
+pub static LOG_FORMAT: ChoiceField = ChoiceField {
+    title: "Log format",
+    choices: &["txt", "csv", "tsv", "bin"],
+    get: |s| {
+        LOG_FORMATS
+            .iter()
+            .position(|&f| f == s.log_format)
+            .unwrap_or(0)
+    },
+    set: |s, i| s.log_format = LOG_FORMATS[i],
+};
+
+pub static START_SCREEN: ChoiceField = ChoiceField {
+    title: "Start screen",
+    choices: &["menu", "elements"],
+    get: |s| match s.start_screen {
+        StartScreen::Menu => 0,
+        StartScreen::Elements => 1,
+    },
+    set: |s, i| {
+        s.start_screen = if i == 1 {
+            StartScreen::Elements
+        } else {
+            StartScreen::Menu
+        }
+    },
+};
+
+pub static TEMPERATURE_UNIT: ChoiceField = ChoiceField {
+    title: "Temperature unit",
+    choices: &["°C", "°F"],
+    get: |s| match s.preferences.temperature_unit {
+        TemperatureUnit::Celsius => 0,
+        TemperatureUnit::Fahrenheit => 1,
+    },
+    set: |s, i| {
+        s.preferences.temperature_unit = if i == 1 {
+            TemperatureUnit::Fahrenheit
+        } else {
+            TemperatureUnit::Celsius
+        }
+    },
+};
+
+pub static PRESSURE_UNIT: ChoiceField = ChoiceField {
+    title: "Pressure unit",
+    choices: &["hPa", "inHg"],
+    get: |s| match s.preferences.pressure_unit {
+        PressureUnit::Hpa => 0,
+        PressureUnit::InHg => 1,
+    },
+    set: |s, i| {
+        s.preferences.pressure_unit = if i == 1 {
+            PressureUnit::InHg
+        } else {
+            PressureUnit::Hpa
+        }
+    },
+};
+
+pub static DECIMALS: ChoiceField = ChoiceField {
+    title: "Decimal places",
+    choices: &["0", "1", "2"],
+    get: |s| s.preferences.decimals as usize,
+    set: |s, i| s.preferences.decimals = i as u8,
+};
+
+pub static CLOCK: ChoiceField = ChoiceField {
+    title: "Clock",
+    choices: &["24h", "12h"],
+    get: |s| if s.preferences.clock_24h { 0 } else { 1 },
+    set: |s, i| s.preferences.clock_24h = i == 0,
+};
+
+const DATE_ORDERS: [DateOrder; 3] = [DateOrder::Ymd, DateOrder::Dmy, DateOrder::Mdy];
+
+pub static DATE_ORDER: ChoiceField = ChoiceField {
+    title: "Date order",
+    choices: &["Y/M/D", "D/M/Y", "M/D/Y"],
+    get: |s| {
+        DATE_ORDERS
+            .iter()
+            .position(|&o| o == s.preferences.date_order)
+            .unwrap_or(0)
+    },
+    set: |s, i| s.preferences.date_order = DATE_ORDERS[i],
+};
+
+// 0.1 単位の整数に丸める
+fn tenths(value: f64) -> i32 {
+    libm::round(value * 10.0) as i32
+}
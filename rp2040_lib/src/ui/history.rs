@@ -0,0 +1,99 @@
+// 計測値の履歴のグラフ
+// Left / Right で温度・湿度・気圧を切り替え、Up / Down で期間 (1h / 6h / 24h) を変える。Center で戻る
+
+use crate::glyph;
+use crate::history::CHANNELS;
+use crate::sc2004::Frame;
+use crate::sc2004::MAX_COLS;
+use crate::ui::Action;
+use crate::ui::Context;
+use crate::ui::Screen;
+use crate::ui::CHANNEL_NAMES;
+use crate::SW;
+
+// グラフの桁数の上限
+const GRAPH_WIDTH: u8 = 20;
+
+// 期間 (分) と表示名
+const WINDOWS: [(u32, &str); 3] = [(60, "1h"), (6 * 60, "6h"), (24 * 60, "24h")];
+
+pub struct HistoryScreen {
+    channel: usize,
+    window: usize,
+}
+
+impl HistoryScreen {
+    pub const fn new() -> Self {
+        Self {
+            channel: 0,
+            window: 0,
+        }
+    }
+}
+
+impl Default for HistoryScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen for HistoryScreen {
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action {
+        match key {
+            SW::Left => self.channel = (self.channel + CHANNELS - 1) % CHANNELS,
+            SW::Right => self.channel = (self.channel + 1) % CHANNELS,
+            SW::Up => self.window = (self.window + 1) % WINDOWS.len(),
+            SW::Down => self.window = (self.window + WINDOWS.len() - 1) % WINDOWS.len(),
+            SW::Center => return Action::Back,
+            SW::CenterLong | SW::None => (),
+        }
+
+        let g = frame.geometry();
+        let width = g.cols.min(GRAPH_WIDTH);
+        let (minutes, window_name) = WINDOWS[self.window];
+        let mut values = [0.0; MAX_COLS];
+        let n = ctx
+            .history
+            .series(self.channel, minutes, &mut values[..width as usize]);
+        let prefs = &ctx.settings.preferences;
+        for v in values[..n].iter_mut() {
+            *v = prefs.convert(self.channel, *v);
+        }
+        let values = &values[..n];
+        let (min, max) = values
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+
+        frame.clear();
+        frame.set_glyphs(&glyph::BARS);
+        let name = CHANNEL_NAMES[self.channel];
+        let unit = prefs.unit(self.channel);
+        let d = prefs.decimals(self.channel);
+        if g.has_title() {
+            // 見出し、最大値、グラフ、最小値の順
+            frame.print(0, 0, format_args!("{} {} ({})", name, window_name, unit));
+            if n > 0 {
+                frame.print(0, 1, format_args!("max {:.*}", d, max));
+                frame.print(0, 3, format_args!("min {:.*}", d, min));
+            }
+        } else {
+            // 1行目に名前と期間、最小値/最大値をまとめる
+            let name = if g.cols < GRAPH_WIDTH {
+                &name[..1]
+            } else {
+                name
+            };
+            let col = frame.print(0, 0, format_args!("{} {}", name, window_name)) + 1;
+            if n > 0 {
+                frame.print(col, 0, format_args!("{:.*}/{:.*}", d, min, d, max));
+            }
+        }
+        let row = if g.has_title() { 2 } else { 1 };
+        if n == 0 {
+            frame.put(0, row, b"No data");
+        } else {
+            glyph::sparkline(frame, width - n as u8, row, values);
+        }
+        Action::None
+    }
+}
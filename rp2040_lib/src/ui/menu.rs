@@ -0,0 +1,74 @@
+// 項目を選んで次の画面に進むメニュー
+// 表示器に入りきらない項目は Up / Down で送る
+
+use crate::sc2004::Frame;
+use crate::ui::Action;
+use crate::ui::Context;
+use crate::ui::Screen;
+use crate::ui::ScreenId;
+use crate::SW;
+
+pub struct MenuItem {
+    pub label: &'static str,
+    pub short: &'static str, // 20桁に満たない表示器での名前
+    pub target: ScreenId,
+}
+
+pub struct ListMenu {
+    title: &'static str,
+    items: &'static [MenuItem],
+    selected: usize,
+    top: usize, // 一番上に表示している項目
+}
+
+impl ListMenu {
+    pub const fn new(title: &'static str, items: &'static [MenuItem]) -> Self {
+        Self {
+            title,
+            items,
+            selected: 0,
+            top: 0,
+        }
+    }
+}
+
+impl Screen for ListMenu {
+    fn update(&mut self, frame: &mut Frame, key: SW, _ctx: &mut Context) -> Action {
+        let n = self.items.len();
+        match key {
+            SW::Up => self.selected = (self.selected + n - 1) % n,
+            SW::Down => self.selected = (self.selected + 1) % n,
+            SW::Center | SW::Right => return Action::Push(self.items[self.selected].target),
+            SW::Left => return Action::Back,
+            SW::CenterLong | SW::None => (),
+        }
+
+        let g = frame.geometry();
+        let first = if g.has_title() { 1 } else { 0 };
+        let visible = (g.rows - first) as usize;
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + visible {
+            self.top = self.selected + 1 - visible;
+        }
+
+        if g.has_title() {
+            frame.clear_row(0);
+            frame.put(0, 0, self.title.as_bytes());
+        }
+        for i in 0..visible {
+            let row = first + i as u8;
+            frame.clear_row(row);
+            if let Some(item) = self.items.get(self.top + i) {
+                let label = if g.cols < 20 && !item.short.is_empty() {
+                    item.short
+                } else {
+                    item.label
+                };
+                frame.put(0, row, label.as_bytes());
+            }
+        }
+        frame.set_cursor(Some((0, first + (self.selected - self.top) as u8)));
+        Action::None
+    }
+}
@@ -0,0 +1,124 @@
+// 最小・最大の画面
+// Left / Right で温度・湿度・気圧を切り替え、Up / Down で「今日」と「リセットしてから」を切り替える
+// Center を長押しすると「リセットしてから」を消す。Center で戻る
+
+use crate::glyph::Glyph;
+use crate::history::CHANNELS;
+use crate::min_max::Extreme;
+use crate::preferences::Preferences;
+use crate::preferences::YearStyle;
+use crate::rtc8564::Time;
+use crate::sc2004::Frame;
+use crate::ui::Action;
+use crate::ui::Context;
+use crate::ui::Screen;
+use crate::ui::CHANNEL_NAMES;
+use crate::SW;
+
+// " 25.3 " と "01/02 " の長さ
+const VALUE_LEN: u8 = 6;
+const DATE_LEN: u8 = 6;
+
+pub struct MinMaxScreen {
+    channel: usize,
+    today: bool,
+}
+
+impl MinMaxScreen {
+    pub const fn new() -> Self {
+        Self {
+            channel: 0,
+            today: true,
+        }
+    }
+
+    // col 桁目から " 25.3 14:05" のように値と日時を書く
+    // 「リセットしてから」で幅が足りれば "MM/DD hh:mm"、そうでなければ今日は "hh:mm"、それより前は "MM/DD"
+    // 日付の順と12/24時間制は prefs に従う
+    fn line(
+        &self,
+        frame: &mut Frame,
+        (col, row): (u8, u8),
+        extreme: Option<Extreme>,
+        now: &Time,
+        prefs: &Preferences,
+    ) {
+        let full = !self.today
+            && col + VALUE_LEN + DATE_LEN + prefs.time_len(false) <= frame.geometry().cols;
+        let Some(e) = extreme else {
+            frame.put(col, row, b"  ---");
+            return;
+        };
+        let d = prefs.decimals(self.channel);
+        let value = prefs.convert(self.channel, e.value);
+        let t = &e.time;
+        let col = col + frame.print(col, row, format_args!("{:>5.*} ", d, value));
+        let same_day = (t.year, t.month, t.day) == (now.year, now.month, now.day);
+        let date = prefs.date(t, YearStyle::None);
+        let time = prefs.time(t, false);
+        if full {
+            frame.print(col, row, format_args!("{} {}", date, time));
+        } else if same_day {
+            frame.print(col, row, format_args!("{}", time));
+        } else {
+            frame.print(col, row, format_args!("{}", date));
+        }
+    }
+}
+
+impl Default for MinMaxScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen for MinMaxScreen {
+    fn update(&mut self, frame: &mut Frame, key: SW, ctx: &mut Context) -> Action {
+        match key {
+            SW::Left => self.channel = (self.channel + CHANNELS - 1) % CHANNELS,
+            SW::Right => self.channel = (self.channel + 1) % CHANNELS,
+            SW::Up | SW::Down => self.today = !self.today,
+            SW::Center => return Action::Back,
+            SW::CenterLong => ctx.extremes.reset(),
+            SW::None => (),
+        }
+
+        let g = frame.geometry();
+        let now = ctx.settings.time_zone.to_local(&ctx.rtc.now());
+        let (period, period_name, period_mark) = if self.today {
+            (&ctx.extremes.today, "today", b'D')
+        } else {
+            (&ctx.extremes.since_reset, "since reset", b'R')
+        };
+        let (max, min) = (period.max(self.channel), period.min(self.channel));
+        let prefs = &ctx.settings.preferences;
+
+        frame.clear();
+        let name = CHANNEL_NAMES[self.channel];
+        if g.has_title() {
+            // 見出し、最大、最小、操作の説明の順。見出しが入りきらなければ名前を1文字にする
+            let unit = prefs.unit(self.channel);
+            let title_len = name.len() + unit.len().max(3) + period_name.len() + 2;
+            let name = if title_len > g.cols as usize {
+                &name[..1]
+            } else {
+                name
+            };
+            frame.print(0, 0, format_args!("{} {:<3} {}", name, unit, period_name));
+            frame.put(0, 1, b"Hi");
+            frame.put(0, 2, b"Lo");
+            self.line(frame, (3, 1), max, &now, prefs);
+            self.line(frame, (3, 2), min, &now, prefs);
+            frame.put(0, 3, b"Hold Center: reset");
+        } else {
+            // 行の頭に計測値 (T/H/P) と期間 (D: 今日, R: リセットしてから) の1文字、続けて最大・最小の矢印
+            frame.put(0, 0, &name.as_bytes()[..1]);
+            frame.put(0, 1, &[period_mark]);
+            frame.put_glyph(1, 0, Glyph::TrendUp);
+            frame.put_glyph(1, 1, Glyph::TrendDown);
+            self.line(frame, (2, 0), max, &now, prefs);
+            self.line(frame, (2, 1), min, &now, prefs);
+        }
+        Action::None
+    }
+}
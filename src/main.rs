@@ -4,6 +4,7 @@
 use embedded_hal_0_2::blocking::delay::{DelayMs, DelayUs}; // embedded-hal ver0.2.x
 
 //use fugit::MicrosDurationU32;
+use fugit::HertzU32;
 use fugit::RateExtU32;
 use rp2040_hal::fugit::MicrosDurationU32;
 
@@ -11,11 +12,13 @@ use hal::pac;
 use hal::uart::{DataBits, StopBits, UartConfig};
 use rp2040_hal::spi::Enabled;
 use rp2040_hal::Clock;
+use rp2040_lib::sc2004::Hd44780Bus;
 use rp2040_lib::sc2004::SC2004;
 use rp_pico::entry;
 
 use embedded_hal::digital::InputPin;
 use embedded_hal::digital::StatefulOutputPin;
+use embedded_hal::pwm::SetDutyCycle;
 
 //use panic_halt as _;
 use rp2040_hal as hal;
@@ -25,8 +28,31 @@ use rp2040_lib::print;
 use rp2040_lib::println;
 
 use rp2040_lib::bme280::spi::BME280;
+
+use binlog::{FLAG_RTC_INVALID, FLAG_SENSOR_ERROR, FLAG_TIME_ADJUSTED};
+use rp2040_lib::display_power::DisplayPower;
+use rp2040_lib::drift::parse_reference;
+use rp2040_lib::drift::DriftTracker;
+use rp2040_lib::drift::RECORD_SIZE;
+use rp2040_lib::ds3231::DS3231;
+use rp2040_lib::ds3231::DS3231_DEVICE_ADDRESS;
+use rp2040_lib::history::History;
+use rp2040_lib::logger::LineBuffer;
+use rp2040_lib::logger::Logger;
+use rp2040_lib::logger::Measurement;
+use rp2040_lib::min_max::Extremes;
+use rp2040_lib::rp2040_rtc::InternalRtc;
+use rp2040_lib::rtc::Rtc;
+use rp2040_lib::rtc::RtcStatus;
+use rp2040_lib::rtc::RtcTimeSource;
+use rp2040_lib::rtc8564::Time;
 use rp2040_lib::rtc8564::RTC8564;
 use rp2040_lib::rtc8564::RTC_DEVICE_ADDRESS;
+use rp2040_lib::schedule::LogSchedule;
+use rp2040_lib::settings;
+use rp2040_lib::settings::Settings;
+
+use rp2040_hal::rtc::{DateTime, DayOfWeek, RealTimeClock};
 
 use rp2040_hal::gpio::bank0::Gpio15;
 use rp2040_hal::gpio::bank0::Gpio19;
@@ -53,7 +79,8 @@ use crate::pac::SPI0;
 use crate::pac::SPI1;
 use rp2040_hal::Spi;
 
-use hd44780_driver::HD44780;
+use hd44780_driver::bus::FourBitBus;
+use hd44780_driver::Display;
 
 use rp2040_hal::gpio::bank0::Gpio16;
 use rp2040_hal::gpio::bank0::Gpio17;
@@ -64,6 +91,7 @@ use rp2040_hal::I2C;
 
 use core::cell::RefCell;
 use critical_section::Mutex;
+use embedded_hal_bus::i2c::RefCellDevice;
 
 use rp2040_hal::timer::Alarm;
 
@@ -80,18 +108,34 @@ static mut LED_AND_ALARM: Mutex<RefCell<Option<LedAndAlarm>>> = Mutex::new(RefCe
 
 const FAST_BLINK_INTERVAL_US: MicrosDurationU32 = MicrosDurationU32::millis(20);
 
-type Rtc = RTC8564<
-    I2C<
-        I2C0,
-        (
-            Pin<Gpio16, FunctionI2c, PullUp>,
-            Pin<Gpio17, FunctionI2c, PullUp>,
-        ),
-    >,
+const DRIFT_FILE_NAME: &str = "DRIFT.BIN";
+
+const RX_LINE_SIZE: usize = 82; // NMEA の1文の最大長
+
+struct RxLine {
+    buf: [u8; RX_LINE_SIZE],
+    len: usize,
+}
+
+type UartRx = hal::uart::Reader<
+    pac::UART0,
+    (
+        Pin<hal::gpio::bank0::Gpio0, hal::gpio::FunctionUart, hal::gpio::PullNone>,
+        Pin<hal::gpio::bank0::Gpio1, hal::gpio::FunctionUart, hal::gpio::PullNone>,
+    ),
 >;
 
-type Volume_Manager = VolumeManager<
-    SdCard<
+// 記録の間隔や形式、タイムゾーンなどは SDカードの CONFIG.INI で設定する (rp2040_lib::settings)
+// RTC は UTC で計時し、LCD とログには設定したタイムゾーンの地方時も出す
+const DEFAULT_UART_BAUD: u32 = 9600;
+
+// SDカードの SPI クロックの上限
+const SD_CLOCK_HZ: u32 = 16_000_000;
+// 初期化のときの SPI クロック。CSD の転送速度がこれより遅ければ、この速さのままにする
+const SD_INIT_CLOCK_HZ: u32 = 400_000;
+
+type SdLogger = Logger<
+    SdSpi<
         Spi<
             Enabled,
             SPI1,
@@ -104,12 +148,164 @@ type Volume_Manager = VolumeManager<
         Pin<Gpio13, FunctionSio<SioOutput>, PullDown>,
         rp2040_hal::timer::Timer,
     >,
-    DummyTimesource,
+    RtcTimeSource<&'static RefCell<dyn Rtc>>,
+>;
+
+type I2c0 = I2C<
+    I2C0,
+    (
+        Pin<Gpio16, FunctionI2c, PullUp>,
+        Pin<Gpio17, FunctionI2c, PullUp>,
+    ),
+>;
+
+type RtcBus = RefCellDevice<'static, I2c0>;
+
+// LCD は 4ビット接続
+type LcdPin<I> = Pin<I, FunctionSio<SioOutput>, PullDown>;
+type LcdBus = FourBitBus<
+    LcdPin<hal::gpio::bank0::Gpio28>,
+    LcdPin<hal::gpio::bank0::Gpio27>,
+    LcdPin<hal::gpio::bank0::Gpio26>,
+    LcdPin<hal::gpio::bank0::Gpio22>,
+    LcdPin<hal::gpio::bank0::Gpio21>,
+    LcdPin<hal::gpio::bank0::Gpio20>,
 >;
 
 static mut SS_NOW: u8 = 0; // 現在の状態
 static mut SS_ONE_BEFORE: u8 = 0; // ひとつ前の状態
 static mut SS_TWO_BEFORE: u8 = 0; // ふたつ前の状態
+static mut CENTER_HELD: u8 = 0; // Center を押し続けている回数
+
+// Center をこの回数 (FAST_BLINK_INTERVAL_US ごと) 押し続けたら長押し
+const LONG_PRESS_TICKS: u8 = 100;
+
+// LCD の画面。main() の screens に同じ順で並べる
+const SCREEN_MENU: ScreenId = 0;
+const SCREEN_ELEMENTS: ScreenId = 1;
+const SCREEN_SET_DATE_TIME: ScreenId = 2;
+const SCREEN_SETTINGS: ScreenId = 3;
+const SCREEN_LOG_INTERVAL: ScreenId = 4;
+const SCREEN_ALARM_LOG_INTERVAL: ScreenId = 5;
+const SCREEN_TEMPERATURE_MIN: ScreenId = 6;
+const SCREEN_TEMPERATURE_MAX: ScreenId = 7;
+const SCREEN_HUMIDITY_MAX: ScreenId = 8;
+const SCREEN_LOG_FORMAT: ScreenId = 9;
+const SCREEN_START_SCREEN: ScreenId = 10;
+const SCREEN_HISTORY: ScreenId = 11;
+const SCREEN_MIN_MAX: ScreenId = 12;
+const SCREEN_LCD_IDLE_TIMEOUT: ScreenId = 13;
+const SCREEN_UNITS: ScreenId = 14;
+const SCREEN_TEMPERATURE_UNIT: ScreenId = 15;
+const SCREEN_PRESSURE_UNIT: ScreenId = 16;
+const SCREEN_DECIMALS: ScreenId = 17;
+const SCREEN_CLOCK: ScreenId = 18;
+const SCREEN_DATE_ORDER: ScreenId = 19;
+const SCREEN_COUNT: usize = 20;
+
+static MENU_ITEMS: [MenuItem; 5] = [
+    MenuItem {
+        label: "1.Display elements",
+        short: "1.Elements",
+        target: SCREEN_ELEMENTS,
+    },
+    MenuItem {
+        label: "2.Set Date Time",
+        short: "",
+        target: SCREEN_SET_DATE_TIME,
+    },
+    MenuItem {
+        label: "3.Settings",
+        short: "",
+        target: SCREEN_SETTINGS,
+    },
+    MenuItem {
+        label: "4.History",
+        short: "",
+        target: SCREEN_HISTORY,
+    },
+    MenuItem {
+        label: "5.Min/Max",
+        short: "",
+        target: SCREEN_MIN_MAX,
+    },
+];
+
+static SETTINGS_ITEMS: [MenuItem; 9] = [
+    MenuItem {
+        label: "Log interval",
+        short: "",
+        target: SCREEN_LOG_INTERVAL,
+    },
+    MenuItem {
+        label: "Alarm log interval",
+        short: "Alarm interval",
+        target: SCREEN_ALARM_LOG_INTERVAL,
+    },
+    MenuItem {
+        label: "Alarm temp min",
+        short: "",
+        target: SCREEN_TEMPERATURE_MIN,
+    },
+    MenuItem {
+        label: "Alarm temp max",
+        short: "",
+        target: SCREEN_TEMPERATURE_MAX,
+    },
+    MenuItem {
+        label: "Alarm humi max",
+        short: "",
+        target: SCREEN_HUMIDITY_MAX,
+    },
+    MenuItem {
+        label: "Log format",
+        short: "",
+        target: SCREEN_LOG_FORMAT,
+    },
+    MenuItem {
+        label: "Start screen",
+        short: "",
+        target: SCREEN_START_SCREEN,
+    },
+    MenuItem {
+        label: "LCD off after",
+        short: "",
+        target: SCREEN_LCD_IDLE_TIMEOUT,
+    },
+    MenuItem {
+        label: "Units & format",
+        short: "",
+        target: SCREEN_UNITS,
+    },
+];
+
+static UNITS_ITEMS: [MenuItem; 5] = [
+    MenuItem {
+        label: "Temperature unit",
+        short: "Temp unit",
+        target: SCREEN_TEMPERATURE_UNIT,
+    },
+    MenuItem {
+        label: "Pressure unit",
+        short: "Pres unit",
+        target: SCREEN_PRESSURE_UNIT,
+    },
+    MenuItem {
+        label: "Decimal places",
+        short: "Decimals",
+        target: SCREEN_DECIMALS,
+    },
+    MenuItem {
+        label: "Clock",
+        short: "",
+        target: SCREEN_CLOCK,
+    },
+    MenuItem {
+        label: "Date order",
+        short: "",
+        target: SCREEN_DATE_ORDER,
+    },
+];
 
 type CenterSw = Pin<Gpio2, FunctionSio<SioInput>, PullDown>;
 type DownSw = Pin<Gpio3, FunctionSio<SioInput>, PullDown>;
@@ -123,13 +319,25 @@ static mut LEFT_SW: Option<LeftSw> = None;
 static mut RIGHT_SW: Option<RightSw> = None;
 static mut UP_SW: Option<UpSw> = None;
 
-use rp2040_lib::ScreenState;
+use rp2040_lib::ui::date_time::DateTimeScreen;
+use rp2040_lib::ui::editor::ChoiceEditor;
+use rp2040_lib::ui::editor::NumberEditor;
+use rp2040_lib::ui::elements::ElementsScreen;
+use rp2040_lib::ui::fields;
+use rp2040_lib::ui::history::HistoryScreen;
+use rp2040_lib::ui::menu::ListMenu;
+use rp2040_lib::ui::menu::MenuItem;
+use rp2040_lib::ui::min_max::MinMaxScreen;
+use rp2040_lib::ui::Context;
+use rp2040_lib::ui::Navigator;
+use rp2040_lib::ui::Screen;
+use rp2040_lib::ui::ScreenId;
+use rp2040_lib::ui::StartScreen;
 use rp2040_lib::SW;
 use rp2040_lib::SWITCH;
 
-use embedded_sdmmc::{Directory, SdCard, TimeSource, Timestamp, Volume, VolumeIdx, VolumeManager};
-
-use embedded_sdmmc::filesystem::Mode;
+use embedded_sdmmc::VolumeManager;
+use rp2040_lib::sd::diskio::SdSpi;
 
 use embedded_hal::delay::DelayNs;
 
@@ -137,28 +345,6 @@ use embedded_hal::delay::DelayNs;
 // use defmt::Debug2Format;
 // use defmt_rtt as _;
 
-pub struct Vol_items {
-    vol_man: Volume_Manager,
-    vol: Volume,
-    dir: Directory,
-}
-
-#[derive(Default)]
-pub struct DummyTimesource();
-
-impl TimeSource for DummyTimesource {
-    fn get_timestamp(&self) -> Timestamp {
-        Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
-        }
-    }
-}
-
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -196,12 +382,12 @@ fn main() -> ! {
     let uart_pins = (pins.gpio0.reconfigure(), pins.gpio1.reconfigure());
     let uart = hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
         .enable(
-            UartConfig::new(9600.Hz(), DataBits::Eight, None, StopBits::One),
+            UartConfig::new(DEFAULT_UART_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
             clocks.peripheral_clock.freq(),
         )
         .unwrap();
 
-    let (_, uart_tx) = uart.split();
+    let (mut uart_rx, uart_tx) = uart.split();
 
     critical_section::with(|_| unsafe {
         UART_TRANSMITTER = Some(uart_tx);
@@ -234,6 +420,7 @@ fn main() -> ! {
     let cs = pins.gpio5.into_push_pull_output();
 
     // LCD Display
+    // 8ビット接続なら EightBitBus::from_pins、I2C バックパックなら I2CBus::new に置き換える
 
     let rs = pins.gpio28.reconfigure();
     let en = pins.gpio27.reconfigure();
@@ -242,8 +429,8 @@ fn main() -> ! {
     let d6 = pins.gpio21.reconfigure();
     let d7 = pins.gpio20.reconfigure();
 
-    let hd44780 = HD44780::new_4bit(rs, en, d4, d5, d6, d7, &mut delay).unwrap();
-    let mut lcd = SC2004::new(hd44780);
+    let lcd_bus: LcdBus = FourBitBus::from_pins(rs, en, d4, d5, d6, d7);
+    let mut lcd = SC2004::new(lcd_bus);
 
     let sda_pin = pins.gpio16.reconfigure();
     let scl_pin = pins.gpio17.reconfigure();
@@ -257,17 +444,47 @@ fn main() -> ! {
         &clocks.peripheral_clock,
     );
 
-    let mut rtc8564 = RTC8564::<
-        I2C<
-            I2C0,
-            (
-                Pin<Gpio16, FunctionI2c, PullUp>,
-                Pin<Gpio17, FunctionI2c, PullUp>,
-            ),
-        >,
-    >::new(i2c, RTC_DEVICE_ADDRESS);
+    // I2C0 はほかの I2C デバイスとも共有できるよう RefCell に入れておく
+    let i2c_bus: &'static RefCell<I2c0> =
+        cortex_m::singleton!(: RefCell<I2c0> = RefCell::new(i2c)).unwrap();
 
-    let _ = rtc8564.init();
+    let mut rtc8564 = RTC8564::<RtcBus>::new(RefCellDevice::new(i2c_bus), RTC_DEVICE_ADDRESS);
+    let mut ds3231 = DS3231::<RtcBus>::new(RefCellDevice::new(i2c_bus), DS3231_DEVICE_ADDRESS);
+
+    // RTC8564 と DS3231 のどちらも実装されていなければ RP2040 内蔵の RTC で代用する
+    let real_time_clock = RealTimeClock::new(
+        pac.RTC,
+        clocks.rtc_clock,
+        &mut pac.RESETS,
+        DateTime {
+            year: 2024,
+            month: 6,
+            day: 25,
+            day_of_week: DayOfWeek::Tuesday,
+            hour: 18,
+            minute: 42,
+            second: 0,
+        },
+    )
+    .ok()
+    .unwrap();
+    let internal_rtc = InternalRtc::new(real_time_clock);
+
+    // 選んだ RTC は表示と SDカードの TimeSource (ファイルの日時) で共有する
+    let shared_rtc: &'static RefCell<dyn Rtc> = if rtc8564.is_present() {
+        let _ = rtc8564.init();
+        cortex_m::singleton!(: RefCell<RTC8564<RtcBus>> = RefCell::new(rtc8564)).unwrap()
+    } else if ds3231.is_present() {
+        println!("RTC8564 not found, using the DS3231.");
+        let _ = ds3231.init();
+        cortex_m::singleton!(: RefCell<DS3231<RtcBus>> = RefCell::new(ds3231)).unwrap()
+    } else {
+        println!("RTC8564 and DS3231 not found, using the internal RTC.");
+        cortex_m::singleton!(: RefCell<InternalRtc> = RefCell::new(internal_rtc)).unwrap()
+    };
+    let time_source = RtcTimeSource::new(shared_rtc);
+    let mut selected_rtc = shared_rtc;
+    let rtc: &mut dyn Rtc = &mut selected_rtc;
 
     let spi0_mosi = pins.gpio7.reconfigure();
     let spi0_miso = pins.gpio4.reconfigure();
@@ -312,8 +529,34 @@ fn main() -> ! {
 
     let cd_sd = pins.gpio14.into_pull_down_input(); // Card Detect pin
 
+    let sdcard = SdSpi::new(spi1, cs_sd, timer);
+
+    // SDカードが無くても計測と表示は続ける。書けない間は RAM に溜めておき、
+    // 差し直されたらマウントし直して書き出す
+    let mut logger: SdLogger = Logger::new(VolumeManager::new(sdcard, time_source));
+
+    println!("Init SD card controller and retrieve card size...");
+    let sd_mounted = mount_sd(&mut logger, clocks.peripheral_clock.freq());
+
+    // 設定は BME280 などを初期化する前に読む。SDカードが無ければ既定値
+    let mut settings = if sd_mounted {
+        load_settings(&mut logger)
+    } else {
+        Settings::DEFAULT
+    };
+    if settings.uart_baud != DEFAULT_UART_BAUD {
+        uart_rx = set_uart_baud(uart_rx, settings.uart_baud, clocks.peripheral_clock.freq());
+    }
+    logger.set_format(settings.log_format, settings.log_columns);
+    logger.set_flush_interval(settings.flush_interval);
+    logger.set_max_file_size(settings.max_file_size);
+    logger.set_min_free_bytes(settings.min_free_bytes);
+
+    bme280.set_config(settings.bme280);
+
     // DeviceのIDコード(0x60)を正しく読めれば成功としている
-    if bme280.init() {
+    let bme280_ok = bme280.init();
+    if bme280_ok {
         println!("BME280 initialization successful.");
         println!("BME280 ID = 0x60.\r\n");
     } else {
@@ -322,64 +565,98 @@ fn main() -> ! {
 
     delay.delay_ms(2000);
 
+    lcd.set_geometry(settings.lcd_geometry);
     lcd.init(&mut delay);
 
+    // バックライト (GP18, PWM1 の A)。点けるか消すかと明るさは DisplayPower が決める
+    let mut pwm = hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS).pwm1;
+    pwm.set_ph_correct();
+    pwm.enable();
+    let mut backlight = pwm.channel_a;
+    let _ = backlight.output_to(pins.gpio18);
+    let mut display_power = DisplayPower::new(settings.display_power);
+
     // let a = 3;
     // let b = 5;
     // assert!(a == b);
 
-    let mut screen_state = ScreenState::Top;
-
-    let sdcard = SdCard::new(spi1, cs_sd, timer);
-
-    let mut volume_mgr = VolumeManager::new(sdcard, DummyTimesource::default());
-
-    println!("Init SD card controller and retrieve card size...");
-    match volume_mgr.device().num_bytes() {
-        Ok(size) => println!("card size is {} bytes", size),
-        _ => loop {},
+    let mut menu = ListMenu::new(" Select Item", &MENU_ITEMS);
+    let mut elements = ElementsScreen::new();
+    let mut set_date_time = DateTimeScreen::new();
+    let mut settings_menu = ListMenu::new(" Settings", &SETTINGS_ITEMS);
+    let mut log_interval = NumberEditor::new(&fields::LOG_INTERVAL);
+    let mut alarm_log_interval = NumberEditor::new(&fields::ALARM_LOG_INTERVAL);
+    let mut temperature_min = NumberEditor::new(&fields::TEMPERATURE_MIN);
+    let mut temperature_max = NumberEditor::new(&fields::TEMPERATURE_MAX);
+    let mut humidity_max = NumberEditor::new(&fields::HUMIDITY_MAX);
+    let mut log_format = ChoiceEditor::new(&fields::LOG_FORMAT);
+    let mut start_screen = ChoiceEditor::new(&fields::START_SCREEN);
+    let mut history_graph = HistoryScreen::new();
+    let mut min_max = MinMaxScreen::new();
+    let mut lcd_idle_timeout = NumberEditor::new(&fields::LCD_IDLE_TIMEOUT);
+    let mut units_menu = ListMenu::new(" Units & format", &UNITS_ITEMS);
+    let mut temperature_unit = ChoiceEditor::new(&fields::TEMPERATURE_UNIT);
+    let mut pressure_unit = ChoiceEditor::new(&fields::PRESSURE_UNIT);
+    let mut decimals = ChoiceEditor::new(&fields::DECIMALS);
+    let mut clock_format = ChoiceEditor::new(&fields::CLOCK);
+    let mut date_order = ChoiceEditor::new(&fields::DATE_ORDER);
+    let mut screens: [&mut dyn Screen; SCREEN_COUNT] = [
+        &mut menu,
+        &mut elements,
+        &mut set_date_time,
+        &mut settings_menu,
+        &mut log_interval,
+        &mut alarm_log_interval,
+        &mut temperature_min,
+        &mut temperature_max,
+        &mut humidity_max,
+        &mut log_format,
+        &mut start_screen,
+        &mut history_graph,
+        &mut min_max,
+        &mut lcd_idle_timeout,
+        &mut units_menu,
+        &mut temperature_unit,
+        &mut pressure_unit,
+        &mut decimals,
+        &mut clock_format,
+        &mut date_order,
+    ];
+    let mut navigator = Navigator::new(SCREEN_MENU);
+    if settings.start_screen == StartScreen::Elements {
+        navigator.push(SCREEN_ELEMENTS);
     }
 
-    // Now that the card is initialized, clock can go faster
-    volume_mgr
-        .device()
-        .spi(|spi| spi.set_baudrate(clocks.peripheral_clock.freq(), 16.MHz()));
-
-    println!("Getting Volume 0...");
-    let mut volume = match volume_mgr.get_volume(VolumeIdx(0)) {
-        Ok(v) => v,
-        _ => loop {},
-    };
+    if sd_mounted {
+        println!("Root directory opened!");
 
-    // After we have the volume (partition) of the drive we got to open the
-    // root directory:
-    let dir = match volume_mgr.open_root_dir(&volume) {
-        Ok(dir) => dir,
-        _ => loop {},
-    };
-
-    println!("Root directory opened!");
-
-    // This shows how to iterate through the directory and how
-    // to get the file names (and print them in hope they are UTF-8 compatible):
-    volume_mgr
-        .iterate_dir(&volume, &dir, |ent| {
+        // This shows how to iterate through the directory and how
+        // to get the file names (and print them in hope they are UTF-8 compatible):
+        let _ = logger.iterate_dir(|ent| {
             println!(
                 "/{}.{}",
-                core::str::from_utf8(ent.name.base_name()).unwrap(),
-                core::str::from_utf8(ent.name.extension()).unwrap()
+                core::str::from_utf8(ent.name.base_name()).unwrap_or("?"),
+                core::str::from_utf8(ent.name.extension()).unwrap_or("?")
             );
-        })
-        .unwrap();
-
-    //    volume_mgr.free();
+        });
+    }
+    let mut drift = load_drift(&mut logger);
+    drift.resume(rtc);
+    println!("RTC drift = {} x 0.01ppm", drift.ppm_x100());
 
-    let mut vi = Vol_items {
-        vol_man: volume_mgr,
-        vol: volume,
-        dir: dir,
+    let mut rx_line = RxLine {
+        buf: [0; RX_LINE_SIZE],
+        len: 0,
     };
 
+    let mut minutes = rtc.now().minute;
+    let mut time_adjusted = false;
+
+    let mut history = History::new();
+    let mut extremes = Extremes::new();
+    let mut schedule = LogSchedule::new(settings.log_interval);
+    schedule.set_alarm_interval(settings.alarm_log_interval);
+
     loop {
         bme280.read_data();
 
@@ -389,163 +666,269 @@ fn main() -> ! {
         // println!("H = {:.2} %", humi);
         // println!("P = {:.2} hPa\r\n", pres);
 
+        if bme280_ok {
+            let now = rtc.now();
+            history.record(&now, (temp, humi, pres));
+            extremes.update(&settings.time_zone.to_local(&now), (temp, humi, pres));
+        }
+        let mut ctx = Context {
+            rtc: &mut *rtc,
+            settings: &mut settings,
+            elements: (temp, humi, pres),
+            history: &history,
+            extremes: &mut extremes,
+            sd_ok: logger.is_mounted(),
+            alarm: schedule.is_alarm(),
+            settings_changed: false,
+            time_set: false,
+        };
+        // 消えている LCD を点けたスイッチは画面には渡さない
+        let now_us = timer.get_counter().ticks();
+        let mut key = unsafe { core::mem::replace(&mut SWITCH, SW::None) };
+        if key != SW::None && !display_power.activity(now_us) {
+            key = SW::None;
+        }
         lcd_display(
             &mut delay,
             &mut lcd,
-            &mut rtc8564,
-            &mut screen_state,
-            (temp, humi, pres),
+            &mut navigator,
+            &mut screens,
+            key,
+            &mut ctx,
+        );
+        let time_set = ctx.time_set;
+        // LCD で変えた設定は CONFIG.INI に保存してすぐに反映する
+        if ctx.settings_changed {
+            if !save_settings(&mut logger, &settings) {
+                println!("Failed to save {}.", settings::FILE_NAME);
+            }
+            logger.set_format(settings.log_format, settings.log_columns);
+            schedule.set_interval(settings.log_interval);
+            schedule.set_alarm_interval(settings.alarm_log_interval);
+            display_power.set_config(settings.display_power);
+        }
+        let local = settings.time_zone.to_local(&rtc.now());
+        if let Some(level) = display_power.update(now_us, &local) {
+            let display = if display_power.is_on() {
+                Display::On
+            } else {
+                Display::Off
+            };
+            lcd.set_display(&mut delay, display);
+            let _ = backlight.set_duty_cycle_percent(level);
+        }
+        // 手で合わせた分はドリフトと数えない
+        if time_set {
+            drift.restart(rtc);
+            save_drift(&mut logger, &drift);
+        }
+        if rtc_updated(rtc, &mut minutes) {
+            // 分が変わった直後なので、ここで RTC を補正しても端数の秒はほとんど失われない
+            if drift.apply(rtc) {
+                save_drift(&mut logger, &drift);
+                time_adjusted = true;
+            }
+            // SDカードが抜かれていたら1分毎にマウントし直してみる
+            if !logger.is_mounted() && mount_sd(&mut logger, clocks.peripheral_clock.freq()) {
+                println!("SD card mounted, {} records pending.", logger.pending());
+                let _ = logger.flush();
+                if drift.ppm_x100() == 0 {
+                    drift = load_drift(&mut logger);
+                    drift.resume(rtc);
+                }
+            }
+        }
+        let alarm = settings
+            .alarm_limits
+            .exceeded(temp, humi, schedule.is_alarm());
+        schedule.set_alarm(bme280_ok && alarm);
+        let now = rtc.now();
+        if schedule.due(&now, settings.time_zone.offset_at(&now)) {
+            let mut status: u8 = 0;
+            if time_adjusted {
+                status |= FLAG_TIME_ADJUSTED;
+                time_adjusted = false;
+            }
+            if rtc.status() != RtcStatus::Running {
+                status |= FLAG_RTC_INVALID;
+            }
+            if !bme280_ok {
+                status |= FLAG_SENSOR_ERROR;
+            }
+            let time = rtc.now();
+            let _ = logger.append(&Measurement {
+                time,
+                utc_offset: settings.time_zone.offset_at(&time),
+                temperature: temp,
+                humidity: humi,
+                pressure: pres,
+                status,
+            });
+        }
+        if let Some(reference) = poll_reference(&mut uart_rx, &mut rx_line) {
+            if drift.on_reference(&reference, rtc) {
+                println!("RTC drift = {} x 0.01ppm", drift.ppm_x100());
+                save_drift(&mut logger, &drift);
+            }
+            minutes = rtc.now().minute;
+        }
+    }
+}
+
+// UART から1行ずつ受け取り、基準時刻 (ISO-8601 の UTC か GPS の RMC) なら返す
+fn poll_reference(uart_rx: &mut UartRx, rx_line: &mut RxLine) -> Option<Time> {
+    let mut buf: [u8; 16] = [0; 16];
+    let mut reference = None;
+    if let Ok(n) = uart_rx.read_raw(&mut buf) {
+        for &c in &buf[..n] {
+            if c == b'\r' || c == b'\n' {
+                if let Ok(line) = core::str::from_utf8(&rx_line.buf[..rx_line.len]) {
+                    if let Some(t) = parse_reference(line) {
+                        reference = Some(t);
+                    }
+                }
+                rx_line.len = 0;
+            } else if rx_line.len < RX_LINE_SIZE {
+                rx_line.buf[rx_line.len] = c;
+                rx_line.len += 1;
+            }
+        }
+    }
+    reference
+}
+
+// SDカードを初期化してルートディレクトリを開く
+// 初期化は 400kHz で行い、その後でカードが対応する速さ (SD_CLOCK_HZ まで) に上げる
+fn mount_sd(logger: &mut SdLogger, peripheral_freq: HertzU32) -> bool {
+    let card = logger.volume_manager().device();
+    card.spi(|spi| spi.set_baudrate(peripheral_freq, SD_INIT_CLOCK_HZ.Hz()));
+    let clock = match card.init() {
+        Ok(info) => {
+            println!("card size is {} bytes", info.num_bytes());
+            info.csd
+                .max_transfer_rate()
+                .clamp(SD_INIT_CLOCK_HZ, SD_CLOCK_HZ)
+        }
+        Err(e) => {
+            println!("SD card not found. ({:?})", e);
+            return false;
+        }
+    };
+    card.spi(|spi| spi.set_baudrate(peripheral_freq, clock.Hz()));
+    if logger.mount().is_err() {
+        return false;
+    }
+    // 前回、書き込みの途中で電源が切れていたら、ジャーナルから書き直している
+    let recovery = logger.recovery();
+    if recovery.replayed > 0 || recovery.lost > 0 {
+        println!(
+            "Log recovered: {} records rewritten, {} lost.",
+            recovery.replayed, recovery.lost
         );
-        if rtc_updated(&mut rtc8564) {
-            save_elements(&mut vi, &mut rtc8564, (temp, humi, pres));
+    }
+    true
+}
+
+// CONFIG.INI を読む。無ければ既定値で作っておき、編集の雛形にしてもらう
+fn load_settings(logger: &mut SdLogger) -> Settings {
+    let mut settings = Settings::DEFAULT;
+    let mut buf: [u8; settings::MAX_FILE_SIZE] = [0; settings::MAX_FILE_SIZE];
+    match logger.read_file(settings::FILE_NAME, &mut buf) {
+        Ok(n) => {
+            if n == buf.len() {
+                println!("{} is too large, the rest is ignored.", settings::FILE_NAME);
+            }
+            // 途中で切れた文字があれば、その手前までを読む
+            let text = match core::str::from_utf8(&buf[..n]) {
+                Ok(text) => text,
+                Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or(""),
+            };
+            settings.load(text, |line, e| {
+                println!("{} line {}: {}", settings::FILE_NAME, line, e);
+            });
+            println!("Settings loaded from {}.", settings::FILE_NAME);
         }
+        Err(embedded_sdmmc::Error::NotFound) => {
+            if save_settings(logger, &settings) {
+                println!("{} created with the default settings.", settings::FILE_NAME);
+            }
+        }
+        Err(_) => println!("{} could not be read.", settings::FILE_NAME),
     }
+    settings
 }
 
-fn rtc_updated(rtc: &mut Rtc) -> bool {
-    let mut b = false;
-    let minute = rtc.get_minutes();
-    let time = rtc.read_date_time();
-    if time.minute != minute {
-        b = true;
+// 設定を CONFIG.INI に書き戻す。LCD のメニューで設定を変えたときにも呼ぶ
+// ファイルがあればコメントや知らないキーの行は残し、値だけを書き換える
+fn save_settings(logger: &mut SdLogger, settings: &Settings) -> bool {
+    let mut buf: [u8; settings::MAX_FILE_SIZE] = [0; settings::MAX_FILE_SIZE];
+    let old = match logger.read_file(settings::FILE_NAME, &mut buf) {
+        // 大きすぎて読みきれないものや UTF-8 でないものは作り直す
+        Ok(n) if n < buf.len() => core::str::from_utf8(&buf[..n]).unwrap_or(""),
+        _ => "",
+    };
+    let mut text: LineBuffer<{ settings::MAX_FILE_SIZE }> = LineBuffer::new();
+    if old.trim().is_empty() || settings.update(old, &mut text).is_err() {
+        text.clear();
+        if settings.write(&mut text).is_err() {
+            return false;
+        }
     }
-    b
+    logger
+        .write_file(settings::FILE_NAME, text.as_bytes())
+        .is_ok()
+}
+
+// UART を設定したボーレートで開き直す
+fn set_uart_baud(uart_rx: UartRx, baud: u32, peripheral_freq: HertzU32) -> UartRx {
+    let uart_tx = critical_section::with(|_| unsafe { UART_TRANSMITTER.take() }).unwrap();
+    let uart = hal::uart::UartPeripheral::join(uart_rx, uart_tx)
+        .disable()
+        .enable(
+            UartConfig::new(baud.Hz(), DataBits::Eight, None, StopBits::One),
+            peripheral_freq,
+        )
+        .unwrap();
+    let (uart_rx, uart_tx) = uart.split();
+    critical_section::with(|_| unsafe {
+        UART_TRANSMITTER = Some(uart_tx);
+    });
+    uart_rx
 }
 
-fn save_elements(vol_item: &mut Vol_items, rtc: &mut Rtc, tup: (f64, f64, f64)) {
-    let mut buf: [u8; 10] = [0; 10];
-
-    let mut y10: u8 = 0;
-    let mut y1: u8 = 0;
-    let mut mo10: u8 = 0;
-    let mut mo1: u8 = 0;
-    let mut d10: u8 = 0;
-    let mut d1: u8 = 0;
-    let mut h10: u8 = 0;
-    let mut h1: u8 = 0;
-    let mut mi10: u8 = 0;
-    let mut mi1: u8 = 0;
-
-    let time = rtc.read_date_time();
-
-    y10 = time.year / 10 | b'0';
-    y1 = time.year % 10 | b'0';
-    mo10 = time.month / 10 | b'0';
-    mo1 = time.month % 10 | b'0';
-    d10 = time.day / 10 | b'0';
-    d1 = time.day % 10 | b'0';
-    h10 = time.hour / 10 | b'0';
-    h1 = time.hour % 10 | b'0';
-    mi10 = time.minute / 10 | b'0';
-    mi1 = time.minute % 10 | b'0';
-
-    // buf format: yymmdd.txt
-    buf[0] = y10;
-    buf[1] = y1;
-    buf[2] = mo10;
-    buf[3] = mo1;
-    buf[4] = d10;
-    buf[5] = d1;
-    buf[6] = b'.';
-    buf[7] = b't';
-    buf[8] = b'x';
-    buf[9] = b't';
-
-    let temp_tens_digit: u8 = ((tup.0 as i32 / 10) % 10) as u8 | b'0';
-    let temp_ones_digit: u8 = ((tup.0 as i32) % 10) as u8 | b'0';
-    let temp_tenths_digit: u8 = (((tup.0 * 10.0) as i32) % 10) as u8 | b'0';
-
-    let humi_tens_digit: u8 = ((tup.1 as i32 / 10) % 10) as u8 | b'0';
-    let humi_ones_digit: u8 = ((tup.1 as i32) % 10) as u8 | b'0';
-    let humi_tenths_digit: u8 = (((tup.1 * 10.0) as i32) % 10) as u8 | b'0';
-
-    let mut pres_thousands_digit: u8 = ((tup.2 as i32 / 1000) % 10) as u8 | b'0';
-    let pres_hundreds_digit: u8 = ((tup.2 as i32 / 100) % 10) as u8 | b'0';
-    let pres_tens_digit: u8 = ((tup.2 as i32 / 10) % 10) as u8 | b'0';
-    let pres_ones_digit: u8 = ((tup.2 as i32) % 10) as u8 | b'0';
-    let pres_tenths_digit: u8 = (((tup.2 * 10.0) as i32) % 10) as u8 | b'0';
-
-    if pres_thousands_digit == b'0' {
-        pres_thousands_digit = b' ';
+fn load_drift(logger: &mut SdLogger) -> DriftTracker {
+    let mut buf: [u8; RECORD_SIZE] = [0; RECORD_SIZE];
+    match logger.read_file(DRIFT_FILE_NAME, &mut buf) {
+        Ok(RECORD_SIZE) => DriftTracker::from_bytes(&buf).unwrap_or_default(),
+        _ => DriftTracker::new(),
     }
+}
+
+fn save_drift(logger: &mut SdLogger, drift: &DriftTracker) {
+    let _ = logger.write_file(DRIFT_FILE_NAME, &drift.to_bytes());
+}
 
-    if let Ok(mut file) = vol_item.vol_man.open_file_in_dir(
-        &mut vol_item.vol,
-        &vol_item.dir,
-        core::str::from_utf8(&buf).unwrap(),
-        Mode::ReadWriteCreateOrAppend,
-    ) {
-        vol_item
-            .vol_man
-            .write(
-                &mut vol_item.vol,
-                &mut file,
-                &[
-                    b'2',
-                    b'0',
-                    y10,
-                    y1,
-                    b'/',
-                    mo10,
-                    mo1,
-                    b'/',
-                    d10,
-                    d1,
-                    b' ',
-                    h10,
-                    h1,
-                    b':',
-                    mi10,
-                    mi1,
-                    b' ',
-                    b'T',
-                    b':',
-                    b' ',
-                    temp_tens_digit,
-                    temp_ones_digit,
-                    b'.',
-                    temp_tenths_digit,
-                    b',',
-                    b' ',
-                    b'H',
-                    b':',
-                    b' ',
-                    humi_tens_digit,
-                    humi_ones_digit,
-                    b'.',
-                    humi_tenths_digit,
-                    b',',
-                    b' ',
-                    b'P',
-                    b':',
-                    b' ',
-                    pres_thousands_digit,
-                    pres_hundreds_digit,
-                    pres_tens_digit,
-                    pres_ones_digit,
-                    b'.',
-                    pres_tenths_digit,
-                    0x0d,
-                    0x0a,
-                ],
-            )
-            .unwrap();
-        vol_item.vol_man.close_file(&vol_item.vol, file).unwrap();
+fn rtc_updated(rtc: &mut dyn Rtc, minutes: &mut u8) -> bool {
+    let mut b = false;
+    let time = rtc.now();
+    if time.minute != *minutes {
+        *minutes = time.minute;
+        b = true;
     }
+    b
 }
 
-fn lcd_display<D: DelayUs<u16> + DelayMs<u8>>(
+// 押されたスイッチ (key) を今の画面に渡し、描き変わった所だけを LCD に送る
+fn lcd_display<D: DelayUs<u16> + DelayMs<u8>, B: Hd44780Bus>(
     delay: &mut D,
-    lcd: &mut SC2004,
-    rtc: &mut Rtc,
-    screen_state: &mut ScreenState,
-    tup: (f64, f64, f64),
+    lcd: &mut SC2004<B>,
+    navigator: &mut Navigator,
+    screens: &mut [&mut dyn Screen],
+    key: SW,
+    ctx: &mut Context,
 ) {
-    match screen_state {
-        ScreenState::Top => lcd.set_top(delay, screen_state),
-        ScreenState::Elements => lcd.set_elements(delay, tup, rtc, screen_state),
-        ScreenState::SetDateTime => lcd.set_date_time(delay, rtc, screen_state),
-    }
+    navigator.update(screens, lcd.frame(), key, ctx);
+    lcd.flush(delay);
 }
 
 #[interrupt]
@@ -604,11 +987,23 @@ fn TIMER_IRQ_0() {
                         SS_NOW |= 0x01;
                     }
                 }
+                // 長押しは押している間に知らせ、離したときの Center は出さない
+                let long_press = CENTER_HELD >= LONG_PRESS_TICKS;
+                if SS_NOW & 0x10 == 0x10 {
+                    CENTER_HELD = CENTER_HELD.saturating_add(1);
+                    if CENTER_HELD == LONG_PRESS_TICKS {
+                        SWITCH = SW::CenterLong;
+                    }
+                } else {
+                    CENTER_HELD = 0;
+                }
                 if (SS_TWO_BEFORE & 0x10 == 0x10)
                     && (SS_ONE_BEFORE & 0x10 == 0x10)
                     && (SS_NOW & 0x10 == 0)
                 {
-                    SWITCH = SW::Center;
+                    if !long_press {
+                        SWITCH = SW::Center;
+                    }
                 } else if (SS_TWO_BEFORE & 0x08 == 0x08)
                     && (SS_ONE_BEFORE & 0x08 == 0x08)
                     && (SS_NOW & 0x08 == 0)